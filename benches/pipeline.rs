@@ -0,0 +1,75 @@
+// Compare the dynamic `Box<Any>` path with the typed pipeline on the same map/filter chain
+#![feature(test)]
+#![feature(box_syntax)]
+
+extern crate test;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate bifrost;
+extern crate bifrost_hasher;
+#[macro_use]
+extern crate hivemind;
+
+use hivemind::rdd::{RDD, RDDTracker, AnyIter, Partition, UNIT_RDDID};
+use hivemind::rdd::funcs::{RDDFunc, RDDFuncResult, to_any};
+use hivemind::rdd::transformers::map::Map;
+use hivemind::rdd::transformers::filter::Filter;
+use hivemind::rdd::typed::{Pipeline, pipe};
+use hivemind::rdd::failure::FailurePolicy;
+use hivemind::contexts::task::TaskContext;
+use bifrost::utils::bincode;
+use std::sync::Arc;
+use test::Bencher;
+
+const RECORDS: u64 = 10_000;
+
+def_rdd_func!(
+    APlusB (a: u64)[b: u64] -> u64 {
+        a + b
+    }
+    IsEven (x: u64)[] -> bool {
+        x % 2 == 0
+    }
+);
+
+// dynamic records are one element tuples because functions take their arguments as tuples
+def_rdd_func!(
+    APlusBTuple (a: u64)[b: u64] -> (u64, ) {
+        (a + b, )
+    }
+);
+
+#[bench]
+fn dynamic_map_filter(b: &mut Bencher) {
+    {
+        let lock = hivemind::INIT_LOCK.lock();
+        APlusBTuple::register().unwrap();
+        IsEven::register().unwrap();
+    }
+    let map = Map::new(
+        UNIT_RDDID,
+        box (APlusBTuple::id(), bincode::serialize(&APlusBTuple { b: 1 }), false)
+    ).unwrap();
+    let filter = Filter::new(
+        UNIT_RDDID,
+        box (IsEven::id(), bincode::serialize(&IsEven {}), false)
+    ).unwrap();
+    let task = Arc::new(TaskContext::new(0, FailurePolicy::fail_fast(), None));
+    let partition = Partition { index: 0, server: 0 };
+    b.iter(|| {
+        let iter: AnyIter = Box::new((0..RECORDS).map(|x| to_any((x, ))));
+        let iter = map.compute(iter, &partition, &task);
+        filter.compute(iter, &partition, &task).count()
+    });
+}
+
+#[bench]
+fn typed_map_filter(b: &mut Bencher) {
+    let pipeline = pipe::<u64>()
+        .map(APlusB { b: 1 })
+        .filter(IsEven {});
+    b.iter(|| {
+        pipeline.clone().run(0..RECORDS).count()
+    });
+}
@@ -0,0 +1,223 @@
+// Command line client of a hivemind cluster
+//  hivemind-cli [--meta <addr,..>] <command>
+// Commands:
+//  nodes [--online]                   list compute nodes
+//  tasks [--status <s>] [--pool <p>]  list tasks
+//  task <task id>                     status, failures and blacklisted nodes of a task
+//  utilization                        cluster utilization summary
+//  locality --server <addr>           how often partitions ran on their preferred servers
+//  cancel <task id>                   cancel a task
+//  drain <node id>                    stop placing occupations on a node
+//  undrain <node id>                  let a draining node take occupations again
+//  submit <file> --server <addr> [--name <n>] [--pool <p>] [--priority <n>]
+//                                     submit a serialized `ScriptContext` job
+// Meta members default to `HIVEMIND_META_MEMBERS`.
+
+extern crate hivemind;
+extern crate bifrost;
+extern crate futures;
+
+use bifrost::raft;
+use bifrost::raft::client::RaftClient;
+use bifrost::rpc;
+use futures::Future;
+use hivemind::server::resources::client::ResourceManagerClient;
+use hivemind::server::resources::manager::{NodeFilter, TaskFilter, TaskStatus};
+use hivemind::server::resources::pools::DEFAULT_POOL;
+use hivemind::server::service;
+use std::env;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::Read;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("usage: hivemind-cli [--meta <addr,..>] \
+               <nodes|tasks|task|utilization|locality|cancel|drain|undrain|submit> [args]");
+    process::exit(2)
+}
+
+fn fail<E: Debug>(what: &str, e: E) -> ! {
+    eprintln!("{}: {:?}", what, e);
+    process::exit(1)
+}
+
+fn parse_status(status: &str) -> TaskStatus {
+    match status.to_lowercase().as_str() {
+        "pending" => TaskStatus::Pending,
+        "running" => TaskStatus::Running,
+        "succeeding" => TaskStatus::Succeeding,
+        "succeed" => TaskStatus::Succeed,
+        "failed" => TaskStatus::Failed,
+        "canceled" => TaskStatus::Canceled,
+        _ => usage()
+    }
+}
+
+fn parse_id(id: Option<String>) -> u64 {
+    id.and_then(|id| id.parse().ok()).unwrap_or_else(|| usage())
+}
+
+fn connect(meta: &Vec<String>) -> ResourceManagerClient {
+    if meta.is_empty() {
+        eprintln!("no meta members, set --meta or HIVEMIND_META_MEMBERS");
+        process::exit(2)
+    }
+    match RaftClient::new(meta, raft::DEFAULT_SERVICE_ID) {
+        Ok(raft_client) => ResourceManagerClient::new(&raft_client),
+        Err(e) => fail("cannot connect to meta members", e)
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut meta: Vec<String> = env::var("HIVEMIND_META_MEMBERS")
+        .map(|members| members.split(',').map(|m| m.trim().to_string()).collect())
+        .unwrap_or(Vec::new());
+    if args.first().map(|a| a == "--meta").unwrap_or(false) {
+        if args.len() < 2 {
+            usage()
+        }
+        meta = args[1].split(',').map(|m| m.trim().to_string()).collect();
+        args.drain(0..2);
+    }
+    let mut args = args.into_iter();
+    let command = args.next().unwrap_or_else(|| usage());
+    match command.as_str() {
+        "nodes" => {
+            let mut filter = NodeFilter::default();
+            for arg in args {
+                match arg.as_str() {
+                    "--online" => filter.online_only = true,
+                    _ => usage()
+                }
+            }
+            let nodes = connect(&meta).nodes(filter).wait()
+                .unwrap_or_else(|e| fail("cannot list nodes", e));
+            println!("{:<20} {:<22} {:<8} {:<9} {:>14} {:>14} {:>6} {:>6}",
+                     "ID", "ADDRESS", "ONLINE", "STATE", "MEMORY", "FREE MEMORY", "CPUS", "FREE");
+            for node in nodes {
+                println!("{:<20} {:<22} {:<8} {:<9} {:>14} {:>14} {:>6} {:>6}",
+                         node.node_id(), node.address(), node.online(),
+                         format!("{:?}", node.state()), node.memory(), node.memory_remains(),
+                         node.processors(), node.processors_remains());
+            }
+        },
+        "tasks" => {
+            let mut filter = TaskFilter::default();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--status" => filter.status = Some(parse_status(&args.next().unwrap_or_else(|| usage()))),
+                    "--pool" => filter.pool = Some(args.next().unwrap_or_else(|| usage())),
+                    _ => usage()
+                }
+            }
+            let tasks = connect(&meta).tasks(filter).wait()
+                .unwrap_or_else(|e| fail("cannot list tasks", e));
+            println!("{:<20} {:<24} {:<12} {:<12} {:>8}", "ID", "NAME", "STATUS", "POOL", "PRIORITY");
+            for task in tasks {
+                println!("{:<20} {:<24} {:<12} {:<12} {:>8}",
+                         task.id(), task.name(), format!("{:?}", task.status()),
+                         task.pool(), task.priority());
+            }
+        },
+        "task" => {
+            let task_id = parse_id(args.next());
+            let task = match connect(&meta).task(task_id).wait() {
+                Ok(Some(task)) => task,
+                Ok(None) => fail("cannot find task", task_id),
+                Err(e) => fail("cannot get task", e)
+            };
+            println!("id: {}", task.id());
+            println!("name: {}", task.name());
+            println!("status: {:?}", task.status());
+            println!("pool: {}", task.pool());
+            println!("priority: {}", task.priority());
+            println!("blacklisted nodes: {:?}", task.blacklist());
+            for failure in task.failures() {
+                println!("stage {} attempt {} failed on node {} at {}: {}",
+                         failure.stage_id, failure.attempt, failure.node_id, failure.time, failure.reason);
+            }
+        },
+        "utilization" => {
+            let util = connect(&meta).utilization().wait()
+                .unwrap_or_else(|e| fail("cannot get utilization", e));
+            println!("nodes: {} ({} online)", util.nodes, util.online_nodes);
+            println!("memory: {} used, {} free, {} total", util.used_memory, util.free_memory, util.total_memory);
+            println!("processors: {} used, {} free, {} total",
+                     util.used_processors, util.free_processors, util.total_processors);
+            for (status, count) in &util.occupations {
+                println!("occupations {:?}: {}", status, count);
+            }
+        },
+        "locality" => {
+            if args.next().map(|flag| flag != "--server").unwrap_or(true) {
+                usage()
+            }
+            let server = args.next().unwrap_or_else(|| usage());
+            let client = rpc::DEFAULT_CLIENT_POOL.get(&server)
+                .unwrap_or_else(|e| fail("cannot connect to server", e));
+            let service = service::SyncServiceClient::new(service::DEFAULT_SERVICE_ID, &client);
+            let report = match service.locality() {
+                Ok(Ok(report)) => report,
+                Ok(Err(e)) => fail("cannot get locality", e),
+                Err(e) => fail("cannot get locality", e)
+            };
+            println!("process local: {}", report.process_local);
+            println!("node local: {}", report.node_local);
+            println!("any: {}", report.any);
+            println!("no preference: {}", report.no_preference);
+            println!("preferred node hit ratio: {:.2}", report.hit_ratio());
+        },
+        "cancel" => {
+            let task_id = parse_id(args.next());
+            connect(&meta).cancel_job(task_id).wait()
+                .unwrap_or_else(|e| fail("cannot cancel task", e));
+            println!("task {} canceled", task_id);
+        },
+        "drain" => {
+            let node_id = parse_id(args.next());
+            connect(&meta).drain_node(node_id).wait()
+                .unwrap_or_else(|e| fail("cannot drain node", e));
+            println!("node {} is draining", node_id);
+        },
+        "undrain" => {
+            let node_id = parse_id(args.next());
+            connect(&meta).undrain_node(node_id).wait()
+                .unwrap_or_else(|e| fail("cannot undrain node", e));
+            println!("node {} is active", node_id);
+        },
+        "submit" => {
+            let path = args.next().unwrap_or_else(|| usage());
+            let mut server = None;
+            let mut name = path.clone();
+            let mut pool = DEFAULT_POOL.to_string();
+            let mut priority = 0;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--server" => server = args.next(),
+                    "--name" => name = args.next().unwrap_or_else(|| usage()),
+                    "--pool" => pool = args.next().unwrap_or_else(|| usage()),
+                    "--priority" => priority = args.next()
+                        .and_then(|p| p.parse().ok())
+                        .unwrap_or_else(|| usage()),
+                    _ => usage()
+                }
+            }
+            let server = server.unwrap_or_else(|| usage());
+            let mut script = Vec::new();
+            File::open(&path)
+                .and_then(|mut file| file.read_to_end(&mut script))
+                .unwrap_or_else(|e| fail("cannot read job script", e));
+            let client = rpc::DEFAULT_CLIENT_POOL.get(&server)
+                .unwrap_or_else(|e| fail("cannot connect to server", e));
+            let service = service::SyncServiceClient::new(service::DEFAULT_SERVICE_ID, &client);
+            match service.submit_job(&script, &name, &pool, &priority) {
+                Ok(Ok(task_id)) => println!("submitted task {}", task_id),
+                Ok(Err(e)) => fail("job rejected", e),
+                Err(e) => fail("cannot submit job", e)
+            }
+        },
+        _ => usage()
+    }
+}
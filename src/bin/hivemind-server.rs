@@ -0,0 +1,139 @@
+// Starts a hivemind server from config. With `is_meta` it runs the raft meta group and the
+//  resource manager, and every server is a compute node.
+//  hivemind-server [--config <file>] [--dump-config]
+// Settings can also come from `HIVEMIND_*` environment variables, see `server::config`.
+// SIGTERM or SIGINT decommissions the server before exiting, see `server::decommission`.
+
+extern crate hivemind;
+extern crate bifrost;
+extern crate futures;
+extern crate libc;
+
+use bifrost::rpc;
+use futures::Future;
+use hivemind::server::HMServer;
+use hivemind::server::config::ServerConfig;
+use hivemind::scheduler::cancel::RunningTasks;
+use std::env;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn usage() -> ! {
+    eprintln!("usage: hivemind-server [--config <file>] [--dump-config]");
+    process::exit(2)
+}
+
+// Signals are blocked in every thread, and taken by `wait_signal` with `sigwait`. Must be called
+//  before any thread is started, so they inherit the mask.
+fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        set
+    }
+}
+
+fn wait_signal(set: &libc::sigset_t) -> i32 {
+    let mut signal = 0;
+    unsafe {
+        libc::sigwait(set, &mut signal);
+    }
+    signal
+}
+
+fn now_ms() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1000000
+}
+
+fn main() {
+    let mut config_path = None;
+    let mut dump = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--dump-config" => dump = true,
+            _ => usage()
+        }
+    }
+    let config = match ServerConfig::load(config_path.as_ref().map(|p| p.as_str())) {
+        Ok(config) => config,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("config error: {}", e);
+            }
+            process::exit(1)
+        }
+    };
+    if dump {
+        println!("{}", config.dump());
+        return;
+    }
+    let signals = block_signals();
+    let opts = config.to_options();
+    let rpc = rpc::Server::new(&opts.address);
+    rpc::Server::listen_and_resume(&rpc);
+    let server = match HMServer::new(&opts, &rpc) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("cannot start server: {:?}", e);
+            process::exit(1)
+        }
+    };
+    println!("hivemind server {} started on {}", server.server_id, opts.address);
+    if opts.is_meta {
+        if let Err(e) = config.apply(&server.resource_manager) {
+            eprintln!("{}", e);
+            process::exit(1)
+        }
+    }
+    // leases of running partitions are renewed before any tick can expire them
+    RunningTasks::heartbeat(
+        &server.tasks, &server.resource_manager, server.server_id,
+        Duration::from_millis(config.timeouts.heartbeat_interval)
+    );
+    {
+        let server = server.clone();
+        thread::spawn(move || {
+            // the resource manager only knows time from ticks, for leases and preemption grace.
+            //  Only the raft leader ticks, so time comes from one clock.
+            loop {
+                let leader = server.raft_service.as_ref().map(|raft| raft.is_leader()).unwrap_or(false);
+                if leader {
+                    if let Err(e) = server.resource_manager.tick(now_ms()).wait() {
+                        eprintln!("cannot tick resource manager: {:?}", e);
+                    }
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+    }
+    let signal = wait_signal(&signals);
+    println!("signal {} received, decommissioning server {}", signal, server.server_id);
+    // another signal stops waiting for running work
+    thread::spawn(move || {
+        wait_signal(&signals);
+        eprintln!("decommission interrupted");
+        process::exit(1)
+    });
+    let code = match server.decommission(&config.decommission_options()) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("cannot decommission server: {:?}", e);
+            // still leave, running occupations are lost with the node
+            server.leave_group();
+            if let Err(e) = server.shutdown() {
+                eprintln!("cannot shut down server: {:?}", e);
+            }
+            1
+        }
+    };
+    process::exit(code)
+}
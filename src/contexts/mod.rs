@@ -1,20 +1,30 @@
 use uuid::Uuid;
 use super::rdd::{RDD, RDDID};
+use super::rdd::failure::FailurePolicy;
 use std::collections::BTreeMap;
 use std::any::{Any, TypeId};
 use std::cell::RefCell;
 
 pub mod script;
+pub mod task;
 
 // #[derive(Serialize, Deserialize, Clone)]
 pub struct JobContext {
-    rdds: BTreeMap<RDDID, Box<RDD>>
+    rdds: BTreeMap<RDDID, Box<RDD>>,
+    order: Vec<RDDID>, // runs one after another on each partition
+    pub failure_policy: FailurePolicy,
 }
 
 impl JobContext {
     pub fn new() -> JobContext {
         JobContext {
-            rdds: BTreeMap::new()
+            rdds: BTreeMap::new(),
+            order: Vec::new(),
+            failure_policy: FailurePolicy::default(),
         }
     }
+    // RDDs in execution order
+    pub fn pipeline(&self) -> Vec<&Box<RDD>> {
+        self.order.iter().filter_map(|id| self.rdds.get(id)).collect()
+    }
 }
\ No newline at end of file
@@ -9,19 +9,29 @@
 // The RDD runtime use dynamic typing heavily by 'Any' trait for transformers, which will also
 //  produce errors if type mismatch. But we can detected such error at compile time by the composer.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::marker::PhantomData;
+use std::any::Any;
+use std::sync::Arc;
 use rdd::funcs::RDDFunc;
 use rdd::{RDDID, RDDTracker, UNIT_RDDID};
 use rdd::script::{RDDScript, RDDScriptCtx};
 use rdd::{transformers as trans};
+use rdd::failure::FailurePolicy;
+use rdd::typed::{Pipeline, StageDef};
+use rdd::batch::{Summable, ColumnType};
+use rdd::transformers::aggregate::AggregateOp;
 use bifrost::utils::bincode;
 use super::JobContext;
+use server::standalone::Standalone;
+use scheduler::JobError;
 
 // only for context transport
 #[derive(Serialize, Deserialize)]
 pub struct ScriptContext {
-    dag: BTreeMap<RDDID, RDDScript>
+    dag: BTreeMap<RDDID, RDDScript>,
+    order: Vec<RDDID>, // RDDs in the order they were compiled, sources first
+    failure_policy: FailurePolicy,
 }
 
 pub trait RDDComposer: Clone {
@@ -30,21 +40,87 @@ pub trait RDDComposer: Clone {
         where Self: Sized,
               F: RDDFunc<In = (Self::Item, )>
     {
-        Map { comps: self.clone(), func: closure, id: RDDID::rand() }
+        Map { comps: self.clone(), func: closure, id: RDDID::rand(), fallible: false }
     }
 
     fn filter<F>(&self, closure: F) -> Filter<Self, F, Self::Item>
         where Self: Sized,
               F: RDDFunc<In = (Self::Item, )>
     {
-        Filter { comps: self.clone(), func: closure, id: RDDID::rand(), mark: PhantomData }
+        Filter {
+            comps: self.clone(), func: closure, id: RDDID::rand(),
+            fallible: false, mark: PhantomData
+        }
+    }
+
+    // `try_map` and `try_filter` are for functions defined with `?->`. Errors they return are
+    //  propagated or skipped by `FuncErrorPolicy` of the job failure policy.
+    fn try_map<F>(&self, closure: F) -> Map<Self, F>
+        where Self: Sized,
+              F: RDDFunc<In = (Self::Item, )>
+    {
+        Map { comps: self.clone(), func: closure, id: RDDID::rand(), fallible: true }
+    }
+
+    fn try_filter<F>(&self, closure: F) -> Filter<Self, F, Self::Item>
+        where Self: Sized,
+              F: RDDFunc<In = (Self::Item, ), Out = bool>
+    {
+        Filter {
+            comps: self.clone(), func: closure, id: RDDID::rand(),
+            fallible: true, mark: PhantomData
+        }
+    }
+    // Run a typed pipeline as one fused stage, see `rdd::typed`
+    fn fused<S>(&self, pipe: S::Pipe) -> Fused<Self, S>
+        where Self: Sized,
+              S: StageDef,
+              S::Pipe: Pipeline<In = Self::Item>
+    {
+        Fused { comps: self.clone(), pipe, id: RDDID::rand() }
+    }
+    // Aggregate each partition into one record, see `transformers::aggregate`
+    fn count(&self) -> Aggregate<Self, u64>
+        where Self: Sized, Self::Item: Summable
+    {
+        Aggregate::new::<Self::Item>(self, AggregateOp::Count)
+    }
+    fn sum(&self) -> Aggregate<Self, Self::Item>
+        where Self: Sized, Self::Item: Summable
+    {
+        Aggregate::new::<Self::Item>(self, AggregateOp::Sum)
+    }
+    fn min(&self) -> Aggregate<Self, Self::Item>
+        where Self: Sized, Self::Item: Summable
+    {
+        Aggregate::new::<Self::Item>(self, AggregateOp::Min)
+    }
+    fn max(&self) -> Aggregate<Self, Self::Item>
+        where Self: Sized, Self::Item: Summable
+    {
+        Aggregate::new::<Self::Item>(self, AggregateOp::Max)
+    }
+    // Run the job in a standalone server and bring all records back
+    fn collect(&self, server: &Standalone) -> Result<Vec<Self::Item>, JobError>
+        where Self: Sized, Self::Item: Any
+    {
+        server.collect(self)
     }
     fn compile(&self, ctx: &mut ScriptContext);
+    // Input partitions of the job. Only sources have them, other composers ask their parents.
+    fn input(&self) -> Option<Vec<Vec<Box<Any>>>> {
+        None
+    }
+    // The RDD this composer compiles to, for dependencies of the next one. Sources have none.
+    fn id(&self) -> Option<RDDID> {
+        None
+    }
     fn compile_with_closure<F>(
         &self,
         rdd_id: RDDID,
         closure: &F,
         trans_id: u64,
+        fallible: bool,
         ctx: &mut ScriptContext,
         deps: Vec<RDDID>,
     )
@@ -54,13 +130,14 @@ pub trait RDDComposer: Clone {
         //  so that the transformer constructor can decode the closure data by func_id
         let func_id = F::id();
         let closure_data =  bincode::serialize(closure);
-        ctx.dag.insert(rdd_id, RDDScript {
+        ctx.insert(RDDScript {
             rdd_id,
             ctx: RDDScriptCtx::Transformer {
                 id: trans_id,
-                data: bincode::serialize(&(func_id, closure_data))
+                data: bincode::serialize(&(func_id, closure_data, fallible))
             },
             deps,
+            funcs: vec![func_id],
         });
     }
 }
@@ -70,6 +147,7 @@ pub struct Filter<C, F, I> {
     comps: C,
     func: F,
     id: RDDID,
+    fallible: bool,
     mark: PhantomData<I>
 }
 
@@ -84,17 +162,25 @@ impl <C, F, I> RDDComposer for Filter<C, F, I>
             self.id,
             &self.func,
             trans::filter::Filter::trans_id(),
+            self.fallible,
             ctx,
             vec![self.id]
         )
     }
+    fn input(&self) -> Option<Vec<Vec<Box<Any>>>> {
+        self.comps.input()
+    }
+    fn id(&self) -> Option<RDDID> {
+        Some(self.id)
+    }
 }
 
 #[derive(Clone)]
 pub struct Map<C, F> {
     comps: C,
     func: F,
-    id: RDDID
+    id: RDDID,
+    fallible: bool,
 }
 
 impl <C, F> RDDComposer for Map<C, F>
@@ -107,26 +193,170 @@ impl <C, F> RDDComposer for Map<C, F>
             self.id,
             &self.func,
             trans::map::Map::trans_id(),
+            self.fallible,
             ctx,
             vec![self.id]
         )
     }
+    fn input(&self) -> Option<Vec<Vec<Box<Any>>>> {
+        self.comps.input()
+    }
+    fn id(&self) -> Option<RDDID> {
+        Some(self.id)
+    }
+}
+
+pub struct Fused<C, S: StageDef> {
+    comps: C,
+    pipe: S::Pipe,
+    id: RDDID,
+}
+
+impl <C: Clone, S: StageDef> Clone for Fused<C, S> {
+    fn clone(&self) -> Self {
+        Fused { comps: self.comps.clone(), pipe: self.pipe.clone(), id: self.id }
+    }
+}
+
+impl <C, S> RDDComposer for Fused<C, S>
+    where S: StageDef,
+          C: RDDComposer {
+    type Item = <S::Pipe as Pipeline>::Out;
+    fn compile(&self, ctx: &mut ScriptContext) {
+        self.comps.compile(ctx);
+        // functions in the pipeline are compiled into the stage, they are not looked up from
+        //  the function registry
+        let pipe_data = bincode::serialize(&self.pipe);
+        ctx.insert(RDDScript {
+            rdd_id: self.id,
+            ctx: RDDScriptCtx::Transformer {
+                id: S::trans_id(),
+                data: bincode::serialize(&(pipe_data, ))
+            },
+            deps: self.comps.id().into_iter().collect(),
+            funcs: vec![],
+        });
+    }
+    fn input(&self) -> Option<Vec<Vec<Box<Any>>>> {
+        self.comps.input()
+    }
+    fn id(&self) -> Option<RDDID> {
+        Some(self.id)
+    }
+}
+
+#[derive(Clone)]
+pub struct Aggregate<C, I> {
+    comps: C,
+    op: AggregateOp,
+    column_type: ColumnType,
+    id: RDDID,
+    mark: PhantomData<I>
+}
+
+impl <C: Clone, I> Aggregate<C, I> {
+    fn new<T: Summable>(comps: &C, op: AggregateOp) -> Aggregate<C, I> {
+        Aggregate {
+            comps: comps.clone(), op,
+            column_type: T::column_type(),
+            id: RDDID::rand(),
+            mark: PhantomData
+        }
+    }
+}
+
+impl <C, I> RDDComposer for Aggregate<C, I>
+    where I: Clone,
+          C: RDDComposer {
+    type Item = I;
+    fn compile(&self, ctx: &mut ScriptContext) {
+        self.comps.compile(ctx);
+        ctx.insert(RDDScript {
+            rdd_id: self.id,
+            ctx: RDDScriptCtx::Transformer {
+                id: trans::aggregate::Aggregate::trans_id(),
+                data: bincode::serialize(&(self.op, self.column_type))
+            },
+            deps: self.comps.id().into_iter().collect(),
+            funcs: vec![],
+        });
+    }
+    fn input(&self) -> Option<Vec<Vec<Box<Any>>>> {
+        self.comps.input()
+    }
+    fn id(&self) -> Option<RDDID> {
+        Some(self.id)
+    }
+}
+
+// In-memory data split into partitions, the source of jobs in standalone mode. Records are
+//  passed as argument tuples like other sources.
+pub struct Source<T> {
+    partitions: Arc<Vec<Vec<T>>>,
+}
+
+impl <T> Clone for Source<T> {
+    fn clone(&self) -> Self {
+        Source { partitions: self.partitions.clone() }
+    }
+}
+
+pub fn parallelize<T>(data: Vec<T>, partitions: usize) -> Source<T> {
+    let partitions = if partitions == 0 { 1 } else { partitions };
+    let size = (data.len() + partitions - 1) / partitions;
+    let mut parts: Vec<Vec<T>> = (0..partitions).map(|_| Vec::with_capacity(size)).collect();
+    for (i, item) in data.into_iter().enumerate() {
+        parts[if size == 0 { 0 } else { i / size }].push(item);
+    }
+    Source { partitions: Arc::new(parts) }
+}
+
+impl <T> RDDComposer for Source<T>
+    where T: Clone + Any {
+    type Item = T;
+    fn compile(&self, ctx: &mut ScriptContext) {}
+    fn input(&self) -> Option<Vec<Vec<Box<Any>>>> {
+        Some(self.partitions.iter().map(|part| {
+            part.iter().map(|item| -> Box<Any> { box (item.clone(), ) }).collect()
+        }).collect())
+    }
 }
 
 impl ScriptContext {
     pub fn compile(&self) -> Result<JobContext, String> {
         let mut runtime_ctx = JobContext::new();
+        runtime_ctx.failure_policy = self.failure_policy.clone();
         for (id, script) in &self.dag {
             let compiled_scr = script.compile()?;
             runtime_ctx.rdds.insert(*id, compiled_scr);
         }
+        runtime_ctx.order = self.order.clone();
         return Ok(runtime_ctx);
     }
+    fn insert(&mut self, script: RDDScript) {
+        let id = script.rdd_id;
+        if self.dag.insert(id, script).is_none() {
+            self.order.push(id);
+        }
+    }
+    // all RDD functions the job needs, for checking against workers before scheduling
+    pub fn required_funcs(&self) -> Vec<u64> {
+        let funcs: BTreeSet<u64> = self.dag
+            .values()
+            .flat_map(|script| script.funcs.iter().cloned())
+            .collect();
+        funcs.into_iter().collect()
+    }
     pub fn new() -> ScriptContext {
         ScriptContext {
             dag: BTreeMap::new(),
+            order: Vec::new(),
+            failure_policy: FailurePolicy::default(),
         }
     }
+    pub fn set_failure_policy(&mut self, policy: FailurePolicy) {
+        self.failure_policy = policy;
+    }
 }
 
 mod test {
@@ -134,7 +364,9 @@ mod test {
     use super::*;
     use rdd::funcs::RDDFuncResult;
     use rdd::transformers;
-    use rdd::RDDTracker;
+    use rdd::transformers::REGISTRY;
+    use rdd::{RDD, RDDID, RDDTracker};
+    use rdd::typed::{pipe, MapPipe, Source as PipeSource};
 
     def_rdd_func!(
         APlusB (a: u64)[b: u64] -> u64 {
@@ -143,8 +375,13 @@ mod test {
         AGreaterThanN(x: u64)[n: u64] -> bool {
             x > n
         }
+        ANotZero(x: u64)[] ?-> u64 {
+            if *x == 0 { Err("zero") } else { Ok(*x) }
+        }
     );
 
+    def_fused_stage!(AddStage: MapPipe<PipeSource<u64>, APlusB>);
+
     #[derive(Clone)]
     struct Dummy;
     impl RDDComposer for Dummy {
@@ -167,6 +404,32 @@ mod test {
             .map(APlusB{b: 5});
         rdd.compile(&mut context);
         assert_eq!(context.dag.len(), 3);
+        let mut funcs = vec![APlusB::id(), AGreaterThanN::id()];
+        funcs.sort();
+        assert_eq!(context.required_funcs(), funcs);
+        ANotZero::register().unwrap();
+        rdd.try_map(ANotZero{}).compile(&mut context);
+        assert_eq!(context.dag.len(), 4);
+        transformers::aggregate::Aggregate::register();
+        rdd.sum().compile(&mut context);
+        assert_eq!(context.dag.len(), 5);
         let job = context.compile().unwrap();
     }
+
+    #[test]
+    fn dependencies() {
+        let mut context = ScriptContext::new();
+        let map = Dummy{}.map(APlusB{b: 1});
+        let fused = map.fused::<AddStage>(pipe::<u64>().map(APlusB{b: 2}));
+        fused.compile(&mut context);
+        assert_eq!(context.dag[&fused.id].deps, vec![map.id]);
+        // sources are not in the script
+        let first = Dummy{}.fused::<AddStage>(pipe::<u64>().map(APlusB{b: 2}));
+        first.compile(&mut context);
+        assert!(context.dag[&first.id].deps.is_empty());
+        assert_eq!(context.order, vec![map.id, fused.id, first.id]);
+        let sum = fused.sum();
+        sum.compile(&mut context);
+        assert_eq!(context.dag[&sum.id].deps, vec![fused.id]);
+    }
 }
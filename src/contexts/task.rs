@@ -1,3 +1,92 @@
+use rdd::failure::{FailurePolicy, FuncErrorPolicy, RDDFuncError, DeadLetterSink};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Runtime state of a task on the executor, shared by all RDDs computing for it
 pub struct TaskContext {
+    pub task_id: u64,
+    pub policy: FailurePolicy,
+    dead_letter: Option<Arc<DeadLetterSink>>,
+    bad_records: AtomicUsize,
+    failed: AtomicBool,
+    canceled: AtomicBool,
+    failure: Mutex<Option<RDDFuncError>>,
+}
 
-}
\ No newline at end of file
+impl TaskContext {
+    pub fn new(
+        task_id: u64,
+        policy: FailurePolicy,
+        dead_letter: Option<Arc<DeadLetterSink>>
+    ) -> TaskContext {
+        TaskContext {
+            task_id, policy, dead_letter,
+            bad_records: AtomicUsize::new(0),
+            failed: AtomicBool::new(false),
+            canceled: AtomicBool::new(false),
+            failure: Mutex::new(None),
+        }
+    }
+    // Returns true if the bad record can be skipped. Otherwise the task is marked failed with
+    //  the error, and computations should stop.
+    pub fn tolerate(&self, error: RDDFuncError) -> bool {
+        let seen = self.bad_records.fetch_add(1, Ordering::SeqCst) as u64;
+        if seen < self.policy.max_skipped {
+            if let Some(ref sink) = self.dead_letter {
+                sink.put(&error);
+            }
+            return true;
+        }
+        self.fail(error);
+        return false;
+    }
+    // For errors returned by fallible functions. Returns true if the record is skipped.
+    pub fn skip_func_error(&self, error: RDDFuncError) -> bool {
+        match self.policy.on_func_error {
+            FuncErrorPolicy::Skip => {
+                if let Some(ref sink) = self.dead_letter {
+                    sink.put(&error);
+                }
+                true
+            },
+            FuncErrorPolicy::Propagate => {
+                self.fail(error);
+                false
+            }
+        }
+    }
+    pub fn fail(&self, error: RDDFuncError) {
+        let mut failure = self.failure.lock();
+        // keep the first error, following errors are mostly caused by it
+        if failure.is_none() {
+            *failure = Some(error);
+        }
+        self.failed.store(true, Ordering::SeqCst);
+    }
+    pub fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+    // Ask computations of the task to stop. It is cooperative, RDDs check it between records.
+    pub fn cancel(&self) {
+        self.canceled.store(true, Ordering::SeqCst);
+    }
+    pub fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::SeqCst)
+    }
+    // RDDs stop pulling records when this is true
+    pub fn is_interrupted(&self) -> bool {
+        self.is_failed() || self.is_canceled()
+    }
+    // number of bad records seen, including the one failed the task
+    pub fn bad_records(&self) -> usize {
+        self.bad_records.load(Ordering::SeqCst)
+    }
+    // called by the executor after draining the partition iterator
+    pub fn result(&self) -> Result<(), RDDFuncError> {
+        match *self.failure.lock() {
+            Some(ref e) => Err(e.clone()),
+            None => Ok(())
+        }
+    }
+}
@@ -21,6 +21,9 @@ extern crate typedef;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate itertools;
+extern crate num_cpus;
+extern crate toml;
+extern crate serde_json;
 
 
 #[macro_use]
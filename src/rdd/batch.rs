@@ -0,0 +1,561 @@
+// Columnar batch execution.
+// RDDs that support it can process records in batches of columns instead of one `Box<Any>` per
+//  record. A column is a vector of primitives with an optional validity bitmap for nulls.
+// Batch mode is optional. `compute_records` runs a batch stream through RDDs that support batches
+//  and falls back to rows for the ones that don't. Once falls back to rows, the stream stays in
+//  rows because rows cannot be converted back to columns without type information.
+// Batch functions only take one argument, which is the first column of the batch. They are
+//  registered by `register_batch_func` in addition to `RDDFunc::register`.
+
+use rdd::{RDD, RDDID, AnyIter, Partition};
+use rdd::funcs::{RDDFunc, RegisterBatchError, REGISTRY as FuncREG};
+use rdd::failure::{RDDFuncError, RDDFuncErrorKind, panic_message};
+use contexts::task::TaskContext;
+use std::any::Any;
+use std::fmt::Debug;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+pub type BatchIter = Box<Iterator<Item = Batch> + 'static>;
+
+// Batch version of an RDD function. Returns the output column and errors by row index.
+// Rows failed or null in the input are null in the output.
+pub type BatchFunc = fn(&Box<Any>, &Column) -> Result<(Column, Vec<(usize, String)>), String>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitmap {
+    bits: Vec<u64>,
+    len: usize,
+}
+
+impl Bitmap {
+    pub fn new(len: usize, value: bool) -> Bitmap {
+        let fill = if value { !0u64 } else { 0u64 };
+        Bitmap { bits: vec![fill; (len + 63) / 64], len }
+    }
+    pub fn get(&self, i: usize) -> bool {
+        self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+    pub fn set(&mut self, i: usize, value: bool) {
+        if value {
+            self.bits[i / 64] |= 1 << (i % 64);
+        } else {
+            self.bits[i / 64] &= !(1 << (i % 64));
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnData {
+    Bool(Vec<bool>),
+    I32(Vec<i32>),
+    I64(Vec<i64>),
+    U32(Vec<u32>),
+    U64(Vec<u64>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+}
+
+// Primitive types that can be stored in a column
+pub trait ColumnValue: Copy + Default + PartialOrd + Debug + 'static {
+    fn values(data: &ColumnData) -> Option<&Vec<Self>>;
+    fn into_data(values: Vec<Self>) -> ColumnData;
+    fn to_f64(&self) -> f64;
+}
+
+macro_rules! impl_column_value {
+    ($($t: ty, $variant: ident, $to_f64: expr);*) => {
+        $(
+            impl ColumnValue for $t {
+                fn values(data: &ColumnData) -> Option<&Vec<$t>> {
+                    match data {
+                        &ColumnData::$variant(ref values) => Some(values),
+                        _ => None
+                    }
+                }
+                fn into_data(values: Vec<$t>) -> ColumnData {
+                    ColumnData::$variant(values)
+                }
+                fn to_f64(&self) -> f64 {
+                    $to_f64(*self)
+                }
+            }
+        )*
+    };
+}
+
+impl_column_value!(
+    bool, Bool, |v| if v { 1f64 } else { 0f64 };
+    i32, I32, |v| v as f64;
+    i64, I64, |v| v as f64;
+    u32, U32, |v| v as f64;
+    u64, U64, |v| v as f64;
+    f32, F32, |v| v as f64;
+    f64, F64, |v| v
+);
+
+// Types of columns that can be summed, for aggregations
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    I32,
+    I64,
+    U32,
+    U64,
+    F32,
+    F64,
+}
+
+pub trait Summable: ColumnValue {
+    // `None` on overflow, floats never overflow
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn column_type() -> ColumnType;
+}
+
+macro_rules! impl_summable {
+    ($($t: ty, $variant: ident, $add: expr);*) => {
+        $(
+            impl Summable for $t {
+                fn checked_add(self, other: $t) -> Option<$t> {
+                    $add(self, other)
+                }
+                fn column_type() -> ColumnType {
+                    ColumnType::$variant
+                }
+            }
+        )*
+    };
+}
+
+impl_summable!(
+    i32, I32, |a: i32, b| a.checked_add(b);
+    i64, I64, |a: i64, b| a.checked_add(b);
+    u32, U32, |a: u32, b| a.checked_add(b);
+    u64, U64, |a: u64, b| a.checked_add(b);
+    f32, F32, |a: f32, b| Some(a + b);
+    f64, F64, |a: f64, b| Some(a + b)
+);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub data: ColumnData,
+    pub validity: Option<Bitmap>, // `None` when there is no null in the column
+}
+
+impl Column {
+    pub fn new<T: ColumnValue>(values: Vec<T>, validity: Option<Bitmap>) -> Column {
+        Column { data: T::into_data(values), validity }
+    }
+    pub fn len(&self) -> usize {
+        match self.data {
+            ColumnData::Bool(ref v) => v.len(),
+            ColumnData::I32(ref v) => v.len(),
+            ColumnData::I64(ref v) => v.len(),
+            ColumnData::U32(ref v) => v.len(),
+            ColumnData::U64(ref v) => v.len(),
+            ColumnData::F32(ref v) => v.len(),
+            ColumnData::F64(ref v) => v.len(),
+        }
+    }
+    pub fn is_null(&self, i: usize) -> bool {
+        match self.validity {
+            Some(ref bitmap) => !bitmap.get(i),
+            None => false
+        }
+    }
+    pub fn values<T: ColumnValue>(&self) -> Option<&Vec<T>> {
+        T::values(&self.data)
+    }
+    // value at row `i` as a one element tuple, the way RDD functions take their arguments
+    pub fn row(&self, i: usize) -> Option<Box<Any>> {
+        if self.is_null(i) {
+            return None;
+        }
+        Some(match self.data {
+            ColumnData::Bool(ref v) => box (v[i], ) as Box<Any>,
+            ColumnData::I32(ref v) => box (v[i], ) as Box<Any>,
+            ColumnData::I64(ref v) => box (v[i], ) as Box<Any>,
+            ColumnData::U32(ref v) => box (v[i], ) as Box<Any>,
+            ColumnData::U64(ref v) => box (v[i], ) as Box<Any>,
+            ColumnData::F32(ref v) => box (v[i], ) as Box<Any>,
+            ColumnData::F64(ref v) => box (v[i], ) as Box<Any>,
+        })
+    }
+    pub fn debug_row(&self, i: usize) -> String {
+        if self.is_null(i) {
+            return format!("null");
+        }
+        match self.data {
+            ColumnData::Bool(ref v) => format!("{:?}", v[i]),
+            ColumnData::I32(ref v) => format!("{:?}", v[i]),
+            ColumnData::I64(ref v) => format!("{:?}", v[i]),
+            ColumnData::U32(ref v) => format!("{:?}", v[i]),
+            ColumnData::U64(ref v) => format!("{:?}", v[i]),
+            ColumnData::F32(ref v) => format!("{:?}", v[i]),
+            ColumnData::F64(ref v) => format!("{:?}", v[i]),
+        }
+    }
+    pub fn filter(&self, selection: &Vec<bool>) -> Column {
+        fn select<T: Copy>(values: &Vec<T>, selection: &Vec<bool>) -> Vec<T> {
+            values.iter().zip(selection.iter())
+                .filter(|&(_, s)| *s)
+                .map(|(v, _)| *v)
+                .collect()
+        }
+        let data = match self.data {
+            ColumnData::Bool(ref v) => ColumnData::Bool(select(v, selection)),
+            ColumnData::I32(ref v) => ColumnData::I32(select(v, selection)),
+            ColumnData::I64(ref v) => ColumnData::I64(select(v, selection)),
+            ColumnData::U32(ref v) => ColumnData::U32(select(v, selection)),
+            ColumnData::U64(ref v) => ColumnData::U64(select(v, selection)),
+            ColumnData::F32(ref v) => ColumnData::F32(select(v, selection)),
+            ColumnData::F64(ref v) => ColumnData::F64(select(v, selection)),
+        };
+        let validity = self.validity.as_ref().map(|bitmap| {
+            let kept: Vec<bool> = (0..bitmap.len())
+                .filter(|i| selection[*i])
+                .map(|i| bitmap.get(i))
+                .collect();
+            let mut new_bitmap = Bitmap::new(kept.len(), true);
+            for (i, valid) in kept.into_iter().enumerate() {
+                new_bitmap.set(i, valid);
+            }
+            new_bitmap
+        });
+        Column { data, validity }
+    }
+
+    // Aggregations, nulls are ignored
+    pub fn count(&self) -> usize {
+        (0..self.len()).filter(|i| !self.is_null(*i)).count()
+    }
+    // `None` if the column is not of `T` or the sum overflows `T`
+    pub fn sum<T: Summable>(&self) -> Option<T> {
+        self.valid_values::<T>().and_then(|values| {
+            values.into_iter().fold(Some(T::default()), |acc, v| acc.and_then(|acc| acc.checked_add(v)))
+        })
+    }
+    pub fn min<T: ColumnValue>(&self) -> Option<T> {
+        self.valid_values::<T>().and_then(|values| values.into_iter().fold(None, |acc, v| {
+            match acc {
+                Some(m) if m <= v => Some(m),
+                _ => Some(v)
+            }
+        }))
+    }
+    pub fn max<T: ColumnValue>(&self) -> Option<T> {
+        self.valid_values::<T>().and_then(|values| values.into_iter().fold(None, |acc, v| {
+            match acc {
+                Some(m) if m >= v => Some(m),
+                _ => Some(v)
+            }
+        }))
+    }
+    fn valid_values<T: ColumnValue>(&self) -> Option<Vec<T>> {
+        self.values::<T>().map(|values| {
+            values.iter().enumerate()
+                .filter(|&(i, _)| !self.is_null(i))
+                .map(|(_, v)| *v)
+                .collect()
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub columns: Vec<Column>,
+    pub rows: usize,
+}
+
+impl Batch {
+    pub fn new(columns: Vec<Column>) -> Batch {
+        let rows = columns.first().map(|c| c.len()).unwrap_or(0);
+        Batch { columns, rows }
+    }
+    pub fn filter(&self, selection: &Vec<bool>) -> Batch {
+        Batch::new(self.columns.iter().map(|c| c.filter(selection)).collect())
+    }
+    // Rows of the first column for RDDs that only work on rows. Null rows are skipped because
+    //  row mode functions have no notion of null.
+    pub fn into_rows(self) -> AnyIter {
+        let rows = self.rows;
+        let column = self.columns.into_iter().next();
+        Box::new((0..rows).filter_map(move |i| {
+            column.as_ref().and_then(|c| c.row(i))
+        }))
+    }
+}
+
+// Input of an RDD, either rows or batches
+pub enum Records {
+    Rows(AnyIter),
+    Batches(BatchIter),
+}
+
+impl Records {
+    pub fn into_rows(self) -> AnyIter {
+        match self {
+            Records::Rows(iter) => iter,
+            Records::Batches(iter) => Box::new(iter.flat_map(|batch| batch.into_rows()))
+        }
+    }
+}
+
+// For a batch stream given to an RDD that cannot take it. Callers should check `supports_batch`,
+//  this fails the task instead of the worker thread.
+pub fn unsupported(task: &Arc<TaskContext>, rdd_id: RDDID, func_id: u64, partition: usize) -> BatchIter {
+    task.fail(RDDFuncError {
+        rdd_id, func_id, partition,
+        record: format!("<batch stream>"),
+        kind: RDDFuncErrorKind::Failed,
+        message: format!("RDD does not support batch mode")
+    });
+    Box::new(iter::empty())
+}
+
+// Compute the RDD in batch mode if both the RDD and the input support it, otherwise in row mode
+pub fn compute_records(
+    rdd: &RDD,
+    records: Records,
+    partition: &Partition,
+    task: &Arc<TaskContext>
+) -> Records {
+    match records {
+        Records::Batches(iter) => {
+            if rdd.supports_batch() {
+                Records::Batches(rdd.compute_batch(iter, partition, task))
+            } else {
+                let rows = Records::Batches(iter).into_rows();
+                Records::Rows(rdd.compute(rows, partition, task))
+            }
+        },
+        Records::Rows(iter) => Records::Rows(rdd.compute(iter, partition, task))
+    }
+}
+
+// Batch counterpart of `FuncGuard`. Errors are reported to the task context per row. Rows
+//  tolerated by the failure policy become null in the output instead of being dropped, so the
+//  output column still lines up with the input batch.
+pub struct BatchGuard {
+    pub rdd_id: RDDID,
+    pub func_id: u64,
+    pub partition: usize,
+    pub func: BatchFunc,
+    pub closure: Box<Any>,
+    pub task: Arc<TaskContext>,
+    pub fallible: bool,
+}
+
+impl BatchGuard {
+    // returns `None` if the task failed
+    pub fn call(&self, input: &Column) -> Option<Column> {
+        let func = self.func;
+        let closure = &self.closure;
+        match panic::catch_unwind(AssertUnwindSafe(|| func(closure, input))) {
+            Ok(Ok((column, errors))) => {
+                for (row, message) in errors {
+                    let error = self.error(input.debug_row(row), RDDFuncErrorKind::Returned, message);
+                    let tolerated = if self.fallible {
+                        self.task.skip_func_error(error)
+                    } else {
+                        self.task.tolerate(error)
+                    };
+                    if !tolerated {
+                        return None;
+                    }
+                }
+                Some(column)
+            },
+            Ok(Err(message)) => {
+                self.task.fail(self.error(self.batch_record(input), RDDFuncErrorKind::Failed, message));
+                None
+            },
+            Err(payload) => {
+                let message = panic_message(&payload);
+                self.task.fail(self.error(self.batch_record(input), RDDFuncErrorKind::Panic, message));
+                None
+            }
+        }
+    }
+    pub fn error_for_batch(&self, input: &Column, message: String) -> RDDFuncError {
+        self.error(self.batch_record(input), RDDFuncErrorKind::Failed, message)
+    }
+    fn batch_record(&self, input: &Column) -> String {
+        format!("<batch of {} rows>", input.len())
+    }
+    fn error(&self, record: String, kind: RDDFuncErrorKind, message: String) -> RDDFuncError {
+        RDDFuncError {
+            rdd_id: self.rdd_id,
+            func_id: self.func_id,
+            partition: self.partition,
+            record, kind, message
+        }
+    }
+}
+
+pub fn batch_call<F, T>(closure: &Box<Any>, input: &Column)
+    -> Result<(Column, Vec<(usize, String)>), String>
+    where F: RDDFunc<In = (T, )>,
+          F::Out: ColumnValue,
+          T: ColumnValue
+{
+    let closure = match closure.downcast_ref::<F>() {
+        Some(closure) => closure,
+        None => return Err(format!("closure is not for the rdd function {:?}", closure))
+    };
+    let values = match input.values::<T>() {
+        Some(values) => values,
+        None => return Err(format!("Cannot cast column type: {:?}", input.data))
+    };
+    let mut output = Vec::with_capacity(values.len());
+    let mut validity = Bitmap::new(values.len(), true);
+    let mut has_null = false;
+    let mut errors = Vec::new();
+    for (i, v) in values.iter().enumerate() {
+        if input.is_null(i) {
+            output.push(F::Out::default());
+            validity.set(i, false);
+            has_null = true;
+            continue;
+        }
+        match closure.invoke(&(*v, )) {
+            Ok(out) => output.push(out),
+            Err(e) => {
+                output.push(F::Out::default());
+                validity.set(i, false);
+                has_null = true;
+                errors.push((i, e));
+            }
+        }
+    }
+    let validity = if has_null { Some(validity) } else { None };
+    Ok((Column::new(output, validity), errors))
+}
+
+// Register the batch version of a function which takes one primitive argument and returns a
+//  primitive. The function itself should be registered first.
+pub fn register_batch_func<F, T>() -> Result<(), RegisterBatchError>
+    where F: RDDFunc<In = (T, )>,
+          F::Out: ColumnValue,
+          T: ColumnValue
+{
+    FuncREG.register_batch(F::id(), batch_call::<F, T>)
+}
+
+mod test {
+    use super::*;
+    use INIT_LOCK;
+    use rdd::{RDDTracker, UNIT_RDDID};
+    use rdd::funcs::{RDDFunc, RDDFuncResult};
+    use rdd::failure::FailurePolicy;
+    use rdd::transformers::map::Map;
+    use rdd::transformers::filter::Filter;
+    use bifrost::utils::bincode;
+
+    def_rdd_func!(
+        BatchPlusB (a: u64)[b: u64] -> u64 {
+            a + b
+        }
+        BatchIsOdd (x: u64)[] -> bool {
+            x % 2 == 1
+        }
+        BatchNoPanic (x: u64)[] -> u64 {
+            *x
+        }
+        BatchUnregistered (x: u64)[] -> u64 {
+            *x
+        }
+    );
+
+    fn prepare() {
+        let lock = INIT_LOCK.lock();
+        BatchPlusB::register().unwrap();
+        BatchIsOdd::register().unwrap();
+        BatchNoPanic::register().unwrap();
+        register_batch_func::<BatchPlusB, u64>().unwrap();
+        register_batch_func::<BatchIsOdd, u64>().unwrap();
+    }
+
+    fn input() -> Records {
+        let mut validity = Bitmap::new(4, true);
+        validity.set(2, false);
+        let batch = Batch::new(vec![Column::new(vec![1u64, 2, 3, 4], Some(validity))]);
+        Records::Batches(Box::new(vec![batch].into_iter()))
+    }
+
+    #[test]
+    fn batch_map_filter() {
+        prepare();
+        let task = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        let partition = Partition { index: 0, server: 0 };
+        let map = Map::new(UNIT_RDDID, box (
+            BatchPlusB::id(), bincode::serialize(&BatchPlusB { b: 10 }), false)).unwrap();
+        let filter = Filter::new(UNIT_RDDID, box (
+            BatchIsOdd::id(), bincode::serialize(&BatchIsOdd {}), false)).unwrap();
+        let records = compute_records(&*map, input(), &partition, &task);
+        let records = compute_records(&*filter, records, &partition, &task);
+        match records {
+            Records::Batches(mut iter) => {
+                let batch = iter.next().unwrap();
+                let column = &batch.columns[0];
+                // 13 is null in the input
+                assert_eq!(column.values::<u64>().unwrap(), &vec![11]);
+                assert_eq!(column.count(), 1);
+            },
+            Records::Rows(_) => panic!("should stay in batch mode")
+        }
+    }
+
+    #[test]
+    fn fallback_to_rows() {
+        prepare();
+        let task = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        let partition = Partition { index: 0, server: 0 };
+        let map = Map::new(UNIT_RDDID, box (
+            BatchNoPanic::id(), bincode::serialize(&BatchNoPanic {}), false)).unwrap();
+        let rows: Vec<u64> = compute_records(&*map, input(), &partition, &task)
+            .into_rows()
+            .map(|r| *r.downcast_ref::<u64>().unwrap())
+            .collect();
+        assert_eq!(rows, vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn batch_not_supported() {
+        prepare();
+        match register_batch_func::<BatchUnregistered, u64>() {
+            Err(RegisterBatchError::FuncNotFound(id)) => assert_eq!(id, BatchUnregistered::id()),
+            other => panic!("registered batch version of an unknown function: {:?}", other)
+        }
+        let task = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        let partition = Partition { index: 0, server: 0 };
+        let map = Map::new(UNIT_RDDID, box (
+            BatchNoPanic::id(), bincode::serialize(&BatchNoPanic {}), false)).unwrap();
+        // skipping `supports_batch` fails the task but not the thread
+        match input() {
+            Records::Batches(iter) => assert_eq!(map.compute_batch(iter, &partition, &task).count(), 0),
+            Records::Rows(_) => unreachable!()
+        }
+        let error = task.result().err().unwrap();
+        assert_eq!(error.kind, RDDFuncErrorKind::Failed);
+        assert_eq!(error.func_id, BatchNoPanic::id());
+    }
+
+    #[test]
+    fn aggregations() {
+        let mut validity = Bitmap::new(3, true);
+        validity.set(1, false);
+        let column = Column::new(vec![5i64, -100, 2], Some(validity));
+        assert_eq!(column.count(), 2);
+        assert_eq!(column.sum::<i64>(), Some(7i64));
+        assert_eq!(column.min::<i64>(), Some(2));
+        assert_eq!(column.max::<i64>(), Some(5));
+        assert_eq!(column.sum::<u64>(), None);
+        let column = Column::new(vec![u64::max_value(), 1], None);
+        assert_eq!(column.sum::<u64>(), None);
+        assert_eq!(column.max::<u64>(), Some(u64::max_value()));
+    }
+}
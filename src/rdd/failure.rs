@@ -0,0 +1,316 @@
+// Failure isolation for RDD functions.
+// User functions are called through `FuncGuard`, which catches both panics and
+//  `RDDFuncResult::Err` per record and wraps them with the RDD, function and partition they come
+//  from. The task context decides by the job failure policy whether the record can be skipped
+//  (and handed to the dead-letter sink) or the whole task has to fail.
+// Failed tasks are not unwound. `Guarded` just stops yielding records and the executor picks the
+//  error from the task context after draining the iterator.
+
+use rdd::{RDDID, AnyIter};
+use rdd::funcs::RDDFuncResult;
+use contexts::task::TaskContext;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RDDFuncErrorKind {
+    Panic,
+    Failed, // the function cannot be applied on the record
+    Returned, // `Err` returned by the function itself
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RDDFuncError {
+    pub rdd_id: RDDID,
+    pub func_id: u64,
+    pub partition: usize,
+    pub record: String,
+    pub kind: RDDFuncErrorKind,
+    pub message: String,
+}
+
+// What to do with errors returned by fallible functions in `try_map` and `try_filter`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FuncErrorPolicy {
+    Propagate, // fail the task
+    Skip, // drop the record and send it to the dead-letter sink, not counted in `max_skipped`
+}
+
+// Job level policy, transported with the job script
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailurePolicy {
+    // how many bad records a task can skip before it fails, 0 to fail on the first one
+    pub max_skipped: u64,
+    pub on_func_error: FuncErrorPolicy,
+}
+
+impl FailurePolicy {
+    pub fn fail_fast() -> FailurePolicy {
+        FailurePolicy {
+            max_skipped: 0,
+            on_func_error: FuncErrorPolicy::Propagate
+        }
+    }
+}
+
+impl Default for FailurePolicy {
+    fn default() -> FailurePolicy {
+        FailurePolicy::fail_fast()
+    }
+}
+
+// Receives skipped records for later inspection. Implementations must not panic.
+pub trait DeadLetterSink: Send + Sync {
+    fn put(&self, error: &RDDFuncError);
+}
+
+pub struct FuncGuard {
+    pub rdd_id: RDDID,
+    pub func_id: u64,
+    pub partition: usize,
+    pub func: fn(&Box<Any>, &Box<Any>) -> RDDFuncResult,
+    pub debug: fn(&Box<Any>) -> String,
+    pub closure: Box<Any>,
+    pub task: Arc<TaskContext>,
+    // errors returned by fallible functions are handled by `FuncErrorPolicy`
+    pub fallible: bool,
+}
+
+impl FuncGuard {
+    pub fn call(&self, record: &Box<Any>) -> Result<Box<Any>, RDDFuncError> {
+        let func = self.func;
+        let closure = &self.closure;
+        match panic::catch_unwind(AssertUnwindSafe(|| func(closure, record))) {
+            Ok(RDDFuncResult::Ok(res)) => Ok(res),
+            Ok(RDDFuncResult::Err(msg)) => Err(self.error(record, RDDFuncErrorKind::Returned, msg)),
+            Ok(RDDFuncResult::Mismatch(msg)) => Err(self.error(record, RDDFuncErrorKind::Failed, msg)),
+            Err(payload) => {
+                let msg = panic_message(&payload);
+                Err(self.error(record, RDDFuncErrorKind::Panic, msg))
+            }
+        }
+    }
+    pub fn error(&self, record: &Box<Any>, kind: RDDFuncErrorKind, message: String) -> RDDFuncError {
+        let debug = self.debug;
+        let record = panic::catch_unwind(AssertUnwindSafe(|| debug(record)))
+            .unwrap_or_else(|_| format!("<cannot render record>"));
+        RDDFuncError {
+            rdd_id: self.rdd_id,
+            func_id: self.func_id,
+            partition: self.partition,
+            record, kind, message
+        }
+    }
+}
+
+// What the transformer wants to do with a record after the function is called
+pub enum Emit {
+    Record, // yield the input record
+    Result(Box<Any>), // yield the function result
+    Nothing, // drop the record
+}
+
+// Iterator that feeds records through the guarded function.
+// The handler receives the function result and decides what to yield. `Err` from the handler is
+//  treated as a failure of the function on that record.
+pub struct Guarded<H> {
+    iter: AnyIter,
+    guard: FuncGuard,
+    handler: H,
+}
+
+impl <H> Guarded<H>
+    where H: FnMut(Box<Any>) -> Result<Emit, String> + 'static
+{
+    pub fn new(iter: AnyIter, guard: FuncGuard, handler: H) -> Guarded<H> {
+        Guarded { iter, guard, handler }
+    }
+}
+
+impl <H> Iterator for Guarded<H>
+    where H: FnMut(Box<Any>) -> Result<Emit, String>
+{
+    type Item = Box<Any>;
+    fn next(&mut self) -> Option<Box<Any>> {
+        loop {
+            if self.guard.task.is_interrupted() {
+                return None;
+            }
+            let record = match self.iter.next() {
+                Some(record) => record,
+                None => return None
+            };
+            let error = match self.guard.call(&record) {
+                Ok(res) => match (self.handler)(res) {
+                    Ok(Emit::Record) => return Some(record),
+                    Ok(Emit::Result(out)) => return Some(out),
+                    Ok(Emit::Nothing) => continue,
+                    Err(msg) => self.guard.error(&record, RDDFuncErrorKind::Failed, msg)
+                },
+                Err(e) => e
+            };
+            // only errors the function returned itself are subject to `FuncErrorPolicy`
+            if self.guard.fallible && error.kind == RDDFuncErrorKind::Returned {
+                if self.guard.task.skip_func_error(error) {
+                    continue;
+                } else {
+                    return None;
+                }
+            }
+            if !self.guard.task.tolerate(error) {
+                return None;
+            }
+        }
+    }
+}
+
+pub fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        format!("unknown panic")
+    }
+}
+
+mod test {
+    use super::*;
+    use INIT_LOCK;
+    use rdd::{RDD, RDDTracker, Partition, UNIT_RDDID};
+    use rdd::funcs::{RDDFunc, RDDFuncResult, to_any};
+    use rdd::transformers::map::Map;
+    use rdd::transformers::filter::Filter;
+    use contexts::task::TaskContext;
+    use bifrost::utils::bincode;
+    use parking_lot::Mutex;
+
+    def_rdd_func!(
+        NDivA (a: u64)[n: u64] -> u64 {
+            n / a
+        }
+        CheckedNDivA (a: u64)[n: u64] ?-> u64 {
+            n.checked_div(*a).ok_or(format!("divide {} by zero", n))
+        }
+        AAboveN (a: u64)[n: u64] -> bool {
+            a > n
+        }
+    );
+
+    struct Collector {
+        errors: Mutex<Vec<RDDFuncError>>
+    }
+
+    impl DeadLetterSink for Collector {
+        fn put(&self, error: &RDDFuncError) {
+            self.errors.lock().push(error.clone());
+        }
+    }
+
+    fn run(task: &Arc<TaskContext>, input: Vec<u64>) -> Vec<u64> {
+        {
+            let lock = INIT_LOCK.lock();
+            NDivA::register().unwrap();
+        }
+        let closure_data = bincode::serialize(&NDivA { n: 4 });
+        let map = Map::new(UNIT_RDDID, box (NDivA::id(), closure_data, false)).unwrap();
+        let iter: AnyIter = Box::new(input.into_iter().map(|x| to_any((x,))));
+        map.compute(iter, &Partition { index: 3, server: 0 }, task)
+            .map(|x| *x.downcast_ref::<u64>().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn map_then_filter() {
+        {
+            let lock = INIT_LOCK.lock();
+            NDivA::register().unwrap();
+            AAboveN::register().unwrap();
+        }
+        let task = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        let partition = Partition { index: 0, server: 0 };
+        let map = Map::new(UNIT_RDDID, box (NDivA::id(), bincode::serialize(&NDivA { n: 12 }), false)).unwrap();
+        let filter = Filter::new(UNIT_RDDID, box (AAboveN::id(), bincode::serialize(&AAboveN { n: 3 }), false)).unwrap();
+        // the filter gets the bare results of the map
+        let iter: AnyIter = Box::new(vec![1u64, 2, 3, 4].into_iter().map(|x| to_any((x,))));
+        let res: Vec<u64> = filter.compute(map.compute(iter, &partition, &task), &partition, &task)
+            .map(|x| *x.downcast_ref::<u64>().unwrap())
+            .collect();
+        assert_eq!(res, vec![12, 6, 4]);
+        assert!(task.result().is_ok());
+    }
+
+    #[test]
+    fn panic_fails_task() {
+        let task = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        let res = run(&task, vec![1, 0, 2]);
+        assert_eq!(res, vec![4]);
+        let error = task.result().err().unwrap();
+        assert_eq!(error.kind, RDDFuncErrorKind::Panic);
+        assert_eq!(error.func_id, NDivA::id());
+        assert_eq!(error.partition, 3);
+        assert_eq!(error.record, "(0,)");
+    }
+
+    #[test]
+    fn skip_to_dead_letter() {
+        let sink = Arc::new(Collector { errors: Mutex::new(Vec::new()) });
+        let mut policy = FailurePolicy::fail_fast();
+        policy.max_skipped = 1;
+        let task = Arc::new(TaskContext::new(1, policy, Some(sink.clone())));
+        let res = run(&task, vec![1, 0, 2]);
+        assert_eq!(res, vec![4, 2]);
+        assert!(task.result().is_ok());
+        assert_eq!(sink.errors.lock().len(), 1);
+    }
+
+    fn skip_policy() -> FailurePolicy {
+        FailurePolicy {
+            max_skipped: 0,
+            on_func_error: FuncErrorPolicy::Skip
+        }
+    }
+
+    #[test]
+    fn skip_returned_errors() {
+        {
+            let lock = INIT_LOCK.lock();
+            CheckedNDivA::register().unwrap();
+        }
+        let sink = Arc::new(Collector { errors: Mutex::new(Vec::new()) });
+        let task = Arc::new(TaskContext::new(1, skip_policy(), Some(sink.clone())));
+        let closure_data = bincode::serialize(&CheckedNDivA { n: 4 });
+        let map = Map::new(UNIT_RDDID, box (CheckedNDivA::id(), closure_data, true)).unwrap();
+        let iter: AnyIter = Box::new(vec![1u64, 0, 2].into_iter().map(|x| to_any((x,))));
+        let res: Vec<u64> = map.compute(iter, &Partition { index: 0, server: 0 }, &task)
+            .map(|x| *x.downcast_ref::<u64>().unwrap())
+            .collect();
+        assert_eq!(res, vec![4, 2]);
+        assert!(task.result().is_ok());
+        let errors = sink.errors.lock();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, RDDFuncErrorKind::Returned);
+    }
+
+    #[test]
+    fn skip_does_not_cover_misuse() {
+        {
+            let lock = INIT_LOCK.lock();
+            CheckedNDivA::register().unwrap();
+        }
+        // the function does not return bool, which is not an error of the function itself
+        let task = Arc::new(TaskContext::new(1, skip_policy(), None));
+        let closure_data = bincode::serialize(&CheckedNDivA { n: 4 });
+        let filter = Filter::new(UNIT_RDDID, box (CheckedNDivA::id(), closure_data.clone(), true)).unwrap();
+        let iter: AnyIter = Box::new(vec![1u64, 2].into_iter().map(|x| to_any((x,))));
+        assert_eq!(filter.compute(iter, &Partition { index: 0, server: 0 }, &task).count(), 0);
+        assert_eq!(task.result().err().unwrap().kind, RDDFuncErrorKind::Failed);
+        // records of the wrong type
+        let task = Arc::new(TaskContext::new(1, skip_policy(), None));
+        let map = Map::new(UNIT_RDDID, box (CheckedNDivA::id(), closure_data, true)).unwrap();
+        let iter: AnyIter = Box::new(vec![1u32].into_iter().map(|x| to_any((x,))));
+        assert_eq!(map.compute(iter, &Partition { index: 0, server: 0 }, &task).count(), 0);
+        assert_eq!(task.result().err().unwrap().kind, RDDFuncErrorKind::Failed);
+    }
+}
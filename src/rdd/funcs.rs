@@ -3,6 +3,7 @@ use std::collections::BTreeMap;
 use std::mem::transmute;
 use serde::{Serialize, Deserialize};
 use std::any::Any;
+use rdd::batch::BatchFunc;
 pub use INIT_LOCK;
 
 // RDD functions will compiled at application compile time. The only way to get the the function at
@@ -23,12 +24,57 @@ pub struct RegistryRDDFunc {
     pub func: fn(&Box<Any>, &Box<Any>) -> RDDFuncResult,
     pub decode: fn(&Vec<u8>) -> Box<Any>,
     pub clone: fn(&Box<Any>) -> Box<Any>,
+    pub debug: fn(&Box<Any>) -> String,
+    pub meta: RDDFuncMeta,
+    pub batch: Option<BatchFunc>,
+}
+
+// Static description of an RDD function, generated by `def_rdd_func!` from the function signature.
+// Type names are only their source text so they are meant for diagnostics, not type checking.
+#[derive(Clone, Copy, Debug)]
+pub struct RDDFuncMeta {
+    pub name: &'static str,
+    pub module: &'static str,
+    pub input: &'static [&'static str],
+    pub output: &'static str,
+    pub closure: &'static [&'static str],
+}
+
+// Owned version of the function metadata that can be sent to other nodes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RDDFuncInfo {
+    pub id: u64,
+    pub name: String,
+    pub module: String,
+    pub input: Vec<String>,
+    pub output: String,
+    pub closure: Vec<String>,
+}
+
+impl RegistryRDDFunc {
+    pub fn info(&self) -> RDDFuncInfo {
+        let meta = &self.meta;
+        RDDFuncInfo {
+            id: self.id,
+            name: meta.name.to_string(),
+            module: meta.module.to_string(),
+            input: meta.input.iter().map(|t| t.to_string()).collect(),
+            output: meta.output.to_string(),
+            closure: meta.closure.iter().map(|f| f.to_string()).collect(),
+        }
+    }
 }
 
 pub struct Registry {
     map: RefCell<BTreeMap<u64, RegistryRDDFunc>>
 }
 
+#[derive(Debug)]
+pub enum RegisterBatchError {
+    Borrowed(BorrowMutError),
+    FuncNotFound(u64), // the function itself has to be registered first
+}
+
 impl Registry {
     pub fn new() -> Registry {
         Registry {
@@ -38,16 +84,37 @@ impl Registry {
     pub fn register(
         &self, id: u64,
         func: fn(&Box<Any>, &Box<Any>) -> RDDFuncResult,
-        decode: fn(&Vec<u8>) -> Box<Any>, clone: fn(&Box<Any>) -> Box<Any>
+        decode: fn(&Vec<u8>) -> Box<Any>, clone: fn(&Box<Any>) -> Box<Any>,
+        debug: fn(&Box<Any>) -> String, meta: RDDFuncMeta
     ) -> Result<(), BorrowMutError> {
         let mut m = self.map.try_borrow_mut()?;
-        m.insert(id, RegistryRDDFunc { id, func, decode, clone });
+        m.insert(id, RegistryRDDFunc { id, func, decode, clone, debug, meta, batch: None });
         Ok(())
     }
+    // attach batch version to a registered function, see `rdd::batch`
+    pub fn register_batch(&self, id: u64, batch: BatchFunc) -> Result<(), RegisterBatchError> {
+        let mut m = self.map.try_borrow_mut().map_err(RegisterBatchError::Borrowed)?;
+        match m.get_mut(&id) {
+            Some(func) => {
+                func.batch = Some(batch);
+                Ok(())
+            },
+            None => Err(RegisterBatchError::FuncNotFound(id))
+        }
+    }
     pub fn get(&self, id: u64) -> Option<RegistryRDDFunc> {
         let m = self.map.borrow();
         m.get(&id).cloned()
     }
+    pub fn list(&self) -> Vec<RDDFuncInfo> {
+        let m = self.map.borrow();
+        m.values().map(|f| f.info()).collect()
+    }
+    // returns ids in `required` that are not in this registry
+    pub fn missing(&self, required: &Vec<u64>) -> Vec<u64> {
+        let m = self.map.borrow();
+        required.iter().filter(|id| !m.contains_key(id)).cloned().collect()
+    }
 }
 
 unsafe impl Sync for Registry {}
@@ -59,7 +126,8 @@ lazy_static! {
 #[derive(Debug)]
 pub enum RDDFuncResult {
     Ok(Box<Any>),
-    Err(String)
+    Err(String), // returned by the function body
+    Mismatch(String) // the closure or arguments are not for the function
 }
 
 impl RDDFuncResult {
@@ -76,7 +144,7 @@ impl RDDFuncResult {
                     }
                 }
             },
-            &RDDFuncResult::Err(ref e) => {
+            &RDDFuncResult::Err(ref e) | &RDDFuncResult::Mismatch(ref e) => {
                 return Err(format!("RDD result is error: {}", e))
             }
         }
@@ -86,7 +154,7 @@ impl RDDFuncResult {
             RDDFuncResult::Ok(data) => {
                 data
             },
-            RDDFuncResult::Err(err) => {
+            RDDFuncResult::Err(err) | RDDFuncResult::Mismatch(err) => {
                 panic!("cannot unwrap rdd func result: {}", err);
             }
         }
@@ -96,10 +164,14 @@ impl RDDFuncResult {
 pub trait RDDFunc: Serialize + Sized + Clone + 'static {
     type Out;
     type In;
+    fn invoke(&self, args: &Self::In) -> Result<Self::Out, String>;
     fn call(closure: &Box<Any>, args: &Box<Any>) -> RDDFuncResult;
     fn id() -> u64;
     fn decode(bytes: &Vec<u8>) -> Box<Any>;
     fn boxed_clone(closure: &Box<Any>) -> Box<Any>;
+    // debug rendering of the arguments for error reports, arguments types must implement `Debug`
+    fn debug_args(args: &Box<Any>) -> String;
+    fn meta() -> RDDFuncMeta;
     fn into_any(self) -> Box<Any> {
         Box::new(self)
     }
@@ -109,6 +181,8 @@ pub trait RDDFunc: Serialize + Sized + Clone + 'static {
             Self::call,
             Self::decode,
             Self::boxed_clone,
+            Self::debug_args,
+            Self::meta(),
         )
     }
 }
@@ -133,6 +207,9 @@ mod test {
         AMultC (a: u32)[c: u32] -> u32 {
             a * c
         }
+        ADivC (a: u32)[c: u32] ?-> u32 {
+            if *c == 0 { Err(format!("divide {} by zero", a)) } else { Ok(a / c) }
+        }
     );
     fn prepare_registry() {
         let lock = INIT_LOCK.lock();
@@ -150,6 +227,20 @@ mod test {
         assert_eq!(APlusB::call(&box APlusB{}.into_any(), &to_any((1 as u64, 2 as u64))).cast::<u64>().unwrap(), 3);
         assert_eq!(AMultB::call(&box AMultB{}.into_any(), &to_any((2 as u32, 3 as u32))).cast::<u32>().unwrap(), 6);
         assert_eq!(AMultC::call(&box AMultC{c: 5}.into_any(), &to_any((2 as u32,))).cast::<u32>().unwrap(), 10);
+        // bare values as maps emit them, only for single argument functions
+        assert_eq!(AMultC::call(&box AMultC{c: 5}.into_any(), &to_any(2 as u32)).cast::<u32>().unwrap(), 10);
+        match APlusB::call(&box APlusB{}.into_any(), &to_any(1 as u64)) {
+            RDDFuncResult::Mismatch(_) => {},
+            _ => panic!("should not take a bare value")
+        }
+    }
+    #[test]
+    fn fallible_rdd() {
+        assert_eq!(ADivC::call(&box ADivC{c: 2}.into_any(), &to_any((6 as u32,))).cast::<u32>().unwrap(), 3);
+        match ADivC::call(&box ADivC{c: 0}.into_any(), &to_any((6 as u32,))) {
+            RDDFuncResult::Err(e) => assert_eq!(e, "divide 6 by zero"),
+            _ => panic!("should fail")
+        }
     }
     #[test]
     fn register_and_invoke_from_registry_by_ptr() {
@@ -183,4 +274,15 @@ mod test {
         assert_eq!(&ai, a_de);
         assert_eq!(&ci, c_de);
     }
+    #[test]
+    fn list_registry() {
+        prepare_registry();
+        let funcs = REGISTRY.list();
+        let c_info = funcs.iter().find(|f| f.id == AMultC::id()).unwrap();
+        assert_eq!(c_info.name, "AMultC");
+        assert_eq!(c_info.input, vec!["u32".to_string()]);
+        assert_eq!(c_info.output, "u32");
+        assert_eq!(c_info.closure, vec!["c".to_string()]);
+        assert_eq!(REGISTRY.missing(&vec![APlusB::id(), 0]), vec![0]);
+    }
 }
\ No newline at end of file
@@ -1,11 +1,11 @@
 #[macro_export]
 macro_rules! impl_rdd_trans_tracker {
-    ($name: ident ($($carg:ident : $cargt: ty),*) $constructor:block) => {
+    ($name: ident [$rdd_id: ident] ($($carg:ident : $cargt: ty),*) $constructor:block) => {
         impl RDDTracker for $name {
             fn trans_id() -> u64 {
                 ident_id!($name)
             }
-            fn new(args: Box<Any>) -> Result<Box<RDD>, String> {
+            fn new($rdd_id: RDDID, args: Box<Any>) -> Result<Box<RDD>, String> {
                 match args.downcast_ref::<( $($cargt,)* )>() {
                     Some(args) => {
                         let &( $(ref $carg,)* ) = args;
@@ -43,60 +43,176 @@ macro_rules! ident_id {
     };
 }
 
+// Define RDD functions. Each function is a struct holding the enclosed variables as the closure.
+// The body is generated into `invoke` for the typed path, `call` is the dynamic typed wrapper
+//  of it used through the registry.
+// `Name (args)[enclosed] -> T { .. }` defines an infallible function.
+// `Name (args)[enclosed] ?-> T { .. }` defines a fallible function which body evaluates to
+//  `Result<T, E>` where `E: Display`. `Err` is turned into `RDDFuncResult::Err` with the message.
+// Both forms can be mixed in one invocation.
+// Records from sources are argument tuples, but maps emit their results bare. Functions of one
+//  argument take either, so they can follow a map.
 #[macro_export]
 macro_rules! def_rdd_func {
-    ($($name: ident($($farg:ident : $argt: ty),*)
-                   [$($enclosed:ident : $ety: ty),*] -> $rt:ty $body:block)*) =>
+    () => {};
+    ($name: ident($($farg:ident : $argt: ty),*)
+                   [$($enclosed:ident : $ety: ty),*] -> $rt:ty $body:block $($rest:tt)*) =>
     {
-        $(
-            #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
-            pub struct $name {
-               $(pub $enclosed: $ety),*
-            }
-            impl RDDFunc for $name {
+        def_rdd_func!(@impl $name ($($farg: $argt),*) [$($enclosed: $ety),*] -> $rt {
+            Ok($body as $rt)
+        });
+        def_rdd_func!($($rest)*);
+    };
+    ($name: ident($($farg:ident : $argt: ty),*)
+                   [$($enclosed:ident : $ety: ty),*] ?-> $rt:ty $body:block $($rest:tt)*) =>
+    {
+        def_rdd_func!(@impl $name ($($farg: $argt),*) [$($enclosed: $ety),*] -> $rt {
+            let res: Result<$rt, _> = $body;
+            res.map_err(|e| format!("{}", e))
+        });
+        def_rdd_func!($($rest)*);
+    };
+    (@bare $closure:ident $args:ident $name:ident ($farg:ident : $argt:ty)
+                   [$($enclosed:ident),*] $result:block) =>
+    {
+        match $args.downcast_ref::<$argt>() {
+            Some($farg) => {
+                let &$name { $(ref $enclosed),* } = $closure;
+                Some($result)
+            },
+            None => None
+        }
+    };
+    (@bare $closure:ident $args:ident $name:ident ($($farg:ident : $argt:ty),*)
+                   [$($enclosed:ident),*] $result:block) =>
+    {
+        None
+    };
+    (@debug $args:ident ($farg:ident : $argt:ty)) => {
+        $args.downcast_ref::<$argt>().map(|arg| format!("{:?}", arg))
+    };
+    (@debug $args:ident ($($farg:ident : $argt:ty),*)) => {
+        None
+    };
+    (@impl $name: ident($($farg:ident : $argt: ty),*)
+                   [$($enclosed:ident : $ety: ty),*] -> $rt:ty $result:block) =>
+    {
+        #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+        pub struct $name {
+           $(pub $enclosed: $ety),*
+        }
+        impl RDDFunc for $name {
 
-                type Out = $rt;
-                type In = ( $($argt,)* );
+            type Out = $rt;
+            type In = ( $($argt,)* );
 
-                fn call(closure: &Box<::std::any::Any>, args: &Box<::std::any::Any>)
-                    -> RDDFuncResult
-                {
-                    match closure.downcast_ref::<Self>() {
-                        Some(_closure) => {
-                             match args.downcast_ref::<( $($argt,)* )>() {
-                                Some(args) => {
-                                    let &( $(ref $farg,)* ) = args;
-                                    let ( $(ref $enclosed,)* ) = ( $(_closure.$enclosed,)* );
-                                    return RDDFuncResult::Ok(Box::new($body as $rt));
-                                },
-                                None => {
-                                    return RDDFuncResult::Err(format!("Cannot cast type: {:?}", args));
-                                }
+            fn invoke(&self, args: &( $($argt,)* )) -> Result<$rt, String> {
+                let &( $(ref $farg,)* ) = args;
+                let &$name { $(ref $enclosed),* } = self;
+                $result
+            }
+            fn call(closure: &Box<::std::any::Any>, args: &Box<::std::any::Any>)
+                -> RDDFuncResult
+            {
+                match closure.downcast_ref::<Self>() {
+                    Some(closure) => {
+                         match args.downcast_ref::<( $($argt,)* )>() {
+                            Some(args) => {
+                                return match closure.invoke(args) {
+                                    Ok(res) => RDDFuncResult::Ok(Box::new(res)),
+                                    Err(e) => RDDFuncResult::Err(e)
+                                };
+                            },
+                            None => {
+                                let bare: Option<Result<$rt, String>> = def_rdd_func!(
+                                    @bare closure args $name ($($farg: $argt),*) [$($enclosed),*] $result
+                                );
+                                return match bare {
+                                    Some(Ok(res)) => RDDFuncResult::Ok(Box::new(res)),
+                                    Some(Err(e)) => RDDFuncResult::Err(e),
+                                    None => RDDFuncResult::Mismatch(format!("Cannot cast type: {:?}", args))
+                                };
                             }
-                        },
-                        None => {
-                          return RDDFuncResult::Err(format!("closure is not for the rdd function {:?}", closure));
                         }
+                    },
+                    None => {
+                      return RDDFuncResult::Mismatch(format!("closure is not for the rdd function {:?}", closure));
                     }
                 }
-                fn id() -> u64 {
-                    ident_id!($name)
+            }
+            fn id() -> u64 {
+                ident_id!($name)
+            }
+            fn decode(bytes: &Vec<u8>) -> Box<::std::any::Any>{
+                let closure: Self = ::bifrost::utils::bincode::deserialize(bytes);
+                Box::new(closure)
+            }
+            fn debug_args(args: &Box<::std::any::Any>) -> String {
+                match args.downcast_ref::<( $($argt,)* )>() {
+                    Some(args) => format!("{:?}", args),
+                    None => def_rdd_func!(@debug args ($($farg: $argt),*))
+                        .unwrap_or_else(|| format!("{:?}", args))
                 }
-                fn decode(bytes: &Vec<u8>) -> Box<::std::any::Any>{
-                    let closure: Self = ::bifrost::utils::bincode::deserialize(bytes);
-                    Box::new(closure)
+            }
+            fn meta() -> $crate::rdd::funcs::RDDFuncMeta {
+                $crate::rdd::funcs::RDDFuncMeta {
+                    name: stringify!($name),
+                    module: module_path!(),
+                    input: &[$(stringify!($argt)),*],
+                    output: stringify!($rt),
+                    closure: &[$(stringify!($enclosed)),*],
                 }
-                fn boxed_clone(closure: &Box<::std::any::Any>) -> Box<::std::any::Any> {
-                    match closure.downcast_ref::<Self>() {
-                        Some(closure) => {
-                            box closure.clone()
-                        },
-                        None => {
-                            panic!(format!("closure is not for the rdd function {:?}", closure));
-                        }
+            }
+            fn boxed_clone(closure: &Box<::std::any::Any>) -> Box<::std::any::Any> {
+                match closure.downcast_ref::<Self>() {
+                    Some(closure) => {
+                        box closure.clone()
+                    },
+                    None => {
+                        panic!(format!("closure is not for the rdd function {:?}", closure));
                     }
                 }
             }
-        )*
+        }
+    };
+}
+
+// Register a typed pipeline as a fused stage transformer, for example
+//  `def_fused_stage!(AddThenFilter: MapPipe<FilterPipe<Source<u64>, AGreaterThanN>, APlusB>);`
+// It needs the same imports in scope as other transformers.
+#[macro_export]
+macro_rules! def_fused_stage {
+    ($name: ident : $pipe: ty) => {
+        pub struct $name {
+            stage: $crate::rdd::typed::FusedStage<$pipe>
+        }
+        impl_rdd_trans_tracker!{
+            $name [id] (pipe_data: Vec<u8>) {
+                let pipe: $pipe = ::bifrost::utils::bincode::deserialize(pipe_data);
+                Ok($name { stage: $crate::rdd::typed::FusedStage { id, pipe } })
+            }
+        }
+        impl $crate::rdd::typed::StageDef for $name {
+            type Pipe = $pipe;
+        }
+        impl RDD for $name {
+            fn compute(
+                &self,
+                iter: $crate::rdd::AnyIter,
+                partition: &$crate::rdd::Partition,
+                task: &::std::sync::Arc<$crate::contexts::task::TaskContext>,
+            ) -> $crate::rdd::AnyIter {
+                self.stage.compute(iter, partition, task)
+            }
+            fn get_dependencies(&self) -> &Vec<&Box<RDD>> {
+                unimplemented!()
+            }
+            fn get_partitioner(&self) -> &Box<$crate::scheduler::dag::partitioner::Partitioner> {
+                unimplemented!()
+            }
+            fn id(&self) -> RDDID {
+                self.stage.id
+            }
+        }
     };
-}
\ No newline at end of file
+}
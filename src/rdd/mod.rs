@@ -1,20 +1,27 @@
 use self::funcs::RDDFunc;
 use super::contexts::JobContext;
+use super::contexts::task::TaskContext;
 use scheduler::dag::partitioner::Partitioner;
+use scheduler::locality::PreferredLocation;
+use self::batch::BatchIter;
 use std::any::{Any, TypeId};
 use uuid::Uuid;
+use std::sync::Arc;
 #[macro_use]
 pub mod macros;
 pub mod funcs;
 pub mod script;
 pub mod transformers;
 pub mod composer;
+pub mod failure;
+pub mod typed;
+pub mod batch;
 
 pub type AnyIter = Box<Iterator<Item = Box<Any + 'static>> + 'static>;
 
 #[derive(
     Ord, PartialOrd, PartialEq, Eq, Hash,
-    Copy, Clone,
+    Copy, Clone, Debug,
     Serialize, Deserialize
 )]
 pub struct RDDID {
@@ -32,9 +39,18 @@ impl RDDID {
     }
 }
 
+// Records are argument tuples as sources and filters pass them, or bare values as maps return
+//  them. Take the value out of either.
+pub fn unwrap_record<T: Any>(record: Box<Any>) -> Result<T, Box<Any>> {
+    match record.downcast::<(T, )>() {
+        Ok(args) => Ok(args.0),
+        Err(record) => record.downcast::<T>().map(|value| *value)
+    }
+}
+
 pub struct Partition {
     pub index: usize,
-    pub server: u64,
+    pub server: u64, // owner of the data of the partition, 0 for none
 }
 
 pub trait RDD {
@@ -42,7 +58,26 @@ pub trait RDD {
         &self,
         iter: AnyIter,
         partition: &Partition,
+        task: &Arc<TaskContext>,
     ) -> AnyIter;
+    // Batch mode is optional. RDDs that return true here must implement `compute_batch`, others
+    //  fail the task if they are given batches.
+    fn supports_batch(&self) -> bool {
+        false
+    }
+    fn compute_batch(
+        &self,
+        iter: BatchIter,
+        partition: &Partition,
+        task: &Arc<TaskContext>,
+    ) -> BatchIter {
+        batch::unsupported(task, self.id(), 0, partition.index)
+    }
+    // Servers this partition is best computed on, like neb data owners. Cached blocks and shuffle
+    //  outputs are reported by location providers, see `scheduler::locality`.
+    fn preferred_locations(&self, partition: &Partition) -> Vec<PreferredLocation> {
+        Vec::new()
+    }
     fn get_dependencies(&self) -> &Vec<&Box<RDD>>;
     fn get_partitioner(&self) -> &Box<Partitioner>;
     fn id(&self) -> RDDID;
@@ -50,7 +85,7 @@ pub trait RDD {
 
 pub trait RDDTracker: RDD + Sized {
     fn trans_id() -> u64;
-    fn new(params: Box<Any>) -> Result<Box<RDD>, String>;
+    fn new(id: RDDID, params: Box<Any>) -> Result<Box<RDD>, String>;
     fn construct_arg (data: &Vec<u8>) -> Box<Any>;
     fn register();
 }
@@ -7,6 +7,7 @@ pub struct RDDScript {
     pub rdd_id: RDDID,
     pub ctx: RDDScriptCtx,
     pub deps: Vec<RDDID>,
+    pub funcs: Vec<u64>, // ids of RDD functions this script requires from the function registry
 }
 
 #[derive(Serialize, Deserialize)]
@@ -23,7 +24,7 @@ impl RDDScript {
             RDDScriptCtx::Transformer {id, ref data} => {
                 let reg_trans = REGISTRY.get(id).ok_or("cannot find rdd transformer")?;
                 let args = (reg_trans.construct_args)(data);
-                (reg_trans.construct)(args)
+                (reg_trans.construct)(self.rdd_id, args)
             }
         }
     }
@@ -0,0 +1,240 @@
+// Aggregates all records of a partition into one. Input in batches is aggregated column by column
+//  without going through rows. Nulls are ignored, results of partitions are not combined.
+use rdd::{RDD, RDDTracker, RDDID, Partition, AnyIter, unwrap_record};
+use rdd::transformers::{Registry, REGISTRY, RegedTrans};
+use rdd::batch::{Batch, BatchIter, Column, ColumnType, Summable};
+use rdd::failure::{RDDFuncError, RDDFuncErrorKind};
+use contexts::task::TaskContext;
+use scheduler::dag::partitioner::Partitioner;
+use std::any::Any;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateOp {
+    Count, // always `u64`
+    Sum,
+    Min,
+    Max,
+}
+
+pub struct Aggregate {
+    id: RDDID,
+    op: AggregateOp,
+    column_type: ColumnType,
+}
+
+impl_rdd_trans_tracker!{
+    Aggregate [id] (op: AggregateOp, column_type: ColumnType) {
+        Ok(Aggregate { id, op: *op, column_type: *column_type })
+    }
+}
+
+// Result of the aggregation as a single row column, `None` if there is no value for min or max
+fn reduce<T, I>(op: AggregateOp, columns: I) -> Result<Option<Column>, String>
+    where T: Summable, I: Iterator<Item = Column>
+{
+    let mut count = 0u64;
+    let mut acc: Option<T> = None;
+    for column in columns {
+        if column.values::<T>().is_none() {
+            return Err(format!("Cannot cast column type for aggregation: {:?}", column.data));
+        }
+        count += column.count() as u64;
+        let partial = match op {
+            AggregateOp::Count => continue,
+            AggregateOp::Sum => match column.sum::<T>() {
+                Some(sum) => Some(sum),
+                None => return Err(format!("Sum overflows"))
+            },
+            AggregateOp::Min => column.min::<T>(),
+            AggregateOp::Max => column.max::<T>(),
+        };
+        acc = match (acc, partial) {
+            (Some(a), Some(b)) => Some(match op {
+                AggregateOp::Sum => a.checked_add(b).ok_or(format!("Sum overflows"))?,
+                AggregateOp::Min => if b < a { b } else { a },
+                _ => if b > a { b } else { a }
+            }),
+            (a, None) => a,
+            (None, b) => b
+        };
+    }
+    Ok(match op {
+        AggregateOp::Count => Some(Column::new(vec![count], None)),
+        AggregateOp::Sum => Some(Column::new(vec![acc.unwrap_or(T::default())], None)),
+        _ => acc.map(|v| Column::new(vec![v], None))
+    })
+}
+
+fn rows_to_column<T: Summable>(rows: AnyIter) -> Result<Column, String> {
+    let values: Result<Vec<T>, String> = rows
+        .map(|row| unwrap_record::<T>(row)
+            .map_err(|row| format!("Cannot cast type for aggregation: {:?}", row)))
+        .collect();
+    Ok(Column::new(values?, None))
+}
+
+impl Aggregate {
+    fn reduce<I>(&self, columns: I) -> Result<Option<Column>, String>
+        where I: Iterator<Item = Column>
+    {
+        match self.column_type {
+            ColumnType::I32 => reduce::<i32, I>(self.op, columns),
+            ColumnType::I64 => reduce::<i64, I>(self.op, columns),
+            ColumnType::U32 => reduce::<u32, I>(self.op, columns),
+            ColumnType::U64 => reduce::<u64, I>(self.op, columns),
+            ColumnType::F32 => reduce::<f32, I>(self.op, columns),
+            ColumnType::F64 => reduce::<f64, I>(self.op, columns),
+        }
+    }
+    fn column(&self, rows: AnyIter) -> Result<Column, String> {
+        match self.column_type {
+            ColumnType::I32 => rows_to_column::<i32>(rows),
+            ColumnType::I64 => rows_to_column::<i64>(rows),
+            ColumnType::U32 => rows_to_column::<u32>(rows),
+            ColumnType::U64 => rows_to_column::<u64>(rows),
+            ColumnType::F32 => rows_to_column::<f32>(rows),
+            ColumnType::F64 => rows_to_column::<f64>(rows),
+        }
+    }
+    fn fail(&self, partition: usize, task: &TaskContext, message: String) {
+        task.fail(RDDFuncError {
+            rdd_id: self.id,
+            func_id: 0,
+            partition,
+            record: format!("<partition>"),
+            kind: RDDFuncErrorKind::Failed,
+            message
+        });
+    }
+}
+
+impl RDD for Aggregate {
+    fn compute (
+        &self,
+        iter: AnyIter,
+        partition: &Partition,
+        task: &Arc<TaskContext>,
+    ) -> AnyIter {
+        let column = {
+            let task = task.clone();
+            self.column(Box::new(iter.take_while(move |_| !task.is_interrupted())))
+        };
+        let result = column.and_then(|column| self.reduce(vec![column].into_iter()));
+        match result {
+            Ok(Some(column)) => Box::new(column.row(0).into_iter()),
+            Ok(None) => Box::new(None::<Box<Any>>.into_iter()),
+            Err(message) => {
+                self.fail(partition.index, task, message);
+                Box::new(None::<Box<Any>>.into_iter())
+            }
+        }
+    }
+    fn supports_batch(&self) -> bool {
+        true
+    }
+    fn compute_batch(
+        &self,
+        iter: BatchIter,
+        partition: &Partition,
+        task: &Arc<TaskContext>,
+    ) -> BatchIter {
+        let columns = {
+            let task = task.clone();
+            iter.take_while(move |_| !task.is_interrupted())
+                .filter_map(|batch: Batch| batch.columns.into_iter().next())
+        };
+        match self.reduce(columns) {
+            Ok(Some(column)) => Box::new(Some(Batch::new(vec![column])).into_iter()),
+            Ok(None) => Box::new(None::<Batch>.into_iter()),
+            Err(message) => {
+                self.fail(partition.index, task, message);
+                Box::new(None::<Batch>.into_iter())
+            }
+        }
+    }
+    fn get_dependencies(&self) -> &Vec<&Box<RDD>> {
+        unimplemented!()
+    }
+    fn get_partitioner(&self) -> &Box<Partitioner> {
+        unimplemented!()
+    }
+    fn id(&self) -> RDDID {
+        self.id
+    }
+}
+
+mod test {
+    use super::*;
+    use rdd::UNIT_RDDID;
+    use rdd::batch::{Bitmap, Records, compute_records};
+    use rdd::failure::FailurePolicy;
+    use rdd::funcs::to_any;
+
+    fn aggregate(op: AggregateOp, column_type: ColumnType) -> Box<RDD> {
+        Aggregate::new(UNIT_RDDID, box (op, column_type)).unwrap()
+    }
+
+    fn batches() -> Records {
+        let mut validity = Bitmap::new(3, true);
+        validity.set(1, false);
+        Records::Batches(Box::new(vec![
+            Batch::new(vec![Column::new(vec![5u64, 100, 2], Some(validity))]),
+            Batch::new(vec![Column::new(vec![7u64], None)]),
+        ].into_iter()))
+    }
+
+    #[test]
+    fn aggregate_batches() {
+        let task = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        let partition = Partition { index: 0, server: 0 };
+        let expected = vec![
+            (AggregateOp::Count, Column::new(vec![3u64], None)),
+            (AggregateOp::Sum, Column::new(vec![14u64], None)),
+            (AggregateOp::Min, Column::new(vec![2u64], None)),
+            (AggregateOp::Max, Column::new(vec![7u64], None)),
+        ];
+        for (op, column) in expected {
+            let rdd = aggregate(op, ColumnType::U64);
+            match compute_records(&*rdd, batches(), &partition, &task) {
+                Records::Batches(iter) => {
+                    let batches: Vec<Batch> = iter.collect();
+                    assert_eq!(batches, vec![Batch::new(vec![column])]);
+                },
+                Records::Rows(_) => panic!("should stay in batch mode")
+            }
+        }
+        assert!(task.result().is_ok());
+    }
+
+    #[test]
+    fn aggregate_rows() {
+        let task = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        let partition = Partition { index: 0, server: 0 };
+        let rows: AnyIter = Box::new(vec![3i64, -5, 4].into_iter().map(|x| to_any((x, ))));
+        let sum: Vec<i64> = aggregate(AggregateOp::Sum, ColumnType::I64)
+            .compute(rows, &partition, &task)
+            .map(|r| unwrap_record::<i64>(r).unwrap())
+            .collect();
+        assert_eq!(sum, vec![2]);
+        let rows: AnyIter = Box::new(Vec::<u32>::new().into_iter().map(|x| to_any((x, ))));
+        assert_eq!(aggregate(AggregateOp::Max, ColumnType::U32).compute(rows, &partition, &task).count(), 0);
+        assert!(task.result().is_ok());
+    }
+
+    #[test]
+    fn sum_overflow_fails_task() {
+        let task = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        let partition = Partition { index: 0, server: 0 };
+        let batches = Records::Batches(Box::new(vec![
+            Batch::new(vec![Column::new(vec![u64::max_value()], None)]),
+            Batch::new(vec![Column::new(vec![1u64], None)]),
+        ].into_iter()));
+        let rdd = aggregate(AggregateOp::Sum, ColumnType::U64);
+        match compute_records(&*rdd, batches, &partition, &task) {
+            Records::Batches(iter) => assert_eq!(iter.count(), 0),
+            Records::Rows(_) => panic!("should stay in batch mode")
+        }
+        assert_eq!(task.result().err().unwrap().kind, RDDFuncErrorKind::Failed);
+    }
+}
@@ -1,22 +1,33 @@
 use rdd::{RDD, RDDTracker, funcs, RDDID, Partition, AnyIter};
 use rdd::funcs::{RDDFunc, RDDFuncResult, REGISTRY as FuncREG};
 use rdd::transformers::{Registry, REGISTRY, RegedTrans};
+use rdd::failure::{FuncGuard, Guarded, Emit};
+use rdd::batch::{self, Batch, BatchFunc, BatchGuard, BatchIter};
+use contexts::task::TaskContext;
 use scheduler::dag::partitioner::Partitioner;
 use std::any::Any;
+use std::sync::Arc;
 
 pub struct Map {
+    id: RDDID,
+    func_id: u64,
     closure: Box<Any>,
     func: fn(&Box<Any>, &Box<Any>) -> RDDFuncResult,
     clone: fn(&Box<Any>) -> Box<Any>,
+    debug: fn(&Box<Any>) -> String,
+    fallible: bool,
+    batch: Option<BatchFunc>,
 }
 
 impl_rdd_trans_tracker!{
-    Map (func_id: u64, closure_data: Vec<u8>) {
+    Map [id] (func_id: u64, closure_data: Vec<u8>, fallible: bool) {
         let reg_func = FuncREG.get(*func_id).ok_or("cannot find rdd function")?;
         let closure = (reg_func.decode)(closure_data);
         let func = reg_func.func;
         let clone = reg_func.clone;
-        Ok(Map{  closure, func, clone })
+        let debug = reg_func.debug;
+        let batch = reg_func.batch;
+        Ok(Map{ id, func_id: *func_id, closure, func, clone, debug, fallible: *fallible, batch })
     }
 }
 
@@ -24,13 +35,55 @@ impl RDD for Map {
     fn compute (
         &self,
         iter: AnyIter,
-        partition: &Partition
+        partition: &Partition,
+        task: &Arc<TaskContext>,
     ) -> AnyIter {
-        let func = (self.func);
         let clone_closure = (self.clone);
-        let closure = clone_closure(&self.closure);
-        let iter = iter.map(move |d: Box<Any>|
-            func(&closure, &d).unwrap_to_any());
+        let guard = FuncGuard {
+            rdd_id: self.id,
+            func_id: self.func_id,
+            partition: partition.index,
+            func: self.func,
+            debug: self.debug,
+            closure: clone_closure(&self.closure),
+            task: task.clone(),
+            fallible: self.fallible,
+        };
+        let iter = Guarded::new(iter, guard, |res: Box<Any>| Ok(Emit::Result(res)));
+        Box::new(iter)
+    }
+    fn supports_batch(&self) -> bool {
+        self.batch.is_some()
+    }
+    fn compute_batch(
+        &self,
+        iter: BatchIter,
+        partition: &Partition,
+        task: &Arc<TaskContext>,
+    ) -> BatchIter {
+        let func = match self.batch {
+            Some(func) => func,
+            None => return batch::unsupported(task, self.id, self.func_id, partition.index)
+        };
+        let clone_closure = (self.clone);
+        let guard = BatchGuard {
+            rdd_id: self.id,
+            func_id: self.func_id,
+            partition: partition.index,
+            func,
+            closure: clone_closure(&self.closure),
+            task: task.clone(),
+            fallible: self.fallible,
+        };
+        let task = task.clone();
+        let iter = iter
+            .take_while(move |_| !task.is_interrupted())
+            .filter_map(move |batch: Batch| {
+                match batch.columns.first() {
+                    Some(input) => guard.call(input).map(|output| Batch::new(vec![output])),
+                    None => None
+                }
+            });
         Box::new(iter)
     }
     fn get_dependencies(&self) -> &Vec<&Box<RDD>> {
@@ -40,6 +93,6 @@ impl RDD for Map {
         unimplemented!()
     }
     fn id(&self) -> RDDID {
-        unimplemented!()
+        self.id
     }
-}
\ No newline at end of file
+}
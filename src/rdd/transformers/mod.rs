@@ -1,15 +1,16 @@
 use std::collections::BTreeMap;
 use std::cell::RefCell;
 use std::any::Any;
-use rdd::RDD;
+use rdd::{RDD, RDDID};
 
 pub mod map;
 pub mod filter;
 pub mod map_partitions;
+pub mod aggregate;
 
 #[derive(Clone)]
 pub struct RegedTrans {
-    pub construct: fn (Box<Any>) -> Result<Box<RDD>, String>,
+    pub construct: fn (RDDID, Box<Any>) -> Result<Box<RDD>, String>,
     pub construct_args: fn (&Vec<u8>) -> Box<Any>
 }
 
@@ -27,7 +28,7 @@ impl Registry {
     pub fn register(
         &self,
         id: u64,
-        construct: fn (Box<Any>) -> Result<Box<RDD>, String>,
+        construct: fn (RDDID, Box<Any>) -> Result<Box<RDD>, String>,
         construct_args: fn (&Vec<u8>) -> Box<Any>
     ) {
         let mut reg = self.map.borrow_mut();
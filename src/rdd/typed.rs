@@ -0,0 +1,371 @@
+// Typed execution path for narrow stages.
+// In the dynamic path every record is a `Box<Any>`, and every function in a chain of maps and
+//  filters downcasts its arguments and boxes its result. A pipeline here is a chain of RDD
+//  functions composed at compile time, so the whole chain is monomorphic and records are passed
+//  by value between functions.
+// The dynamic typing is kept only at the stage boundary. `FusedStage` downcasts each input record
+//  once, runs it through the pipeline, and boxes the output once. Fused stages have to be
+//  registered as transformers by `def_fused_stage!` because the executor can only find concrete
+//  types from the registry.
+
+use rdd::{RDDID, RDDTracker, AnyIter, Partition, unwrap_record};
+use rdd::funcs::RDDFunc;
+use rdd::failure::{RDDFuncError, RDDFuncErrorKind, panic_message};
+use contexts::task::TaskContext;
+use serde::Serialize;
+use std::any::Any;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+// Error in a pipeline, with the id of the function that produced it
+pub type PipelineError = (u64, String);
+
+// Panic payload of a function in a pipeline, so the stage can tell which function panicked
+pub struct FuncPanic {
+    pub func_id: u64,
+    pub message: String,
+}
+
+fn invoke<F: RDDFunc>(func: &F, args: &F::In) -> Result<F::Out, String> {
+    match panic::catch_unwind(AssertUnwindSafe(|| func.invoke(args))) {
+        Ok(res) => res,
+        Err(payload) => panic::resume_unwind(box FuncPanic {
+            func_id: F::id(),
+            message: panic_message(&payload)
+        })
+    }
+}
+
+pub trait Pipeline {
+    type In;
+    type Out;
+    // `Ok(None)` means the record was filtered out
+    fn process(&self, input: Self::In) -> Result<Option<Self::Out>, PipelineError>;
+    // Render an input record by `debug_args` of the first function, none for an empty pipeline
+    fn debug_input(record: &Box<Any>) -> Option<String>;
+
+    fn map<F>(self, func: F) -> MapPipe<Self, F>
+        where Self: Sized,
+              F: RDDFunc<In = (Self::Out, )>
+    {
+        MapPipe { prev: self, func }
+    }
+    fn filter<F>(self, func: F) -> FilterPipe<Self, F>
+        where Self: Sized,
+              F: RDDFunc<In = (Self::Out, ), Out = bool>
+    {
+        FilterPipe { prev: self, func }
+    }
+    fn run<I>(self, iter: I) -> TypedIter<Self, I>
+        where Self: Sized,
+              I: Iterator<Item = Self::In>
+    {
+        TypedIter { pipe: self, iter }
+    }
+}
+
+// Start a pipeline that takes records of type `T`
+pub fn pipe<T>() -> Source<T> {
+    Source { mark: PhantomData }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Source<T> {
+    mark: PhantomData<T>
+}
+
+impl <T> Pipeline for Source<T> {
+    type In = T;
+    type Out = T;
+    fn process(&self, input: T) -> Result<Option<T>, PipelineError> {
+        Ok(Some(input))
+    }
+    fn debug_input(_record: &Box<Any>) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MapPipe<P, F> {
+    prev: P,
+    func: F,
+}
+
+impl <P, F> Pipeline for MapPipe<P, F>
+    where P: Pipeline,
+          F: RDDFunc<In = (P::Out, )>
+{
+    type In = P::In;
+    type Out = F::Out;
+    fn process(&self, input: P::In) -> Result<Option<F::Out>, PipelineError> {
+        match self.prev.process(input)? {
+            Some(v) => invoke(&self.func, &(v, ))
+                .map(|out| Some(out))
+                .map_err(|e| (F::id(), e)),
+            None => Ok(None)
+        }
+    }
+    fn debug_input(record: &Box<Any>) -> Option<String> {
+        P::debug_input(record).or_else(|| Some(F::debug_args(record)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FilterPipe<P, F> {
+    prev: P,
+    func: F,
+}
+
+impl <P, F> Pipeline for FilterPipe<P, F>
+    where P: Pipeline,
+          F: RDDFunc<In = (P::Out, ), Out = bool>
+{
+    type In = P::In;
+    type Out = P::Out;
+    fn process(&self, input: P::In) -> Result<Option<P::Out>, PipelineError> {
+        match self.prev.process(input)? {
+            Some(v) => {
+                let args = (v, );
+                match invoke(&self.func, &args) {
+                    Ok(true) => Ok(Some(args.0)),
+                    Ok(false) => Ok(None),
+                    Err(e) => Err((F::id(), e))
+                }
+            },
+            None => Ok(None)
+        }
+    }
+    fn debug_input(record: &Box<Any>) -> Option<String> {
+        P::debug_input(record).or_else(|| Some(F::debug_args(record)))
+    }
+}
+
+// Runs a pipeline over a typed iterator, stops on the first error
+pub struct TypedIter<P, I> {
+    pipe: P,
+    iter: I,
+}
+
+impl <P, I> Iterator for TypedIter<P, I>
+    where P: Pipeline,
+          I: Iterator<Item = P::In>
+{
+    type Item = Result<P::Out, PipelineError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let input = match self.iter.next() {
+                Some(input) => input,
+                None => return None
+            };
+            match self.pipe.process(input) {
+                Ok(Some(out)) => return Some(Ok(out)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e))
+            }
+        }
+    }
+}
+
+// The stage boundary. Converts dynamic records to the pipeline input type and back.
+// Failures are reported to the task context in the same way as the dynamic transformers.
+pub struct FusedStage<P> {
+    pub id: RDDID,
+    pub pipe: P,
+}
+
+impl <P> FusedStage<P>
+    where P: Pipeline + Clone + 'static,
+          P::In: 'static,
+          P::Out: 'static
+{
+    pub fn compute(&self, iter: AnyIter, partition: &Partition, task: &Arc<TaskContext>) -> AnyIter {
+        let pipe = self.pipe.clone();
+        let task = task.clone();
+        let rdd_id = self.id;
+        let partition = partition.index;
+        let iter = iter
+            .take_while({
+                let task = task.clone();
+                move |_| !task.is_interrupted()
+            })
+            .filter_map(move |record: Box<Any>| {
+                // the record is moved into the pipeline, so it is rendered up front for errors
+                let rendered = panic::catch_unwind(AssertUnwindSafe(|| P::debug_input(&record)))
+                    .ok()
+                    .and_then(|rendered| rendered)
+                    .unwrap_or_else(|| format!("<cannot render record>"));
+                let error = match unwrap_record::<P::In>(record) {
+                    Ok(input) => {
+                        match panic::catch_unwind(AssertUnwindSafe(|| pipe.process(input))) {
+                            Ok(Ok(Some(out))) => return Some(box out as Box<Any>),
+                            Ok(Ok(None)) => return None,
+                            Ok(Err((func_id, msg))) => (func_id, RDDFuncErrorKind::Returned, msg),
+                            Err(payload) => match payload.downcast::<FuncPanic>() {
+                                Ok(panic) => (panic.func_id, RDDFuncErrorKind::Panic, panic.message),
+                                Err(payload) => (0, RDDFuncErrorKind::Panic, panic_message(&payload))
+                            }
+                        }
+                    },
+                    Err(record) => {
+                        (0, RDDFuncErrorKind::Failed,
+                         format!("Cannot cast type for fused stage: {:?}", record))
+                    }
+                };
+                let error = RDDFuncError {
+                    rdd_id, partition,
+                    func_id: error.0,
+                    record: rendered,
+                    kind: error.1,
+                    message: error.2
+                };
+                // as in `Guarded`, only errors returned by functions follow `FuncErrorPolicy`
+                if error.kind == RDDFuncErrorKind::Returned {
+                    task.skip_func_error(error);
+                } else {
+                    task.tolerate(error);
+                }
+                None
+            });
+        Box::new(iter)
+    }
+}
+
+// Implemented by `def_fused_stage!` to link the transformer to its pipeline type
+pub trait StageDef: RDDTracker {
+    type Pipe: Pipeline + Serialize + Clone;
+}
+
+mod test {
+    use super::*;
+    use INIT_LOCK;
+    use rdd::RDD;
+    use rdd::funcs::{RDDFunc, RDDFuncResult, to_any};
+    use rdd::transformers::REGISTRY;
+    use rdd::failure::{FailurePolicy, FuncErrorPolicy, DeadLetterSink};
+    use parking_lot::Mutex;
+    use contexts::JobContext;
+    use contexts::script::{RDDComposer, ScriptContext, parallelize};
+
+    def_rdd_func!(
+        APlusB (a: u64)[b: u64] -> u64 {
+            a + b
+        }
+        AGreaterThanN (x: u64)[n: u64] -> bool {
+            x > n
+        }
+        ANotN (x: u64)[n: u64] ?-> u64 {
+            if x == n { Err("equals n") } else { Ok(*x) }
+        }
+        NDivA (a: u64)[n: u64] -> u64 {
+            n / a
+        }
+    );
+
+    def_fused_stage!(
+        DivFilterAdd: MapPipe<FilterPipe<MapPipe<Source<u64>, NDivA>, AGreaterThanN>, APlusB>
+    );
+    def_fused_stage!(CheckNotN: MapPipe<Source<u64>, ANotN>);
+
+    struct Collector {
+        errors: Mutex<Vec<RDDFuncError>>
+    }
+
+    impl DeadLetterSink for Collector {
+        fn put(&self, error: &RDDFuncError) {
+            self.errors.lock().push(error.clone());
+        }
+    }
+
+    #[test]
+    fn typed_pipeline() {
+        let res: Vec<u64> = pipe::<u64>()
+            .map(APlusB { b: 10 })
+            .filter(AGreaterThanN { n: 12 })
+            .map(APlusB { b: 1 })
+            .run(vec![1, 2, 3, 4].into_iter())
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(res, vec![14, 15]);
+    }
+
+    #[test]
+    fn typed_pipeline_error() {
+        let res: Vec<Result<u64, PipelineError>> = pipe::<u64>()
+            .map(ANotN { n: 2 })
+            .run(vec![1, 2].into_iter())
+            .collect();
+        assert_eq!(res[0], Ok(1));
+        assert_eq!(res[1], Err((ANotN::id(), "equals n".to_string())));
+    }
+
+    fn run(job: &JobContext, task: &Arc<TaskContext>, input: Vec<u64>) -> Vec<u64> {
+        let partition = Partition { index: 0, server: 0 };
+        let mut iter: AnyIter = Box::new(input.into_iter().map(|x| to_any((x, ))));
+        for rdd in job.pipeline() {
+            iter = rdd.compute(iter, &partition, task);
+        }
+        iter.map(|x| *x.downcast_ref::<u64>().unwrap()).collect()
+    }
+
+    #[test]
+    fn fused_stage() {
+        {
+            let lock = INIT_LOCK.lock();
+            DivFilterAdd::register();
+        }
+        let stage = pipe::<u64>()
+            .map(NDivA { n: 12 })
+            .filter(AGreaterThanN { n: 3 })
+            .map(APlusB { b: 1 });
+        let mut script = ScriptContext::new();
+        parallelize(Vec::<u64>::new(), 1)
+            .fused::<DivFilterAdd>(stage)
+            .compile(&mut script);
+        let job = script.compile().unwrap();
+        assert_eq!(job.pipeline().len(), 1);
+
+        let task = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        assert_eq!(run(&job, &task, vec![1, 2, 3, 4]), vec![13, 7, 5]);
+        assert!(task.result().is_ok());
+
+        // division by zero panics in the first function of the stage
+        let task = Arc::new(TaskContext::new(2, FailurePolicy::fail_fast(), None));
+        assert_eq!(run(&job, &task, vec![1, 0]), vec![13]);
+        let error = task.result().err().unwrap();
+        assert_eq!(error.kind, RDDFuncErrorKind::Panic);
+        assert_eq!(error.func_id, NDivA::id());
+        assert_eq!(error.record, "(0,)");
+    }
+
+    #[test]
+    fn fused_stage_func_errors() {
+        {
+            let lock = INIT_LOCK.lock();
+            CheckNotN::register();
+        }
+        let mut script = ScriptContext::new();
+        parallelize(Vec::<u64>::new(), 1)
+            .fused::<CheckNotN>(pipe::<u64>().map(ANotN { n: 2 }))
+            .compile(&mut script);
+        let job = script.compile().unwrap();
+
+        // returned errors are not bad records, `max_skipped` does not cover them
+        let policy = FailurePolicy { max_skipped: 5, on_func_error: FuncErrorPolicy::Propagate };
+        let task = Arc::new(TaskContext::new(1, policy, None));
+        assert_eq!(run(&job, &task, vec![1, 2, 3]), vec![1]);
+        let error = task.result().err().unwrap();
+        assert_eq!(error.kind, RDDFuncErrorKind::Returned);
+        assert_eq!(error.func_id, ANotN::id());
+        assert_eq!(error.record, "(2,)");
+
+        let sink = Arc::new(Collector { errors: Mutex::new(Vec::new()) });
+        let policy = FailurePolicy { max_skipped: 0, on_func_error: FuncErrorPolicy::Skip };
+        let task = Arc::new(TaskContext::new(2, policy, Some(sink.clone())));
+        assert_eq!(run(&job, &task, vec![1, 2, 3]), vec![1, 3]);
+        assert!(task.result().is_ok());
+        let errors = sink.errors.lock();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].record, "(2,)");
+    }
+}
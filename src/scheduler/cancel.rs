@@ -0,0 +1,215 @@
+// Tasks executing on this server, so they can be interrupted when canceled, or when another copy
+//  of a speculated partition is committed.
+// A canceled task is marked on its `TaskContext`, RDDs stop between records and the executor
+//  returns `JobError::Canceled`. Registered `TaskCleanup`s then remove what the task left on
+//  this server, like shuffle files and cached blocks.
+// The leases of partitions computing here are renewed by `heartbeat`.
+
+use contexts::task::TaskContext;
+use server::resources::client::ResourceManagerClient;
+use server::resources::manager::{TaskStatus, OccupationStatus};
+use futures::Future;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub trait TaskCleanup: Send + Sync {
+    fn name(&self) -> &str;
+    fn cleanup(&self, task_id: u64) -> Result<(), String>;
+}
+
+pub struct RunningTasks {
+    // stage id and context of the partition computing now, none between partitions
+    tasks: Mutex<BTreeMap<u64, Option<(u64, Arc<TaskContext>)>>>,
+    canceled: Mutex<BTreeSet<u64>>,
+    cleanups: RwLock<Vec<Arc<TaskCleanup>>>,
+}
+
+impl RunningTasks {
+    pub fn new() -> Arc<RunningTasks> {
+        Arc::new(RunningTasks {
+            tasks: Mutex::new(BTreeMap::new()),
+            canceled: Mutex::new(BTreeSet::new()),
+            cleanups: RwLock::new(Vec::new()),
+        })
+    }
+
+    // Interrupt tasks on this server when the resource manager reports them canceled, and
+    //  partitions superseded by a copy on another node
+    pub fn watch(this: &Arc<RunningTasks>, rm: &Arc<ResourceManagerClient>, node_id: u64) {
+        let tasks = this.clone();
+        let res = rm.on_task_ended(move |task| {
+            if let Ok(task) = task {
+                if *task.status() == TaskStatus::Canceled {
+                    tasks.cancel(task.id());
+                }
+            }
+        });
+        if let Err(e) = res {
+            error!("Cannot watch for canceled tasks: {:?}", e);
+        }
+        let tasks = this.clone();
+        let res = rm.on_occupation_changed(move |occ| {
+            if let Ok(occ) = occ {
+                if occ.node_id == node_id && occ.status == OccupationStatus::Superseded {
+                    tasks.kill(occ.task_id, occ.stage_id);
+                }
+            }
+        });
+        if let Err(e) = res {
+            error!("Cannot watch for superseded partitions: {:?}", e);
+        }
+    }
+
+    // Renew the leases of partitions computing here every `interval`. A partition whose occupation
+    //  is not running anymore, like an expired one, is stopped. Ends when the tasks are dropped.
+    pub fn heartbeat(
+        this: &Arc<RunningTasks>,
+        rm: &Arc<ResourceManagerClient>,
+        node_id: u64,
+        interval: Duration
+    ) {
+        let weak = Arc::downgrade(this);
+        let rm = rm.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let tasks = match weak.upgrade() {
+                Some(tasks) => tasks,
+                None => return
+            };
+            for (task_id, stage_id) in tasks.running() {
+                match rm.heartbeat_occupation(task_id, stage_id, node_id).wait() {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        warn!("Occupation of stage {} of task {} is not running, stopping it", stage_id, task_id);
+                        tasks.kill(task_id, stage_id);
+                    },
+                    Err(e) => warn!("Cannot renew lease of stage {} of task {}: {:?}", stage_id, task_id, e)
+                }
+            }
+        });
+    }
+
+    pub fn add_cleanup(&self, cleanup: Arc<TaskCleanup>) {
+        self.cleanups.write().push(cleanup);
+    }
+
+    // The executor calls `begin` before the first partition of a task, `start` and `finish`
+    //  around each partition and `end` when the task is done, whatever the outcome.
+    pub fn begin(&self, task_id: u64) {
+        self.tasks.lock().entry(task_id).or_insert(None);
+    }
+    pub fn start(&self, stage_id: u64, context: &Arc<TaskContext>) {
+        if self.is_canceled(context.task_id) {
+            context.cancel();
+        }
+        self.tasks.lock().insert(context.task_id, Some((stage_id, context.clone())));
+    }
+    pub fn finish(&self, task_id: u64) {
+        if let Some(context) = self.tasks.lock().get_mut(&task_id) {
+            *context = None;
+        }
+    }
+    // Cleanups of a task canceled while executing here are deferred to this point, so nothing
+    //  is written after them.
+    pub fn end(&self, task_id: u64) {
+        self.tasks.lock().remove(&task_id);
+        if self.canceled.lock().remove(&task_id) {
+            self.cleanup(task_id);
+        }
+    }
+
+    // Stop one partition, its output is dropped by the output commit
+    pub fn kill(&self, task_id: u64, stage_id: u64) {
+        if let Some(&Some((running, ref context))) = self.tasks.lock().get(&task_id) {
+            if running == stage_id {
+                context.cancel();
+            }
+        }
+    }
+
+    // task and stage ids of partitions computing now
+    pub fn running(&self) -> Vec<(u64, u64)> {
+        self.tasks.lock().iter()
+            .filter_map(|(task_id, running)| running.as_ref().map(|&(stage_id, _)| (*task_id, stage_id)))
+            .collect()
+    }
+
+    pub fn is_canceled(&self, task_id: u64) -> bool {
+        self.canceled.lock().contains(&task_id)
+    }
+
+    pub fn cancel(&self, task_id: u64) {
+        let executing = {
+            let tasks = self.tasks.lock();
+            match tasks.get(&task_id) {
+                Some(context) => {
+                    self.canceled.lock().insert(task_id);
+                    if let &Some((_, ref context)) = context {
+                        context.cancel();
+                    }
+                    true
+                },
+                None => false
+            }
+        };
+        if !executing {
+            self.cleanup(task_id);
+        }
+    }
+
+    fn cleanup(&self, task_id: u64) {
+        for cleanup in self.cleanups.read().iter() {
+            if let Err(e) = cleanup.cleanup(task_id) {
+                warn!("Cannot clean up {} of canceled task {}: {}", cleanup.name(), task_id, e);
+            }
+        }
+    }
+}
+
+mod test {
+    use super::*;
+    use rdd::failure::FailurePolicy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter {
+        cleaned: AtomicUsize,
+    }
+
+    impl TaskCleanup for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+        fn cleanup(&self, _task_id: u64) -> Result<(), String> {
+            self.cleaned.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cancel_interrupts_and_defers_cleanup() {
+        let tasks = RunningTasks::new();
+        let counter = Arc::new(Counter { cleaned: AtomicUsize::new(0) });
+        tasks.add_cleanup(counter.clone());
+        let context = Arc::new(TaskContext::new(1, FailurePolicy::fail_fast(), None));
+        tasks.begin(1);
+        tasks.begin(3);
+        tasks.start(0, &context);
+        assert_eq!(tasks.running(), vec![(1, 0)]);
+        tasks.end(3);
+        tasks.cancel(1);
+        assert!(context.is_interrupted());
+        assert!(tasks.is_canceled(1));
+        assert_eq!(counter.cleaned.load(Ordering::SeqCst), 0);
+        tasks.finish(1);
+        assert!(tasks.running().is_empty());
+        tasks.end(1);
+        assert_eq!(counter.cleaned.load(Ordering::SeqCst), 1);
+        assert!(!tasks.is_canceled(1));
+        // not executing here, cleaned up right away
+        tasks.cancel(2);
+        assert_eq!(counter.cleaned.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,215 @@
+// Scheduler for standalone mode. Each partition of a job is an occupation on the local node,
+//  computed one after another in the calling thread after the resource manager admits it.
+// A canceled job stops between records, see `scheduler::cancel`.
+// There is only one node. Placement still goes through delay scheduling with it as the only free
+//  server, so a partition preferring other servers runs here once the delay of the job allows any
+//  node. Locality is recorded in the metrics.
+
+use contexts::JobContext;
+use contexts::task::TaskContext;
+use rdd::{AnyIter, Partition};
+use scheduler::JobError;
+use scheduler::cancel::RunningTasks;
+use scheduler::locality::{Locality, LocalityLevel};
+use server::resources::client::ResourceManagerClient;
+use server::resources::manager::{Task, TaskStatus, Occupation, OccupationStatus, RetryPolicy, NodeFilter};
+use server::resources::pools::DEFAULT_POOL;
+use bifrost_hasher::hash_str;
+use futures::Future;
+use neb::dovahkiin::types::Map;
+use uuid::Uuid;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct LocalScheduler {
+    rm: Arc<ResourceManagerClient>,
+    node_id: u64,
+    tasks: Arc<RunningTasks>,
+    locality: Arc<Locality>,
+    pub memory_per_partition: u64,
+    pub admission_timeout: Duration,
+}
+
+impl LocalScheduler {
+    pub fn new(
+        rm: &Arc<ResourceManagerClient>,
+        node_id: u64,
+        tasks: &Arc<RunningTasks>,
+        locality: &Arc<Locality>
+    ) -> LocalScheduler {
+        LocalScheduler {
+            rm: rm.clone(),
+            node_id,
+            tasks: tasks.clone(),
+            locality: locality.clone(),
+            memory_per_partition: 64 * 1024 * 1024,
+            admission_timeout: Duration::from_secs(60),
+        }
+    }
+
+    pub fn run(&self, job: &JobContext, input: Vec<Vec<Box<Any>>>) -> Result<Vec<Box<Any>>, JobError> {
+        let task_id = hash_str(&Uuid::new_v4().to_string());
+        // input records are consumed by the first attempt, failed partitions cannot run again
+        let task = Task::new(task_id, "local", DEFAULT_POOL, 0, 0, Map::new())
+            .with_retry(RetryPolicy::no_retry());
+        let occupations = (0..input.len())
+            .map(|index| {
+                let stage_id = LocalScheduler::stage_id(task_id, index);
+                Occupation::new(task_id, stage_id, self.node_id, 1, self.memory_per_partition)
+            })
+            .collect();
+        let running: Vec<u64> = self.rm.register_task(task, occupations).wait()
+            .map_err(|e| JobError::Failed(format!("Cannot register task: {:?}", e)))?
+            .into_iter()
+            .map(|occ| occ.stage_id)
+            .collect();
+        let addresses: BTreeMap<u64, String> = self.rm.nodes(NodeFilter::default()).wait()
+            .map(|nodes| {
+                nodes.iter().map(|node| (node.node_id(), node.address().to_string())).collect()
+            })
+            .unwrap_or(BTreeMap::new());
+        self.tasks.begin(task_id);
+        let started = Instant::now();
+        let mut results = Vec::new();
+        let mut outcome = Ok(());
+        for (index, records) in input.into_iter().enumerate() {
+            let stage_id = LocalScheduler::stage_id(task_id, index);
+            if self.tasks.is_canceled(task_id) {
+                outcome = Err(JobError::Canceled(task_id));
+                break;
+            }
+            if !running.contains(&stage_id) {
+                if let Err(e) = self.wait_admitted(stage_id) {
+                    outcome = Err(JobError::Failed(e));
+                    break;
+                }
+            }
+            match self.place(job, task_id, index, &addresses, started) {
+                Ok(level) => self.locality.metrics.record(level),
+                Err(e) => {
+                    outcome = Err(e);
+                    break;
+                }
+            }
+            match self.compute(job, task_id, index, records) {
+                Ok(mut records) => {
+                    // results are kept in memory, committing only guards against other copies
+                    let committed = self.rm.commit_partition(task_id, stage_id, self.node_id).wait();
+                    let _ = self.rm.release_occupation(task_id, stage_id, self.node_id).wait();
+                    match committed {
+                        Ok(true) => results.append(&mut records),
+                        Ok(false) if self.tasks.is_canceled(task_id) => {
+                            outcome = Err(JobError::Canceled(task_id));
+                            break;
+                        },
+                        other => {
+                            outcome = Err(JobError::Failed(
+                                format!("Cannot commit partition {}: {:?}", stage_id, other)
+                            ));
+                            break;
+                        }
+                    }
+                },
+                Err(e) => {
+                    // keeps the reason on the task, and fails it as there are no retries
+                    if let JobError::Failed(ref reason) = e {
+                        let _ = self.rm.stage_failed(task_id, stage_id, self.node_id, reason.clone()).wait();
+                    } else {
+                        let _ = self.rm.release_occupation(task_id, stage_id, self.node_id).wait();
+                    }
+                    outcome = Err(e);
+                    break;
+                }
+            }
+        }
+        // the occupations of a canceled task are released by the resource manager already
+        if self.tasks.is_canceled(task_id) {
+            outcome = Err(JobError::Canceled(task_id));
+        } else {
+            let status = if outcome.is_ok() { TaskStatus::Succeed } else { TaskStatus::Failed };
+            let _ = self.rm.task_ended(task_id, status).wait();
+        }
+        self.tasks.end(task_id);
+        outcome.map(|_| results)
+    }
+
+    fn compute(
+        &self, job: &JobContext, task_id: u64, index: usize, records: Vec<Box<Any>>
+    ) -> Result<Vec<Box<Any>>, JobError> {
+        let task = Arc::new(TaskContext::new(task_id, job.failure_policy.clone(), None));
+        let partition = LocalScheduler::partition(index);
+        self.tasks.start(LocalScheduler::stage_id(task_id, index), &task);
+        let mut iter: AnyIter = Box::new(records.into_iter());
+        for rdd in job.pipeline() {
+            iter = rdd.compute(iter, &partition, &task);
+        }
+        let records: Vec<Box<Any>> = iter.collect();
+        self.tasks.finish(task_id);
+        if task.is_canceled() {
+            if self.tasks.is_canceled(task_id) {
+                return Err(JobError::Canceled(task_id));
+            }
+            return Err(JobError::Failed(format!("Partition {} is superseded or its lease expired", index)));
+        }
+        task.result().map_err(|e| JobError::Failed(format!("{:?}", e)))?;
+        Ok(records)
+    }
+
+    // Occupations are kept by stage id on the node, so jobs running at the same time need
+    //  their own. Task ids are random, stage ids follow them.
+    fn stage_id(task_id: u64, index: usize) -> u64 {
+        task_id.wrapping_add(index as u64)
+    }
+
+    // input is handed over in memory, no server owns its data
+    fn partition(index: usize) -> Partition {
+        Partition { index, server: 0 }
+    }
+
+    // Wait until delay scheduling lets the partition run on this node. The delay counts from the
+    //  start of the job, so it is paid once for all partitions. `None` for no preference.
+    fn place(
+        &self,
+        job: &JobContext,
+        task_id: u64,
+        index: usize,
+        addresses: &BTreeMap<u64, String>,
+        started: Instant
+    ) -> Result<Option<LocalityLevel>, JobError> {
+        let preferred = self.locality.preferred(job, &LocalScheduler::partition(index));
+        loop {
+            let elapsed = started.elapsed();
+            let waited = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
+            let chosen = self.locality.delay.choose(&preferred, &[self.node_id], addresses, waited);
+            if let Some((_, level)) = chosen {
+                return Ok(if preferred.is_empty() { None } else { Some(level) })
+            }
+            if self.tasks.is_canceled(task_id) {
+                return Err(JobError::Canceled(task_id))
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // queued occupations are admitted by the resource manager when earlier ones are released
+    fn wait_admitted(&self, stage_id: u64) -> Result<(), String> {
+        let started = Instant::now();
+        loop {
+            let status = self.rm.node(self.node_id).wait()
+                .map_err(|e| format!("Cannot query node: {:?}", e))?
+                .and_then(|node| node.occupations().get(&stage_id).map(|occ| occ.status));
+            match status {
+                Some(OccupationStatus::Running) => return Ok(()),
+                Some(OccupationStatus::Scheduled) => {},
+                other => return Err(format!("Occupation {} cannot run: {:?}", stage_id, other))
+            }
+            if started.elapsed() > self.admission_timeout {
+                return Err(format!("Occupation {} is not admitted in time", stage_id));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,219 @@
+// Data locality. A partition prefers the servers that own its neb data, hold its cached blocks
+//  or host the shuffle output it reads. RDDs report them with `RDD::preferred_locations`, and
+//  registered `LocationProvider`s (block cache, shuffle) add what they hold.
+// Placement uses delay scheduling: a partition waits up to `process_wait` for a free slot on a
+//  preferred server, then up to `node_wait` more for one on the same host, then runs anywhere.
+
+use contexts::JobContext;
+use rdd::{RDDID, Partition};
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LocalityLevel {
+    ProcessLocal, // on a preferred server
+    NodeLocal, // another server on the host of a preferred server
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LocationKind {
+    DataOwner,
+    CachedBlock,
+    ShuffleOutput,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreferredLocation {
+    pub server: u64,
+    pub kind: LocationKind,
+}
+
+pub trait LocationProvider: Send + Sync {
+    fn name(&self) -> &str;
+    fn locations(&self, rdd: RDDID, partition: &Partition) -> Vec<PreferredLocation>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DelayScheduling {
+    pub process_wait: u64, // ms
+    pub node_wait: u64, // ms, after `process_wait`
+}
+
+impl Default for DelayScheduling {
+    fn default() -> DelayScheduling {
+        DelayScheduling {
+            process_wait: 3000,
+            node_wait: 3000,
+        }
+    }
+}
+
+fn host(address: &str) -> &str {
+    address.rsplitn(2, ':').last().unwrap_or(address)
+}
+
+impl DelayScheduling {
+    // worst level a partition may take after waiting `waited` ms
+    pub fn allowed_level(&self, waited: u64) -> LocalityLevel {
+        if waited < self.process_wait {
+            LocalityLevel::ProcessLocal
+        } else if waited < self.process_wait.saturating_add(self.node_wait) {
+            LocalityLevel::NodeLocal
+        } else {
+            LocalityLevel::Any
+        }
+    }
+    // `addresses` maps server ids to their addresses, to find servers on the same host
+    pub fn level_of(
+        server: u64, preferred: &[PreferredLocation], addresses: &BTreeMap<u64, String>
+    ) -> LocalityLevel {
+        if preferred.iter().any(|loc| loc.server == server) {
+            return LocalityLevel::ProcessLocal;
+        }
+        if let Some(address) = addresses.get(&server) {
+            let same_host = preferred.iter()
+                .filter_map(|loc| addresses.get(&loc.server))
+                .any(|other| host(other) == host(address));
+            if same_host {
+                return LocalityLevel::NodeLocal;
+            }
+        }
+        LocalityLevel::Any
+    }
+    // Pick a server with a free slot for the partition, or none to keep waiting for a better one.
+    //  Partitions without preference take any server.
+    pub fn choose(
+        &self,
+        preferred: &[PreferredLocation],
+        free: &[u64],
+        addresses: &BTreeMap<u64, String>,
+        waited: u64
+    ) -> Option<(u64, LocalityLevel)> {
+        let best = free.iter()
+            .map(|server| (*server, DelayScheduling::level_of(*server, preferred, addresses)))
+            .min_by_key(|&(_, level)| level);
+        match best {
+            Some((server, level)) => {
+                if preferred.is_empty() || level <= self.allowed_level(waited) {
+                    Some((server, level))
+                } else {
+                    None
+                }
+            },
+            None => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LocalityReport {
+    pub process_local: usize,
+    pub node_local: usize,
+    pub any: usize, // had a preference but ran elsewhere
+    pub no_preference: usize,
+}
+
+impl LocalityReport {
+    // part of partitions with a preference that ran on a preferred server
+    pub fn hit_ratio(&self) -> f64 {
+        let preferred = self.process_local + self.node_local + self.any;
+        if preferred == 0 {
+            return 1.0;
+        }
+        self.process_local as f64 / preferred as f64
+    }
+}
+
+#[derive(Default)]
+pub struct LocalityMetrics {
+    process_local: AtomicUsize,
+    node_local: AtomicUsize,
+    any: AtomicUsize,
+    no_preference: AtomicUsize,
+}
+
+impl LocalityMetrics {
+    // `None` for partitions without preference
+    pub fn record(&self, level: Option<LocalityLevel>) {
+        let counter = match level {
+            Some(LocalityLevel::ProcessLocal) => &self.process_local,
+            Some(LocalityLevel::NodeLocal) => &self.node_local,
+            Some(LocalityLevel::Any) => &self.any,
+            None => &self.no_preference
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn report(&self) -> LocalityReport {
+        LocalityReport {
+            process_local: self.process_local.load(Ordering::Relaxed),
+            node_local: self.node_local.load(Ordering::Relaxed),
+            any: self.any.load(Ordering::Relaxed),
+            no_preference: self.no_preference.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Location providers and metrics of this server
+pub struct Locality {
+    providers: RwLock<Vec<Arc<LocationProvider>>>,
+    pub delay: DelayScheduling,
+    pub metrics: LocalityMetrics,
+}
+
+impl Locality {
+    pub fn new() -> Arc<Locality> {
+        Arc::new(Locality {
+            providers: RwLock::new(Vec::new()),
+            delay: DelayScheduling::default(),
+            metrics: LocalityMetrics::default(),
+        })
+    }
+    pub fn add_provider(&self, provider: Arc<LocationProvider>) {
+        self.providers.write().push(provider);
+    }
+    // preferred servers of a partition for every RDD of the job, without duplicates
+    pub fn preferred(&self, job: &JobContext, partition: &Partition) -> Vec<PreferredLocation> {
+        let providers = self.providers.read();
+        let mut locations: Vec<PreferredLocation> = Vec::new();
+        if partition.server != 0 {
+            locations.push(PreferredLocation { server: partition.server, kind: LocationKind::DataOwner });
+        }
+        for rdd in job.pipeline() {
+            let mut found = rdd.preferred_locations(partition);
+            for provider in providers.iter() {
+                found.append(&mut provider.locations(rdd.id(), partition));
+            }
+            for loc in found {
+                if !locations.iter().any(|l| l.server == loc.server) {
+                    locations.push(loc);
+                }
+            }
+        }
+        locations
+    }
+}
+
+mod test {
+    use super::*;
+
+    #[test]
+    fn delay_scheduling() {
+        let delay = DelayScheduling { process_wait: 100, node_wait: 100 };
+        let mut addresses = BTreeMap::new();
+        addresses.insert(1, "10.0.0.1:5000".to_string());
+        addresses.insert(2, "10.0.0.1:5001".to_string());
+        addresses.insert(3, "10.0.0.2:5000".to_string());
+        let preferred = vec![PreferredLocation { server: 1, kind: LocationKind::DataOwner }];
+        assert_eq!(delay.choose(&preferred, &[1, 2, 3], &addresses, 0), Some((1, LocalityLevel::ProcessLocal)));
+        // preferred server is busy
+        assert_eq!(delay.choose(&preferred, &[2, 3], &addresses, 50), None);
+        assert_eq!(delay.choose(&preferred, &[2, 3], &addresses, 150), Some((2, LocalityLevel::NodeLocal)));
+        assert_eq!(delay.choose(&preferred, &[3], &addresses, 150), None);
+        assert_eq!(delay.choose(&preferred, &[3], &addresses, 250), Some((3, LocalityLevel::Any)));
+        assert_eq!(delay.choose(&[], &[3], &addresses, 0), Some((3, LocalityLevel::Any)));
+    }
+}
@@ -1,2 +1,12 @@
 pub mod stages;
-pub mod dag;
\ No newline at end of file
+pub mod dag;
+pub mod local;
+pub mod cancel;
+pub mod speculation;
+pub mod locality;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobError {
+    Canceled(u64), // task id
+    Failed(String),
+}
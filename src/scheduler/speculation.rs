@@ -0,0 +1,164 @@
+// Speculative execution of stragglers. Schedulers record when partitions of a stage start and
+//  finish. Once `quantile` of the partitions finished, a partition running longer than
+//  `multiplier` times their median runtime is a straggler, and a copy of it is placed on another
+//  node with `speculate_stage`.
+// Both copies write their output under their own attempt. The first to call `commit_partition`
+//  wins, the resource manager supersedes the other one and its executor stops it. Committers must
+//  make `commit` idempotent, an executor can crash after committing and run it again.
+
+use server::resources::client::ResourceManagerClient;
+use server::resources::manager::Occupation;
+use futures::Future;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpeculationConfig {
+    pub enabled: bool,
+    pub quantile: f64, // part of the partitions that must finish before speculating
+    pub multiplier: f64, // of the median runtime
+    pub min_runtime: u64, // ms, shorter partitions are never stragglers
+}
+
+impl Default for SpeculationConfig {
+    fn default() -> SpeculationConfig {
+        SpeculationConfig {
+            enabled: true,
+            quantile: 0.75,
+            multiplier: 1.5,
+            min_runtime: 1000,
+        }
+    }
+}
+
+// Runtimes of the partitions of one stage, times are in ms
+pub struct StageRuntimes {
+    partitions: usize,
+    running: BTreeMap<u64, u64>, // partition to start time
+    finished: Vec<u64>, // runtimes
+    speculated: BTreeSet<u64>,
+}
+
+impl StageRuntimes {
+    pub fn new(partitions: usize) -> StageRuntimes {
+        StageRuntimes {
+            partitions,
+            running: BTreeMap::new(),
+            finished: Vec::new(),
+            speculated: BTreeSet::new(),
+        }
+    }
+    pub fn started(&mut self, partition: u64, now: u64) {
+        self.running.entry(partition).or_insert(now);
+    }
+    // only the first copy of a partition to finish counts
+    pub fn finished(&mut self, partition: u64, now: u64) {
+        if let Some(started) = self.running.remove(&partition) {
+            self.finished.push(now.saturating_sub(started));
+        }
+    }
+    pub fn median(&self) -> Option<u64> {
+        if self.finished.is_empty() {
+            return None;
+        }
+        let mut runtimes = self.finished.clone();
+        runtimes.sort();
+        Some(runtimes[runtimes.len() / 2])
+    }
+    // Partitions to speculate now, each is returned once
+    pub fn stragglers(&mut self, now: u64, config: &SpeculationConfig) -> Vec<u64> {
+        if !config.enabled {
+            return Vec::new();
+        }
+        let required = cmp::max((self.partitions as f64 * config.quantile).ceil() as usize, 1);
+        if self.finished.len() < required {
+            return Vec::new();
+        }
+        let median = match self.median() {
+            Some(median) => median,
+            None => return Vec::new()
+        };
+        let threshold = cmp::max((median as f64 * config.multiplier) as u64, config.min_runtime);
+        let stragglers: Vec<u64> = self.running.iter()
+            .filter(|&(partition, started)| {
+                !self.speculated.contains(partition) && now.saturating_sub(*started) > threshold
+            })
+            .map(|(partition, _)| *partition)
+            .collect();
+        for partition in &stragglers {
+            self.speculated.insert(*partition);
+        }
+        stragglers
+    }
+}
+
+// Place copies of the stragglers. `place` picks the node for a copy, or none if there is no
+//  other node to run it. Returns partitions with a copy placed.
+pub fn speculate<F>(
+    rm: &ResourceManagerClient,
+    runtimes: &mut StageRuntimes,
+    now: u64,
+    config: &SpeculationConfig,
+    mut place: F
+) -> Vec<u64> where F: FnMut(u64) -> Option<Occupation> {
+    let mut placed = Vec::new();
+    for partition in runtimes.stragglers(now, config) {
+        let occ = match place(partition) {
+            Some(occ) => occ,
+            None => continue
+        };
+        match rm.speculate_stage(occ).wait() {
+            Ok(_) => placed.push(partition),
+            Err(e) => debug!("Cannot speculate partition {}: {:?}", partition, e)
+        }
+    }
+    placed
+}
+
+// Keeps output of partition attempts apart until one of them is committed
+pub trait OutputCommitter: Send + Sync {
+    // make the output of the attempt on `node_id` the output of the partition
+    fn commit(&self, task_id: u64, stage_id: u64, node_id: u64) -> Result<(), String>;
+    // drop the output of a superseded attempt
+    fn abort(&self, task_id: u64, stage_id: u64, node_id: u64) -> Result<(), String>;
+}
+
+// Returns whether the output of this attempt is kept
+pub fn commit_output(
+    rm: &ResourceManagerClient,
+    committer: &OutputCommitter,
+    task_id: u64, stage_id: u64, node_id: u64
+) -> Result<bool, String> {
+    let won = rm.commit_partition(task_id, stage_id, node_id).wait()
+        .map_err(|e| format!("Cannot commit partition {} of task {}: {:?}", stage_id, task_id, e))?;
+    if won {
+        committer.commit(task_id, stage_id, node_id)?;
+    } else {
+        committer.abort(task_id, stage_id, node_id)?;
+    }
+    Ok(won)
+}
+
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_stragglers() {
+        let config = SpeculationConfig { min_runtime: 0, ..SpeculationConfig::default() };
+        let mut runtimes = StageRuntimes::new(4);
+        for partition in 0..4 {
+            runtimes.started(partition, 0);
+        }
+        runtimes.finished(0, 100);
+        runtimes.finished(1, 120);
+        assert!(runtimes.stragglers(1000, &config).is_empty()); // 2 of 4 finished
+        runtimes.finished(2, 110);
+        assert_eq!(runtimes.median(), Some(110));
+        assert!(runtimes.stragglers(160, &config).is_empty());
+        assert_eq!(runtimes.stragglers(170, &config), vec![3]);
+        assert!(runtimes.stragglers(1000, &config).is_empty()); // speculated once
+        runtimes.finished(3, 1000);
+        runtimes.finished(3, 1200); // the slower copy
+        assert_eq!(runtimes.median(), Some(120));
+    }
+}
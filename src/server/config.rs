@@ -0,0 +1,483 @@
+// Server configuration loaded from a TOML or JSON file, with `HIVEMIND_*` environment variables
+//  overriding it. Missing settings take defaults, `validate` reports every problem found.
+// Environment overrides:
+//  HIVEMIND_ADDRESS, HIVEMIND_GROUP_NAME, HIVEMIND_META_MEMBERS (comma separated),
+//  HIVEMIND_IS_META, HIVEMIND_STANDALONE, HIVEMIND_PROCESSORS, HIVEMIND_MEMORY,
+//  HIVEMIND_STORAGE (`memory` or a directory), HIVEMIND_SPILL_DIRS (comma separated)
+
+use futures::Future;
+use server::ServerOptions;
+use server::decommission::DecommissionOptions;
+use server::resources::client::{ResourceManagerClient, ClientError};
+use server::resources::manager::ManagerConfig;
+use server::resources::pools::{Pool, PoolError, DEFAULT_POOL};
+use serde_json;
+use toml;
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: String,
+    pub group_name: String,
+    pub meta_members: Vec<String>,
+    pub is_meta: bool,
+    pub standalone: bool,
+    pub processors: Option<u16>, // detect if not set
+    pub memory: Option<u64>, // bytes, detect if not set
+    pub spill_dirs: Vec<String>,
+    // tables go after plain values for TOML
+    pub storage: StorageConfig,
+    pub shuffle: ShuffleConfig,
+    pub pools: Vec<PoolConfig>,
+    pub timeouts: TimeoutConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Memory,
+    Disk { path: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ShuffleConfig {
+    pub partitions: usize,
+    pub buffer_size: usize, // bytes per partition before spilling
+    pub compress: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    pub name: String,
+    pub parent: Option<String>,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(default)]
+    pub min_memory: u64,
+    #[serde(default)]
+    pub min_processors: u32,
+    pub max_memory: Option<u64>,
+    pub max_processors: Option<u32>,
+}
+
+// all in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    pub preemption_grace: u64,
+    pub lease_timeout: u64, // 0 to disable leases
+    pub heartbeat_interval: u64, // lease renewal of running partitions, see `RunningTasks::heartbeat`
+    pub decommission: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    Io(String, String), // path, error
+    UnknownFormat(String),
+    Parse(String, String), // path, error
+    InvalidEnv { key: String, value: String },
+    EmptyAddress,
+    InvalidAddress(String),
+    EmptyMetaMembers,
+    InvalidMetaMember(String),
+    StandaloneMustAlsoBeMetaServer,
+    ZeroProcessors,
+    ZeroMemory,
+    EmptyStoragePath,
+    EmptySpillDir,
+    ZeroShufflePartitions,
+    ZeroShuffleBufferSize,
+    DuplicatePool(String),
+    PoolParentNotFound { pool: String, parent: String },
+    ZeroPoolWeight(String),
+    PoolMinAboveMax(String),
+    ZeroTimeout(&'static str),
+    HeartbeatNotBelowLease,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            address: "127.0.0.1:5600".to_string(),
+            group_name: "hivemind".to_string(),
+            meta_members: Vec::new(),
+            is_meta: false,
+            standalone: false,
+            processors: None,
+            memory: None,
+            spill_dirs: Vec::new(),
+            storage: StorageConfig::Memory,
+            shuffle: ShuffleConfig::default(),
+            pools: Vec::new(),
+            timeouts: TimeoutConfig::default(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig::Memory
+    }
+}
+
+impl Default for ShuffleConfig {
+    fn default() -> ShuffleConfig {
+        ShuffleConfig {
+            partitions: 64,
+            buffer_size: 32 * 1024 * 1024,
+            compress: true,
+        }
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> TimeoutConfig {
+        let manager = ManagerConfig::default();
+        TimeoutConfig {
+            preemption_grace: manager.preemption_grace,
+            lease_timeout: manager.lease_timeout,
+            heartbeat_interval: 10 * 1000,
+            decommission: 10 * 60 * 1000,
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref e) => write!(f, "cannot read config file {}: {}", path, e),
+            ConfigError::UnknownFormat(ref path) =>
+                write!(f, "config file {} should end with .toml or .json", path),
+            ConfigError::Parse(ref path, ref e) => write!(f, "cannot parse config file {}: {}", path, e),
+            ConfigError::InvalidEnv { ref key, ref value } =>
+                write!(f, "invalid value '{}' for environment variable {}", value, key),
+            ConfigError::EmptyAddress => write!(f, "address is empty"),
+            ConfigError::InvalidAddress(ref addr) =>
+                write!(f, "address '{}' should be in host:port form", addr),
+            ConfigError::EmptyMetaMembers =>
+                write!(f, "meta_members is empty, it is only allowed in standalone mode"),
+            ConfigError::InvalidMetaMember(ref addr) =>
+                write!(f, "meta member '{}' should be in host:port form", addr),
+            ConfigError::StandaloneMustAlsoBeMetaServer =>
+                write!(f, "standalone server must also be a meta server, set is_meta = true"),
+            ConfigError::ZeroProcessors =>
+                write!(f, "processors is 0, leave it out to detect from the machine"),
+            ConfigError::ZeroMemory =>
+                write!(f, "memory is 0, leave it out to detect from the machine"),
+            ConfigError::EmptyStoragePath => write!(f, "disk storage needs a path"),
+            ConfigError::EmptySpillDir => write!(f, "spill_dirs contains an empty path"),
+            ConfigError::ZeroShufflePartitions => write!(f, "shuffle.partitions is 0"),
+            ConfigError::ZeroShuffleBufferSize => write!(f, "shuffle.buffer_size is 0"),
+            ConfigError::DuplicatePool(ref name) => write!(f, "pool '{}' is defined twice", name),
+            ConfigError::PoolParentNotFound { ref pool, ref parent } =>
+                write!(f, "parent '{}' of pool '{}' is not defined", parent, pool),
+            ConfigError::ZeroPoolWeight(ref name) => write!(f, "weight of pool '{}' is 0", name),
+            ConfigError::PoolMinAboveMax(ref name) =>
+                write!(f, "minimum share of pool '{}' is above its maximum", name),
+            ConfigError::ZeroTimeout(name) => write!(f, "timeouts.{} is 0", name),
+            ConfigError::HeartbeatNotBelowLease =>
+                write!(f, "timeouts.heartbeat_interval should be below timeouts.lease_timeout"),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_file(path: &str) -> Result<ServerConfig, ConfigError> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|e| ConfigError::Io(path.to_string(), format!("{}", e)))?;
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content)
+                .map_err(|e| ConfigError::Parse(path.to_string(), format!("{}", e))),
+            Some("json") => serde_json::from_str(&content)
+                .map_err(|e| ConfigError::Parse(path.to_string(), format!("{}", e))),
+            _ => Err(ConfigError::UnknownFormat(path.to_string()))
+        }
+    }
+
+    // Load the file if any, apply environment overrides, then validate
+    pub fn load(path: Option<&str>) -> Result<ServerConfig, Vec<ConfigError>> {
+        let mut config = match path {
+            Some(path) => ServerConfig::from_file(path).map_err(|e| vec![e])?,
+            None => ServerConfig::default()
+        };
+        config.apply_env(env::vars()).map_err(|e| vec![e])?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), ConfigError>
+        where I: Iterator<Item = (String, String)>
+    {
+        fn list(value: &str) -> Vec<String> {
+            value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+        }
+        for (key, value) in vars {
+            let invalid = || ConfigError::InvalidEnv { key: key.clone(), value: value.clone() };
+            match key.as_str() {
+                "HIVEMIND_ADDRESS" => self.address = value.clone(),
+                "HIVEMIND_GROUP_NAME" => self.group_name = value.clone(),
+                "HIVEMIND_META_MEMBERS" => self.meta_members = list(&value),
+                "HIVEMIND_IS_META" => self.is_meta = value.parse().map_err(|_| invalid())?,
+                "HIVEMIND_STANDALONE" => self.standalone = value.parse().map_err(|_| invalid())?,
+                "HIVEMIND_PROCESSORS" => self.processors = Some(value.parse().map_err(|_| invalid())?),
+                "HIVEMIND_MEMORY" => self.memory = Some(value.parse().map_err(|_| invalid())?),
+                "HIVEMIND_STORAGE" => self.storage = if value == "memory" {
+                    StorageConfig::Memory
+                } else {
+                    StorageConfig::Disk { path: value.clone() }
+                },
+                "HIVEMIND_SPILL_DIRS" => self.spill_dirs = list(&value),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        if self.address.is_empty() {
+            errors.push(ConfigError::EmptyAddress);
+        } else if self.address.parse::<SocketAddr>().is_err() && !is_host_port(&self.address) {
+            errors.push(ConfigError::InvalidAddress(self.address.clone()));
+        }
+        if self.meta_members.is_empty() && !self.standalone {
+            errors.push(ConfigError::EmptyMetaMembers);
+        }
+        for member in &self.meta_members {
+            if !is_host_port(member) {
+                errors.push(ConfigError::InvalidMetaMember(member.clone()));
+            }
+        }
+        if self.standalone && !self.is_meta {
+            errors.push(ConfigError::StandaloneMustAlsoBeMetaServer);
+        }
+        if self.processors == Some(0) {
+            errors.push(ConfigError::ZeroProcessors);
+        }
+        if self.memory == Some(0) {
+            errors.push(ConfigError::ZeroMemory);
+        }
+        if let StorageConfig::Disk { ref path } = self.storage {
+            if path.is_empty() {
+                errors.push(ConfigError::EmptyStoragePath);
+            }
+        }
+        if self.spill_dirs.iter().any(|dir| dir.is_empty()) {
+            errors.push(ConfigError::EmptySpillDir);
+        }
+        if self.shuffle.partitions == 0 {
+            errors.push(ConfigError::ZeroShufflePartitions);
+        }
+        if self.shuffle.buffer_size == 0 {
+            errors.push(ConfigError::ZeroShuffleBufferSize);
+        }
+        let mut names: BTreeSet<&str> = BTreeSet::new();
+        names.insert(DEFAULT_POOL);
+        for pool in &self.pools {
+            if !names.insert(pool.name.as_str()) {
+                errors.push(ConfigError::DuplicatePool(pool.name.clone()));
+            }
+        }
+        for pool in &self.pools {
+            if let Some(ref parent) = pool.parent {
+                if !names.contains(parent.as_str()) {
+                    errors.push(ConfigError::PoolParentNotFound {
+                        pool: pool.name.clone(), parent: parent.clone()
+                    });
+                }
+            }
+            if pool.weight == 0 {
+                errors.push(ConfigError::ZeroPoolWeight(pool.name.clone()));
+            }
+            if pool.max_memory.map(|max| pool.min_memory > max).unwrap_or(false) ||
+                pool.max_processors.map(|max| pool.min_processors > max).unwrap_or(false) {
+                errors.push(ConfigError::PoolMinAboveMax(pool.name.clone()));
+            }
+        }
+        if self.timeouts.preemption_grace == 0 {
+            errors.push(ConfigError::ZeroTimeout("preemption_grace"));
+        }
+        if self.timeouts.heartbeat_interval == 0 {
+            errors.push(ConfigError::ZeroTimeout("heartbeat_interval"));
+        }
+        if self.timeouts.decommission == 0 {
+            errors.push(ConfigError::ZeroTimeout("decommission"));
+        }
+        if self.timeouts.lease_timeout != 0 &&
+            self.timeouts.heartbeat_interval >= self.timeouts.lease_timeout {
+            errors.push(ConfigError::HeartbeatNotBelowLease);
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    pub fn to_options(&self) -> ServerOptions {
+        ServerOptions {
+            processors: self.processors.unwrap_or(0),
+            memory: self.memory.unwrap_or(0),
+            address: self.address.clone(),
+            group_name: self.group_name.clone(),
+            meta_members: if self.standalone && self.meta_members.is_empty() {
+                vec![self.address.clone()]
+            } else {
+                self.meta_members.clone()
+            },
+            storage: match self.storage {
+                StorageConfig::Memory => String::new(),
+                StorageConfig::Disk { ref path } => path.clone()
+            },
+            is_meta: self.is_meta,
+            standalone: self.standalone,
+            // stored until shuffle reads them
+            spill_dirs: self.spill_dirs.clone(),
+            shuffle: self.shuffle.clone(),
+        }
+    }
+
+    pub fn decommission_options(&self) -> DecommissionOptions {
+        DecommissionOptions {
+            timeout: Duration::from_millis(self.timeouts.decommission),
+            ..DecommissionOptions::default()
+        }
+    }
+
+    pub fn manager_config(&self) -> ManagerConfig {
+        ManagerConfig {
+            preemption_grace: self.timeouts.preemption_grace,
+            lease_timeout: self.timeouts.lease_timeout,
+            ..ManagerConfig::default()
+        }
+    }
+
+    pub fn to_pools(&self) -> Vec<Pool> {
+        self.pools.iter().map(|conf| {
+            let mut pool = Pool::new(&conf.name, conf.parent.as_ref().map(|p| p.as_str()), conf.weight);
+            pool.min_memory = conf.min_memory;
+            pool.min_processors = conf.min_processors;
+            pool.max_memory = conf.max_memory;
+            pool.max_processors = conf.max_processors;
+            pool
+        }).collect()
+    }
+
+    // Push pools and timeouts to the resource manager. Pools are created parents first, existing
+    //  ones are updated.
+    pub fn apply(&self, rm: &ResourceManagerClient) -> Result<(), String> {
+        rm.set_config(self.manager_config()).wait()
+            .map_err(|e| format!("Cannot set resource manager config: {:?}", e))?;
+        let mut pending = self.to_pools();
+        while !pending.is_empty() {
+            let before = pending.len();
+            let mut deferred = Vec::new();
+            for pool in pending {
+                let res = match rm.create_pool(pool.clone()).wait() {
+                    Err(ClientError::Manager(PoolError::PoolAlreadyExisted)) => {
+                        rm.update_pool(pool.clone()).wait()
+                    },
+                    res => res
+                };
+                match res {
+                    Ok(()) => {},
+                    Err(ClientError::Manager(PoolError::ParentNotFound(_))) => deferred.push(pool),
+                    Err(e) => return Err(format!("Cannot apply pool {}: {:?}", pool.name, e))
+                }
+            }
+            if deferred.len() == before {
+                return Err(format!("Cannot find parents of pools {:?}",
+                                   deferred.iter().map(|p| &p.name).collect::<Vec<_>>()));
+            }
+            pending = deferred;
+        }
+        Ok(())
+    }
+
+    // The effective config in TOML, for debugging deployments
+    pub fn dump(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_else(|e| format!("cannot dump config: {}", e))
+    }
+}
+
+fn is_host_port(addr: &str) -> bool {
+    match addr.rfind(':') {
+        Some(pos) => pos > 0 && addr[pos + 1..].parse::<u16>().is_ok(),
+        None => false
+    }
+}
+
+mod test {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        let mut config = ServerConfig::default();
+        config.processors = Some(0);
+        config.pools.push(PoolConfig {
+            name: "etl".to_string(), parent: Some("batch".to_string()), weight: 0,
+            min_memory: 0, min_processors: 0, max_memory: None, max_processors: None,
+        });
+        let errors = config.validate().unwrap_err();
+        assert!(errors.contains(&ConfigError::EmptyMetaMembers));
+        assert!(errors.contains(&ConfigError::ZeroProcessors));
+        assert!(errors.contains(&ConfigError::ZeroPoolWeight("etl".to_string())));
+        assert!(errors.contains(&ConfigError::PoolParentNotFound {
+            pool: "etl".to_string(), parent: "batch".to_string()
+        }));
+        config.standalone = true;
+        config.is_meta = true;
+        config.processors = None;
+        config.pools.clear();
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.to_options().meta_members, vec![config.address.clone()]);
+    }
+
+    #[test]
+    fn toml_and_env() {
+        let config: ServerConfig = toml::from_str(r#"
+            address = "10.0.0.1:5600"
+            meta_members = ["10.0.0.1:5600", "10.0.0.2:5600"]
+            processors = 8
+            [storage]
+            backend = "disk"
+            path = "/var/lib/hivemind"
+            [shuffle]
+            partitions = 16
+            [[pools]]
+            name = "etl"
+            max_memory = 1024
+        "#).unwrap();
+        assert_eq!(config.storage, StorageConfig::Disk { path: "/var/lib/hivemind".to_string() });
+        assert_eq!(config.pools[0].weight, 1);
+        let mut config = config;
+        let vars = vec![
+            ("HIVEMIND_PROCESSORS".to_string(), "16".to_string()),
+            ("HIVEMIND_META_MEMBERS".to_string(), "a:1, b:2".to_string()),
+            ("HIVEMIND_SPILL_DIRS".to_string(), "/tmp/a,/tmp/b".to_string()),
+        ];
+        config.apply_env(vars.into_iter()).unwrap();
+        assert_eq!(config.processors, Some(16));
+        assert_eq!(config.meta_members, vec!["a:1".to_string(), "b:2".to_string()]);
+        assert!(config.validate().is_ok());
+        let options = config.to_options();
+        assert_eq!(options.spill_dirs, vec!["/tmp/a".to_string(), "/tmp/b".to_string()]);
+        assert_eq!(options.shuffle.partitions, 16);
+        assert!(options.shuffle.compress);
+        let bad = vec![("HIVEMIND_MEMORY".to_string(), "lots".to_string())];
+        assert!(config.apply_env(bad.into_iter()).is_err());
+        let dumped: ServerConfig = toml::from_str(&config.dump()).unwrap();
+        assert_eq!(dumped, config);
+    }
+}
@@ -0,0 +1,96 @@
+// Taking a node out of the cluster for maintenance without killing running work.
+//  1. The node is put into `Draining`, it takes no new occupations and queued ones are moved away.
+//  2. Running occupations are given time to finish. Those still running after the timeout are
+//     marked lost, so their schedulers can place them elsewhere.
+//  3. Data that should survive the node, like cached blocks and shuffle outputs, is handed to
+//     peers by registered `DataMigration`s.
+//  4. The node leaves the membership group and deregisters.
+
+use futures::Future;
+use server::{HMServer, ServerError};
+use server::resources::manager::{ComputeNode, NodeFilter, OccupationStatus};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait DataMigration: Send + Sync {
+    fn name(&self) -> &str;
+    // `peers` are online and active nodes other than the decommissioned one
+    fn migrate(&self, peers: &Vec<ComputeNode>) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone)]
+pub struct DecommissionOptions {
+    pub timeout: Duration, // for running occupations to finish
+    pub poll_interval: Duration,
+}
+
+impl Default for DecommissionOptions {
+    fn default() -> DecommissionOptions {
+        DecommissionOptions {
+            timeout: Duration::from_secs(10 * 60),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+pub fn decommission(
+    server: &HMServer,
+    opts: &DecommissionOptions,
+    migrations: &Vec<Arc<DataMigration>>
+) -> Result<(), ServerError> {
+    let rm = &server.resource_manager;
+    let node_id = server.server_id;
+    if let Err(e) = rm.drain_node(node_id).wait() {
+        error!("Cannot drain node {}: {:?}", node_id, e);
+        return Err(ServerError::CannotDrainNode)
+    }
+    info!("Node {} is draining", node_id);
+    let started = Instant::now();
+    loop {
+        let running = match rm.node(node_id).wait() {
+            Ok(Some(node)) => node.occupations().values()
+                .filter(|occ| {
+                    occ.status == OccupationStatus::Running ||
+                        occ.status == OccupationStatus::Preempting
+                })
+                .count(),
+            Ok(None) => 0,
+            Err(e) => {
+                error!("Cannot check occupations of node {}: {:?}", node_id, e);
+                return Err(ServerError::CannotDrainNode)
+            }
+        };
+        if running == 0 {
+            break;
+        }
+        if started.elapsed() >= opts.timeout {
+            warn!("{} occupations are still running on node {}, they are lost", running, node_id);
+            if let Err(e) = rm.lose_node_occupations(node_id).wait() {
+                error!("Cannot mark occupations of node {} lost: {:?}", node_id, e);
+                return Err(ServerError::CannotDrainNode)
+            }
+            break;
+        }
+        thread::sleep(opts.poll_interval);
+    }
+    if !migrations.is_empty() {
+        let peers: Vec<ComputeNode> = match rm.nodes(NodeFilter { online_only: true, ..NodeFilter::default() }).wait() {
+            Ok(nodes) => nodes.into_iter()
+                .filter(|node| node.node_id() != node_id && node.accepts())
+                .collect(),
+            Err(e) => {
+                error!("Cannot find peers to migrate data to: {:?}", e);
+                return Err(ServerError::CannotMigrateData)
+            }
+        };
+        for migration in migrations {
+            if let Err(e) = migration.migrate(&peers) {
+                error!("Cannot migrate {} from node {}: {}", migration.name(), node_id, e);
+                return Err(ServerError::CannotMigrateData)
+            }
+        }
+    }
+    server.leave_group();
+    server.shutdown()
+}
@@ -0,0 +1,47 @@
+// Meta servers run the raft group holding cluster membership and the resource manager.
+// The first meta server bootstraps the group, others join through `meta_members`.
+
+use bifrost::rpc;
+use bifrost::raft;
+use bifrost::raft::{RaftService, Options, Storage};
+use bifrost::raft::state_machine::callback::server::SMCallback;
+use bifrost::membership;
+use bifrost::membership::server::Membership;
+use bifrost_hasher::hash_str;
+use server::{ServerOptions, ServerError};
+use server::resources::manager::ResourceManager;
+use std::sync::Arc;
+
+pub fn bootstrap(
+    opts: &ServerOptions,
+    rpc: &Arc<rpc::Server>
+) -> Result<Arc<RaftService>, ServerError> {
+    let raft_service = RaftService::new(Options {
+        storage: if opts.storage.is_empty() {
+            Storage::Default()
+        } else {
+            Storage::Disk(opts.storage.clone())
+        },
+        address: opts.address.clone(),
+        service_id: raft::DEFAULT_SERVICE_ID,
+    });
+    rpc.register_service(raft::DEFAULT_SERVICE_ID, &raft_service);
+    RaftService::start(&raft_service);
+    let others: Vec<String> = opts.meta_members.iter()
+        .filter(|addr| **addr != opts.address)
+        .cloned()
+        .collect();
+    if others.is_empty() {
+        raft_service.bootstrap();
+    } else if let Err(e) = raft_service.join(&others) {
+        error!("Cannot join meta cluster: {:?}", e);
+        return Err(ServerError::CannotJoinCluster)
+    }
+    Membership::new(rpc, &raft_service);
+    // membership changes are fed into the resource manager to mark nodes online or offline
+    let membership_cb = SMCallback::new(membership::raft::DEFAULT_SERVICE_ID, raft_service.clone());
+    let group_id = hash_str(&opts.group_name);
+    let manager = ResourceManager::new(raft_service.clone(), group_id, &membership_cb);
+    raft_service.register_state_machine(Box::new(manager));
+    Ok(raft_service)
+}
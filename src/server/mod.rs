@@ -6,8 +6,19 @@ use bifrost::raft::state_machine::{master as sm_master};
 use bifrost::membership::server::Membership;
 use bifrost::membership::member::MemberService;
 use std::sync::Arc;
+use parking_lot::RwLock;
+use self::resources::client::ResourceManagerClient;
+use self::config::ShuffleConfig;
+use scheduler::cancel::RunningTasks;
+use scheduler::locality::Locality;
 
-mod resources;
+pub mod resources;
+pub mod service;
+pub mod node;
+pub mod meta;
+pub mod standalone;
+pub mod decommission;
+pub mod config;
 
 #[derive(Debug)]
 pub enum ServerError {
@@ -19,22 +30,44 @@ pub enum ServerError {
     CannotLoadMetaClient,
     CannotInitializeSchemaServer(sm_master::ExecError),
     StandaloneMustAlsoBeMetaServer,
+    CannotDetectCapacity,
+    CannotRegisterNode,
+    CannotDeregisterNode,
+    CannotDrainNode,
+    CannotMigrateData,
 }
 
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerOptions {
-    pub processors: u16,
+    pub processors: u16, // 0 to detect
+    #[serde(default)]
+    pub memory: u64, // bytes, 0 to detect
     pub address: String,
     pub group_name: String,
     pub meta_members: Vec<String>,
-    pub storage: String
+    pub storage: String,
+    #[serde(default)]
+    pub is_meta: bool, // run raft and the resource manager on this server
+    #[serde(default)]
+    pub standalone: bool, // single process mode, see `standalone`
+    #[serde(default)]
+    pub spill_dirs: Vec<String>, // for shuffle output that does not fit in memory
+    #[serde(default)]
+    pub shuffle: ShuffleConfig,
 }
 
 pub struct HMServer {
     pub rpc: Arc<rpc::Server>,
     pub member_pool: rpc::ClientPool,
-    pub server_id: u64
+    pub server_id: u64,
+    pub resource_manager: Arc<ResourceManagerClient>,
+    pub raft_service: Option<Arc<raft::RaftService>>, // only on meta servers
+    pub member_service: Arc<MemberService>,
+    pub tasks: Arc<RunningTasks>, // executing here, interrupted when canceled
+    pub locality: Arc<Locality>,
+    group_name: String,
+    migrations: RwLock<Vec<Arc<decommission::DataMigration>>>,
 }
 
 impl HMServer {
@@ -42,20 +75,72 @@ impl HMServer {
         opts: &ServerOptions,
         rpc: &Arc<rpc::Server>,
     ) -> Result<Arc<HMServer>, ServerError> {
-        HMServer::load_cluster_clients(&opts, &rpc)?;
+        if opts.standalone && !opts.is_meta {
+            return Err(ServerError::StandaloneMustAlsoBeMetaServer)
+        }
+        let raft_service = if opts.is_meta {
+            Some(meta::bootstrap(opts, rpc)?)
+        } else {
+            None
+        };
+        let (raft_client, member_service) = HMServer::load_cluster_clients(&opts, &rpc)?;
+        let resource_manager = node::register(opts, rpc.server_id, &raft_client)?;
+        let tasks = RunningTasks::new();
+        RunningTasks::watch(&tasks, &resource_manager, rpc.server_id);
+        let locality = Locality::new();
+        rpc.register_service(
+            service::DEFAULT_SERVICE_ID,
+            &Arc::new(service::HMService::new(&resource_manager, &locality))
+        );
         Ok(Arc::new(
             HMServer {
                 rpc: rpc.clone(),
                 member_pool: rpc::ClientPool::new(),
-                server_id: rpc.server_id
+                server_id: rpc.server_id,
+                resource_manager,
+                raft_service,
+                member_service,
+                tasks,
+                locality,
+                group_name: opts.group_name.clone(),
+                migrations: RwLock::new(Vec::new()),
             }
         ))
     }
 
-    pub fn join_group(opt: &ServerOptions, raft_client: &Arc<RaftClient>) -> Result<(), ServerError> {
+    // Remove this server from the resource manager, running occupations on it are lost
+    pub fn shutdown(&self) -> Result<(), ServerError> {
+        node::deregister(self.server_id, &self.resource_manager)
+    }
+
+    // Graceful shutdown, see `decommission`
+    pub fn decommission(&self, opts: &decommission::DecommissionOptions) -> Result<(), ServerError> {
+        let migrations = self.migrations.read().clone();
+        decommission::decommission(self, opts, &migrations)
+    }
+    pub fn add_migration(&self, migration: Arc<decommission::DataMigration>) {
+        self.migrations.write().push(migration);
+    }
+
+    // Leave the group without deregistering, the resource manager will see the node offline
+    pub fn leave_group(&self) {
+        if let Err(e) = self.member_service.leave() {
+            error!("Cannot leave cluster group: {:?}", e);
+        }
+    }
+    pub fn rejoin_group(&self) -> Result<(), ServerError> {
+        match self.member_service.join_group(&self.group_name) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ServerError::CannotJoinClusterGroup(e))
+        }
+    }
+
+    pub fn join_group(opt: &ServerOptions, raft_client: &Arc<RaftClient>)
+        -> Result<Arc<MemberService>, ServerError>
+    {
         let member_service = MemberService::new(&opt.address, raft_client);
         match member_service.join_group(&opt.group_name) {
-            Ok(_) => Ok(()),
+            Ok(_) => Ok(member_service),
             Err(e) => {
                 error!("Cannot join cluster group");
                 Err(ServerError::CannotJoinClusterGroup(e))
@@ -65,14 +150,14 @@ impl HMServer {
     fn load_cluster_clients (
         opt: &ServerOptions,
         rpc_server: &Arc<rpc::Server>
-    ) -> Result<(), ServerError> {
+    ) -> Result<(Arc<RaftClient>, Arc<MemberService>), ServerError> {
         let raft_client =
             RaftClient::new(&opt.meta_members, raft::DEFAULT_SERVICE_ID);
         match raft_client {
             Ok(raft_client) => {
                 RaftClient::prepare_subscription(rpc_server);
-                Self::join_group(opt, &raft_client)?;
-                Ok(())
+                let member_service = Self::join_group(opt, &raft_client)?;
+                Ok((raft_client, member_service))
             },
             Err(e) => {
                 error!("Cannot load meta client: {:?}", e);
@@ -0,0 +1,113 @@
+// Registers this server as a compute node in the resource manager.
+// Capacity comes from `ServerOptions`, zero means detecting it from the machine.
+
+use bifrost::raft::client::RaftClient;
+use futures::Future;
+use server::resources::manager::{ComputeNode, RegisterNodeError};
+use server::resources::client::{ResourceManagerClient, ClientError};
+use server::{ServerOptions, ServerError};
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+use num_cpus;
+
+// Returns bytes of physical memory, reading `/proc/meminfo` on Linux
+pub fn detect_memory() -> Option<u64> {
+    let mut meminfo = String::new();
+    File::open("/proc/meminfo").ok()?.read_to_string(&mut meminfo).ok()?;
+    parse_meminfo(&meminfo)
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    meminfo.lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+pub fn detect_processors() -> u32 {
+    num_cpus::get() as u32
+}
+
+pub fn node_capacity(opts: &ServerOptions) -> Result<(u64, u32), ServerError> {
+    let memory = if opts.memory > 0 {
+        opts.memory
+    } else {
+        match detect_memory() {
+            Some(memory) => memory,
+            None => {
+                error!("Cannot detect memory of this server, please set it in options");
+                return Err(ServerError::CannotDetectCapacity)
+            }
+        }
+    };
+    let processors = if opts.processors > 0 {
+        opts.processors as u32
+    } else {
+        detect_processors()
+    };
+    Ok((memory, processors))
+}
+
+// Register the node, or make sure the existing record is up to date after a restart.
+// The node comes back online through group membership, a record with different address or
+//  capacity is replaced.
+pub fn register(
+    opts: &ServerOptions,
+    node_id: u64,
+    raft_client: &Arc<RaftClient>
+) -> Result<Arc<ResourceManagerClient>, ServerError> {
+    let (memory, processors) = node_capacity(opts)?;
+    let node = ComputeNode::new(&opts.address, node_id, memory, processors);
+    let client = Arc::new(ResourceManagerClient::new(raft_client));
+    match client.register_node(node.clone()).wait() {
+        Ok(()) => {
+            info!("Registered compute node {} with {} bytes memory, {} processors",
+                  node_id, memory, processors);
+        },
+        Err(ClientError::Manager(RegisterNodeError::NodeAlreadyExisted)) => {
+            let existing = match client.node(node_id).wait() {
+                Ok(existing) => existing,
+                _ => return Err(ServerError::CannotRegisterNode)
+            };
+            let unchanged = existing.map(|existing| {
+                existing.address() == node.address() &&
+                    existing.memory() == memory &&
+                    existing.processors() == processors
+            }).unwrap_or(false);
+            if !unchanged {
+                info!("Compute node {} changed, registering again", node_id);
+                let reregistered = client.deregister_node(node_id).wait().is_ok() &&
+                    client.register_node(node).wait().is_ok();
+                if !reregistered {
+                    return Err(ServerError::CannotRegisterNode)
+                }
+            }
+        },
+        Err(e) => {
+            error!("Cannot register compute node: {:?}", e);
+            return Err(ServerError::CannotRegisterNode)
+        }
+    }
+    Ok(client)
+}
+
+pub fn deregister(node_id: u64, client: &ResourceManagerClient) -> Result<(), ServerError> {
+    match client.deregister_node(node_id).wait() {
+        Ok(()) => Ok(()),
+        _ => {
+            error!("Cannot deregister compute node {}", node_id);
+            Err(ServerError::CannotDeregisterNode)
+        }
+    }
+}
+
+mod test {
+    #[test]
+    fn meminfo() {
+        let meminfo = "MemTotal:       16314708 kB\nMemFree:         1225232 kB\n";
+        assert_eq!(super::parse_meminfo(meminfo), Some(16314708 * 1024));
+        assert_eq!(super::parse_meminfo("MemFree: 1 kB"), None);
+    }
+}
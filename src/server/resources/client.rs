@@ -0,0 +1,183 @@
+// High level client of the resource manager. Calls run on a thread pool and return futures,
+//  subscriptions forward to the generated state machine client.
+
+use bifrost::raft::client::{RaftClient, SubscriptionError, SubscriptionReceipt};
+use bifrost::raft::state_machine::master::ExecError;
+use futures_cpupool::{CpuPool, CpuFuture};
+use std::sync::Arc;
+use super::manager::*;
+use super::manager::client::SMClient;
+use super::pools::{Pool, PoolError};
+use super::constraints::Labels;
+
+#[derive(Debug)]
+pub enum ClientError<E> {
+    Exec(ExecError), // failed to reach the raft group
+    Manager(E), // rejected by the resource manager
+}
+
+pub struct ResourceManagerClient {
+    sm: Arc<SMClient>,
+    pool: CpuPool,
+}
+
+impl ResourceManagerClient {
+    pub fn new(raft_client: &Arc<RaftClient>) -> ResourceManagerClient {
+        ResourceManagerClient {
+            sm: Arc::new(SMClient::new(DEFAULT_SERVICE_ID, raft_client)),
+            pool: CpuPool::new(2),
+        }
+    }
+    // the generated client, for blocking calls
+    pub fn sm(&self) -> &Arc<SMClient> {
+        &self.sm
+    }
+    fn spawn<T, E, F>(&self, f: F) -> CpuFuture<T, ClientError<E>>
+        where F: FnOnce(&SMClient) -> Result<Result<T, E>, ExecError> + Send + 'static,
+              T: Send + 'static, E: Send + 'static
+    {
+        let sm = self.sm.clone();
+        self.pool.spawn_fn(move || match f(&sm) {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(e)) => Err(ClientError::Manager(e)),
+            Err(e) => Err(ClientError::Exec(e))
+        })
+    }
+
+    pub fn register_node(&self, node: ComputeNode) -> CpuFuture<(), ClientError<RegisterNodeError>> {
+        self.spawn(move |sm| sm.register_node(&node))
+    }
+    pub fn deregister_node(&self, node_id: u64) -> CpuFuture<(), ClientError<String>> {
+        self.spawn(move |sm| sm.deregister_node(&node_id))
+    }
+    #[deprecated(note = "misspelled, use `deregister_node`")]
+    pub fn degegister_node(&self, node_id: u64) -> CpuFuture<(), ClientError<String>> {
+        self.deregister_node(node_id)
+    }
+    pub fn drain_node(&self, node_id: u64) -> CpuFuture<(), ClientError<String>> {
+        self.spawn(move |sm| sm.drain_node(&node_id))
+    }
+    pub fn undrain_node(&self, node_id: u64) -> CpuFuture<(), ClientError<String>> {
+        self.spawn(move |sm| sm.undrain_node(&node_id))
+    }
+    pub fn lose_node_occupations(&self, node_id: u64) -> CpuFuture<(), ClientError<String>> {
+        self.spawn(move |sm| sm.lose_node_occupations(&node_id))
+    }
+    pub fn update_node_labels(&self, node_id: u64, labels: Labels) -> CpuFuture<(), ClientError<String>> {
+        self.spawn(move |sm| sm.update_node_labels(&node_id, &labels))
+    }
+    pub fn register_task(&self, task: Task, occupations: Vec<Occupation>)
+        -> CpuFuture<Vec<Occupation>, ClientError<RegisterTaskError>>
+    {
+        self.spawn(move |sm| sm.register_task(&task, &occupations))
+    }
+    pub fn update_task_status(&self, task_id: u64, status: TaskStatus) -> CpuFuture<(), ClientError<String>> {
+        self.spawn(move |sm| sm.update_task_status(&task_id, &status))
+    }
+    pub fn task_ended(&self, task_id: u64, status: TaskStatus) -> CpuFuture<(), ClientError<String>> {
+        self.spawn(move |sm| sm.task_ended(&task_id, &status))
+    }
+    // Mark the task canceled, its occupations are released and executors interrupt it
+    pub fn cancel_job(&self, task_id: u64) -> CpuFuture<(), ClientError<String>> {
+        self.task_ended(task_id, TaskStatus::Canceled)
+    }
+    pub fn try_acquire_node_resource(&self, task_id: u64, stage_id: u64, node_id: u64)
+        -> CpuFuture<bool, ClientError<ChangeOccupationStatusError>>
+    {
+        self.spawn(move |sm| sm.try_acquire_node_resource(&task_id, &stage_id, &node_id))
+    }
+    pub fn release_occupation(&self, task_id: u64, stage_id: u64, node_id: u64)
+        -> CpuFuture<bool, ClientError<ChangeOccupationStatusError>>
+    {
+        self.spawn(move |sm| sm.release_occupation(&task_id, &stage_id, &node_id))
+    }
+    pub fn preemption_done(&self, task_id: u64, stage_id: u64, node_id: u64)
+        -> CpuFuture<bool, ClientError<ChangeOccupationStatusError>>
+    {
+        self.spawn(move |sm| sm.preemption_done(&task_id, &stage_id, &node_id))
+    }
+    // report a failed attempt, the reason is kept on the task
+    pub fn stage_failed(&self, task_id: u64, stage_id: u64, node_id: u64, reason: String)
+        -> CpuFuture<RetryDecision, ClientError<ChangeOccupationStatusError>>
+    {
+        self.spawn(move |sm| sm.stage_failed(&task_id, &stage_id, &node_id, &reason))
+    }
+    pub fn retry_stage(&self, occupation: Occupation) -> CpuFuture<bool, ClientError<RegisterTaskError>> {
+        self.spawn(move |sm| sm.retry_stage(&occupation))
+    }
+    // extra copy of a straggling stage on another node
+    pub fn speculate_stage(&self, occupation: Occupation) -> CpuFuture<bool, ClientError<RegisterTaskError>> {
+        self.spawn(move |sm| sm.speculate_stage(&occupation))
+    }
+    // true if the output of the copy on the node is the one to keep
+    pub fn commit_partition(&self, task_id: u64, stage_id: u64, node_id: u64)
+        -> CpuFuture<bool, ClientError<ChangeOccupationStatusError>>
+    {
+        self.spawn(move |sm| sm.commit_partition(&task_id, &stage_id, &node_id))
+    }
+    pub fn heartbeat_occupation(&self, task_id: u64, stage_id: u64, node_id: u64)
+        -> CpuFuture<bool, ClientError<ChangeOccupationStatusError>>
+    {
+        self.spawn(move |sm| sm.heartbeat_occupation(&task_id, &stage_id, &node_id))
+    }
+    pub fn tick(&self, now: u64) -> CpuFuture<(), ClientError<()>> {
+        self.spawn(move |sm| sm.tick(&now))
+    }
+    pub fn set_config(&self, config: ManagerConfig) -> CpuFuture<(), ClientError<()>> {
+        self.spawn(move |sm| sm.set_config(&config))
+    }
+    pub fn create_pool(&self, pool: Pool) -> CpuFuture<(), ClientError<PoolError>> {
+        self.spawn(move |sm| sm.create_pool(&pool))
+    }
+    pub fn update_pool(&self, pool: Pool) -> CpuFuture<(), ClientError<PoolError>> {
+        self.spawn(move |sm| sm.update_pool(&pool))
+    }
+
+    pub fn node(&self, node_id: u64) -> CpuFuture<Option<ComputeNode>, ClientError<()>> {
+        self.spawn(move |sm| sm.node(&node_id))
+    }
+    pub fn nodes(&self, filter: NodeFilter) -> CpuFuture<Vec<ComputeNode>, ClientError<()>> {
+        self.spawn(move |sm| sm.query_nodes(&filter))
+    }
+    pub fn task(&self, task_id: u64) -> CpuFuture<Option<Task>, ClientError<()>> {
+        self.spawn(move |sm| sm.task(&task_id))
+    }
+    pub fn tasks(&self, filter: TaskFilter) -> CpuFuture<Vec<Task>, ClientError<()>> {
+        self.spawn(move |sm| sm.query_tasks(&filter))
+    }
+    pub fn pools(&self) -> CpuFuture<Vec<Pool>, ClientError<()>> {
+        self.spawn(move |sm| sm.pools())
+    }
+    pub fn config(&self) -> CpuFuture<ManagerConfig, ClientError<()>> {
+        self.spawn(move |sm| sm.config())
+    }
+    pub fn utilization(&self) -> CpuFuture<ClusterUtilization, ClientError<()>> {
+        self.spawn(move |sm| sm.utilization())
+    }
+
+    pub fn on_member_changed<F>(&self, f: F) -> Result<SubscriptionReceipt, SubscriptionError>
+        where F: Fn(Result<ComputeNode, ()>) + 'static + Send + Sync
+    {
+        self.sm.on_member_changed(f)
+    }
+    pub fn on_occupation_changed<F>(&self, f: F) -> Result<SubscriptionReceipt, SubscriptionError>
+        where F: Fn(Result<Occupation, ()>) + 'static + Send + Sync
+    {
+        self.sm.on_occupation_changed(f)
+    }
+    pub fn on_resource_available<F>(&self, f: F) -> Result<SubscriptionReceipt, SubscriptionError>
+        where F: Fn(Result<Occupation, ()>) + 'static + Send + Sync
+    {
+        self.sm.on_resource_available(f)
+    }
+    pub fn on_preemption<F>(&self, f: F) -> Result<SubscriptionReceipt, SubscriptionError>
+        where F: Fn(Result<Occupation, ()>) + 'static + Send + Sync
+    {
+        self.sm.on_preemption(f)
+    }
+    pub fn on_task_ended<F>(&self, f: F) -> Result<SubscriptionReceipt, SubscriptionError>
+        where F: Fn(Result<Task, ()>) + 'static + Send + Sync
+    {
+        self.sm.on_task_ended(f)
+    }
+}
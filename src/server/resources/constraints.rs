@@ -0,0 +1,114 @@
+// Placement constraints of occupations, matched against node labels.
+// Required selectors and anti-affinities are hard constraints enforced by the resource manager.
+// Preferred selectors are only hints for schedulers to rank nodes, see `preferred_score`.
+
+use std::collections::BTreeMap;
+
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SelectorOp {
+    In(Vec<String>),
+    NotIn(Vec<String>),
+    Exists,
+    DoesNotExist,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LabelSelector {
+    pub key: String,
+    pub op: SelectorOp,
+}
+
+// Keep away from occupations of `task_id`. With `topology_key`, nodes sharing the same value of
+//  that label (like a rack) count as the same place. Nodes without the label only conflict with
+//  themselves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AntiAffinity {
+    pub task_id: u64,
+    pub topology_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Constraints {
+    pub required: Vec<LabelSelector>,
+    pub preferred: Vec<LabelSelector>,
+    pub anti_affinity: Vec<AntiAffinity>,
+}
+
+impl LabelSelector {
+    pub fn is_in(key: &str, values: &[&str]) -> LabelSelector {
+        LabelSelector {
+            key: key.to_string(),
+            op: SelectorOp::In(values.iter().map(|v| v.to_string()).collect())
+        }
+    }
+    pub fn exists(key: &str) -> LabelSelector {
+        LabelSelector { key: key.to_string(), op: SelectorOp::Exists }
+    }
+    pub fn matches(&self, labels: &Labels) -> bool {
+        let value = labels.get(&self.key);
+        match self.op {
+            SelectorOp::In(ref values) => value.map(|v| values.contains(v)).unwrap_or(false),
+            SelectorOp::NotIn(ref values) => value.map(|v| !values.contains(v)).unwrap_or(true),
+            SelectorOp::Exists => value.is_some(),
+            SelectorOp::DoesNotExist => value.is_none(),
+        }
+    }
+}
+
+impl Constraints {
+    pub fn is_empty(&self) -> bool {
+        self.required.is_empty() && self.preferred.is_empty() && self.anti_affinity.is_empty()
+    }
+    pub fn matches_required(&self, labels: &Labels) -> bool {
+        self.required.iter().all(|selector| selector.matches(labels))
+    }
+    // number of preferred selectors matched, higher is better
+    pub fn preferred_score(&self, labels: &Labels) -> usize {
+        self.preferred.iter().filter(|selector| selector.matches(labels)).count()
+    }
+}
+
+impl AntiAffinity {
+    // whether nodes with labels `a` and `b` are in the same topology domain
+    pub fn same_domain(&self, a_id: u64, a: &Labels, b_id: u64, b: &Labels) -> bool {
+        if a_id == b_id {
+            return true;
+        }
+        match self.topology_key {
+            Some(ref key) => match (a.get(key), b.get(key)) {
+                (Some(a), Some(b)) => a == b,
+                _ => false
+            },
+            None => false
+        }
+    }
+}
+
+mod test {
+    use super::*;
+
+    #[test]
+    fn selectors() {
+        let mut labels = Labels::new();
+        labels.insert("rack".to_string(), "r1".to_string());
+        labels.insert("neb".to_string(), "true".to_string());
+        let constraints = Constraints {
+            required: vec![LabelSelector::exists("neb")],
+            preferred: vec![LabelSelector::is_in("rack", &["r2"])],
+            anti_affinity: vec![],
+        };
+        assert!(constraints.matches_required(&labels));
+        assert_eq!(constraints.preferred_score(&labels), 0);
+        labels.remove("neb");
+        assert!(!constraints.matches_required(&labels));
+        let anti = AntiAffinity { task_id: 1, topology_key: Some("rack".to_string()) };
+        let mut other = Labels::new();
+        other.insert("rack".to_string(), "r1".to_string());
+        assert!(anti.same_domain(1, &labels, 2, &other));
+        other.insert("rack".to_string(), "r2".to_string());
+        assert!(!anti.same_domain(1, &labels, 2, &other));
+        assert!(anti.same_domain(2, &labels, 2, &other));
+    }
+}
@@ -4,10 +4,41 @@
 //  placement. All of those occupation status should be initialized to `Scheduled`
 // In the meanwhile, if there is resources available, RM may change occupation status into
 //  `Running` and return new status to scheduler on `register_task`, for performance consideration.
-// When scheduler received notification from RM about occupation changes, it should try to
-//  compete with other nodes through `try_acquire_node_resource` API. If it returns true, then
-//  the scheduler is safe to use the resource like sending job to the node. If RM return false,
-//  the scheduler should wait for another new resource notification from RM.
+// Occupations that cannot run right away are put into the admission queue of their node, ordered
+//  by task priority, then task submission time, then the order they arrived at RM. When resources
+//  are released, RM promotes queued occupations to `Running` by itself and notifies schedulers
+//  through `on_occupation_changed`, so schedulers don't need to race for resources.
+// Each task belongs to a scheduling pool (see `pools`). Queued occupations are admitted from the
+//  pools by weighted fair share. Within a pool the queue is strict: if the head occupation of the
+//  chosen pool cannot be afforded, others have to wait, even if they are smaller. This prevents
+//  large occupations from starving. Pools that reached their quotas are skipped.
+// `try_acquire_node_resource` is kept for schedulers that manage resources by themselves. It
+//  bypasses the queue.
+// If the highest priority occupation queued on a node cannot be afforded, RM will preempt running
+//  occupations of lower priority, the lowest priority and the most recently started first. Victims
+//  are marked `Preempting` and their schedulers are notified through `on_preemption` to checkpoint
+//  or kill the work. The capacity is reclaimed when the scheduler calls `preemption_done`, or
+//  forcibly after the grace period.
+// When a node goes offline or is deregistered, all its occupations become `Lost`, and their tasks
+//  are notified through `on_occupation_changed`. Offline nodes don't admit any occupation.
+// Running occupations are leased. Executors must renew the lease by `heartbeat_occupation` within
+//  `lease_timeout`, or the occupation is marked `Expired` and its resources reclaimed, in case the
+//  executor hangs.
+// Tasks start `Pending`, become `Running` when any of their occupations starts to run, and may be
+//  marked `Succeeding` by the scheduler while committing results. `task_ended` moves a task into a
+//  terminal status and releases all its occupations. Ended tasks are kept for a while as history,
+//  bounded by `finished_task_limit` and `finished_task_retention`.
+// Capacity of nodes and demand of occupations are `Resources` vectors (see `vector`). Memory and
+//  processors are dimensions in them like the optional ones. An occupation can only run when all
+//  of its dimensions fit.
+// Nodes carry labels, and occupations may carry placement constraints (see `constraints`). RM checks
+//  required selectors and anti-affinities on `register_task`, and again before admitting queued
+//  occupations, as labels and placed occupations may have changed in the meantime.
+// Nodes to be decommissioned are put into `Draining` by `drain_node`. Draining nodes don't take new
+//  occupations, queued ones are marked `Lost` for schedulers to place them elsewhere, and running
+//  ones can finish.
+// RM does not read clocks by itself to stay deterministic. Time only advances by `tick`, which
+//  should be called periodically with the timestamp of the raft leader.
 
 
 use bifrost::conshash::ConsistentHashing;
@@ -21,10 +52,14 @@ use bifrost::membership::raft::commands::{
 use bifrost::membership::client::{Member as ClientMember};
 use bifrost::utils::bincode;
 use neb::dovahkiin::types::Map;
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::sync::Arc;
 use parking_lot::RwLock;
 use itertools::Itertools;
+use super::pools::{Pools, Pool, PoolError};
+use super::vector::{self, Resources};
+use super::constraints::{Constraints, Labels};
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(HIVEMIND_RESOURCE_MANAGER) as u64;
 
@@ -35,8 +70,16 @@ raft_state_machine! {
         occupations: Vec<Occupation>
     ) -> Vec<Occupation> | RegisterTaskError;
 
+    // deprecated, misspelled name of `deregister_node`. Only kept to replay old raft logs.
     def cmd degegister_node(node_id: u64) | String;
+    def cmd deregister_node(node_id: u64) | String;
+    def cmd update_node_labels(node_id: u64, labels: Labels) | String;
+    def cmd drain_node(node_id: u64) | String;
+    def cmd undrain_node(node_id: u64) | String;
+    // give up occupations still on the node, when decommission times out
+    def cmd lose_node_occupations(node_id: u64) | String;
     def cmd task_ended(task_id: u64, status: TaskStatus) | String;
+    def cmd update_task_status(task_id: u64, status: TaskStatus) | String;
 
     def cmd try_acquire_node_resource(
         task_id: u64,
@@ -50,49 +93,121 @@ raft_state_machine! {
         node_id: u64
     ) -> bool | ChangeOccupationStatusError;
 
+    def cmd preemption_done(
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64
+    ) -> bool | ChangeOccupationStatusError;
+
+    def cmd stage_failed(
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64,
+        reason: String
+    ) -> RetryDecision | ChangeOccupationStatusError;
+    def cmd retry_stage(occupation: Occupation) -> bool | RegisterTaskError;
+    def cmd speculate_stage(occupation: Occupation) -> bool | RegisterTaskError;
+    def cmd commit_partition(
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64
+    ) -> bool | ChangeOccupationStatusError;
+
+    def cmd heartbeat_occupation(
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64
+    ) -> bool | ChangeOccupationStatusError;
+    def cmd tick(now: u64);
+    def cmd set_config(config: ManagerConfig);
+
+    def cmd create_pool(pool: Pool) | PoolError;
+    def cmd update_pool(pool: Pool) | PoolError;
+
     def qry tasks() -> Vec<Task>;
     def qry nodes() -> Vec<ComputeNode>;
+    def qry pools() -> Vec<Pool>;
+    def qry config() -> ManagerConfig;
+    def qry task(task_id: u64) -> Option<Task>;
+    def qry node(node_id: u64) -> Option<ComputeNode>;
+    def qry query_tasks(filter: TaskFilter) -> Vec<Task>;
+    def qry query_nodes(filter: NodeFilter) -> Vec<ComputeNode>;
+    def qry utilization() -> ClusterUtilization;
 
     def sub on_member_changed() -> ComputeNode;
     def sub on_occupation_changed() -> Occupation;
     def sub on_resource_available() -> Occupation;
+    def sub on_preemption() -> Occupation;
+    def sub on_task_ended() -> Task; // executors interrupt canceled tasks on this
 }
 
 macro_rules! acquire_res {
-    ($s: expr, $node: expr, $occ: expr) => {
+    ($pools: expr, $clock: expr, $node: expr, $occ: expr) => {
         $occ.status = OccupationStatus::Running;
-        $node.memory_remains -= $occ.memory;
-        $node.processors_remains -= $occ.workers;
-        $s.notify_occupation_changes($occ);
+        $occ.started = $clock;
+        $occ.last_updated = $clock;
+        $node.resources_remains.sub(&$occ.resources);
+        $pools.account(&$occ.pool, $occ.memory(), $occ.workers(), true);
+    };
+}
+
+macro_rules! release_res {
+    ($pools: expr, $node: expr, $occ: expr) => {
+        $node.resources_remains.add(&$occ.resources);
+        $pools.account(&$occ.pool, $occ.memory(), $occ.workers(), false);
     };
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum RegisterNodeError {
     NodeAlreadyExisted
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum RegisterTaskError {
     NodeIdNotFound(u64),
-    OccupationStatusNotScheduled
+    NodeOffline(u64),
+    NodeDraining(u64),
+    OccupationStatusNotScheduled,
+    PoolNotFound(String),
+    QuotaExceeded,
+    ConstraintsNotSatisfied(u64), // stage id
+    NodeBlacklisted(u64),
+    TaskNotFound(u64),
+    TaskEnded(u64),
+    // for `retry_stage`, with stage ids
+    StageNotFailed(u64),
+    StageAlreadyPlaced(u64),
+    AttemptsExhausted(u64),
+    BackoffNotElapsed(u64),
+    // for `speculate_stage`, with stage ids
+    StageNotRunning(u64),
+    StageCommitted(u64),
+    SpeculationLimit(u64),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum ChangeOccupationStatusError {
     CannotFindOccupation,
-    OccupationTaskNotMatch
+    OccupationTaskNotMatch,
+    OccupationNotRunning(OccupationStatus),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Occupation {
     pub task_id: u64,
     pub stage_id: u64,
-    pub workers: u32,
-    pub memory: u64,
     pub node_id: u64,
     pub status: OccupationStatus,
-    pub last_updated: u64,
+    pub last_updated: u64, // last lease renewal
+    // copied from the task when registered
+    pub priority: u32,
+    pub submitted: u64,
+    pub pool: String,
+    pub started: u64,
+    pub preempt_deadline: u64, // only for `Preempting`
+    pub resources: Resources, // demand, workers are counted as processors
+    pub constraints: Constraints,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -103,102 +218,320 @@ pub struct Task {
     stages: Vec<u64>,
     nodes: Vec<u64>,
     meta: Map,
+    priority: u32, // larger runs first
+    submitted: u64, // submission time supplied by the scheduler
+    pool: String,
+    history: Vec<TaskEvent>,
+    ended: u64, // only for terminal status
+    retry: RetryPolicy,
+    failures: Vec<StageFailure>,
+    blacklist: BTreeSet<u64>, // nodes the task keeps failing on, its occupations avoid them
+    commits: BTreeMap<u64, u64>, // stage id to the node whose copy of the stage is committed
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32, // per stage, including the first run
+    pub backoff: u64, // ms before the first retry, doubles on each retry
+    pub max_backoff: u64, // ms
+    pub blacklist_after: u32, // failures of the task on a node before avoiding it, 0 to disable
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            backoff: 1000,
+            max_backoff: 60 * 1000,
+            blacklist_after: 2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn no_retry() -> RetryPolicy {
+        RetryPolicy { max_attempts: 1, ..RetryPolicy::default() }
+    }
+    // time to wait after the stage failed `failures` times
+    pub fn backoff_for(&self, failures: u32) -> u64 {
+        let shift = cmp::min(failures.saturating_sub(1), 32);
+        cmp::min(self.backoff.saturating_mul(1 << shift), self.max_backoff)
+    }
+    // what to do after the `attempt`th failure of a stage at `time`
+    pub fn decide(&self, attempt: u32, time: u64, task_ended: bool) -> RetryDecision {
+        if attempt >= self.max_attempts || task_ended {
+            RetryDecision::GiveUp
+        } else {
+            RetryDecision::Retry {
+                attempt: attempt + 1,
+                not_before: time + self.backoff_for(attempt)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StageFailure {
+    pub time: u64,
+    pub stage_id: u64,
+    pub node_id: u64,
+    pub attempt: u32, // starts from 1
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RetryDecision {
+    // place the stage again with `retry_stage`, on a node not blacklisted by the task
+    Retry { attempt: u32, not_before: u64 },
+    GiveUp, // attempts exhausted, the task is failed
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TaskEvent {
+    pub time: u64,
+    pub stage_id: u64,
+    pub node_id: u64,
+    pub kind: TaskEventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TaskEventKind {
+    Preempted { by_task: u64 },
+    PreemptionReclaimed,
+    OccupationLost, // the node went offline, was deregistered or is draining
+    LeaseExpired,
+    StatusChanged { from: TaskStatus, to: TaskStatus },
+    StageFailed { attempt: u32 },
+    NodeBlacklisted,
+    Speculated,
+    PartitionCommitted,
+    Superseded, // another copy of the stage is committed
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManagerConfig {
+    pub preemption_grace: u64, // ms
+    pub lease_timeout: u64, // ms, 0 to disable leases
+    pub finished_task_limit: usize, // max number of ended tasks to keep
+    pub finished_task_retention: u64, // ms to keep ended tasks
+}
+
+impl Default for ManagerConfig {
+    fn default() -> ManagerConfig {
+        ManagerConfig {
+            preemption_grace: 30 * 1000,
+            lease_timeout: 60 * 1000,
+            finished_task_limit: 1000,
+            finished_task_retention: 60 * 60 * 1000,
+        }
+    }
+}
+
+// Position in the admission queue of a node
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AdmissionKey {
+    rev_priority: u32,
+    submitted: u64,
+    seq: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct ComputeNode {
     address: String,
-    memory: u64,
-    memory_remains: u64,
-    processors: u32,
-    processors_remains: u32,
     node_id: u64,
     online: bool,
     occupations: BTreeMap<u64, Occupation>,
+    queue: BTreeMap<AdmissionKey, u64>, // stage ids of scheduled occupations in admission order
+    resources: Resources, // capacity
+    resources_remains: Resources,
+    labels: Labels,
+    state: NodeState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    Active,
+    Draining, // being decommissioned, no new occupations
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum TaskStatus {
+    Pending,
+    Running,
+    Succeeding, // all work done, committing results
     Succeed,
     Failed,
     Canceled
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+impl TaskStatus {
+    pub fn is_terminal(&self) -> bool {
+        match *self {
+            TaskStatus::Succeed | TaskStatus::Failed | TaskStatus::Canceled => true,
+            _ => false
+        }
+    }
+    pub fn can_change_to(&self, to: &TaskStatus) -> bool {
+        match (self, to) {
+            (_, &TaskStatus::Pending) => false,
+            (&TaskStatus::Pending, &TaskStatus::Running) => true,
+            (&TaskStatus::Running, &TaskStatus::Succeeding) => true,
+            (&TaskStatus::Succeeding, &TaskStatus::Succeed) => true,
+            (from, to) => !from.is_terminal() && to.is_terminal()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum OccupationStatus {
     Running,
     Released,
     Scheduled, // default initial status
+    Preempting, // still holds resources until preemption is done or the grace period ends
+    Lost, // the node went offline or was deregistered, resources are gone with it
+    Expired, // lease was not renewed in time, resources are reclaimed
+    Failed, // reported by the executor, see `stage_failed`
+    Superseded, // another copy of the stage is committed, the executor should stop it
+    Withdrawn, // the task ended while the occupation was still queued, it never held resources
+}
+
+
+// Filters for `query_tasks`, `None` matches everything
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TaskFilter {
+    pub status: Option<TaskStatus>,
+    pub pool: Option<String>,
+}
+
+// Filters for `query_nodes`. Occupations and admission queues are left out unless asked for.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NodeFilter {
+    pub online_only: bool,
+    pub min_free_memory: u64,
+    pub min_free_processors: u32,
+    pub with_occupations: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeUtilization {
+    pub node_id: u64,
+    pub address: String,
+    pub online: bool,
+    pub state: NodeState,
+    pub memory: u64,
+    pub memory_used: u64,
+    pub processors: u32,
+    pub processors_used: u32,
 }
 
+// Totals only count online nodes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ClusterUtilization {
+    pub nodes: u64,
+    pub online_nodes: u64,
+    pub total_memory: u64,
+    pub used_memory: u64,
+    pub free_memory: u64,
+    pub total_processors: u64,
+    pub used_processors: u64,
+    pub free_processors: u64,
+    pub total_resources: Resources, // all dimensions, including memory and processors
+    pub free_resources: Resources,
+    pub occupations: BTreeMap<OccupationStatus, u64>,
+    pub per_node: Vec<NodeUtilization>,
+}
+
+// Snapshots start with the magic and a version byte. Snapshots without them are from before
+//  versioning, only nodes and tasks, and are upgraded on recover (see `legacy`).
+static SNAPSHOT_MAGIC: &'static [u8] = b"HMRMSNAP";
+static SNAPSHOT_VERSION: u8 = 6;
+
+type SnapshotPack = (
+    BTreeMap<u64, ComputeNode>, BTreeMap<u64, Task>, BTreeMap<String, Pool>,
+    u64, u64, ManagerConfig, VecDeque<u64>
+);
 
 pub struct ResourceManager {
-    compute_nodes: Arc<RwLock<BTreeMap<u64, ComputeNode>>>,
-    tasks: BTreeMap<u64, Task>,
+    state: Arc<RwLock<ManagerState>>,
     callback: Arc<CallbackTrigger>,
     sm_id: u64
 }
 
+// All data of the state machine. It is shared with membership callbacks, which are also applied
+//  in raft log order.
+struct ManagerState {
+    compute_nodes: BTreeMap<u64, ComputeNode>,
+    tasks: BTreeMap<u64, Task>,
+    pools: Pools,
+    admission_seq: u64,
+    clock: u64,
+    config: ManagerConfig,
+    finished: VecDeque<u64>, // ended task ids in the order they ended
+    // node ids by label, for topology domains of anti-affinities. Not in snapshots, rebuilt on
+    //  recover.
+    label_index: BTreeMap<(String, String), BTreeSet<u64>>,
+}
+
 impl StateMachineCmds for ResourceManager {
-    fn register_node(&mut self, mut node: ComputeNode) -> Result<(), RegisterNodeError> {
-        let mut nodes = self.compute_nodes.write();
-        if !nodes.contains_key(&node.node_id) {
-            nodes.insert(node.node_id, node);
-            return Ok(())
-        } else {
-            return Err(RegisterNodeError::NodeAlreadyExisted)
-        }
+    fn register_node(&mut self, node: ComputeNode) -> Result<(), RegisterNodeError> {
+        self.state.write().add_node(node)
     }
-    fn register_task(&mut self, mut task: Task, mut occupations: Vec<Occupation>)
+    fn register_task(&mut self, task: Task, occupations: Vec<Occupation>)
         -> Result<Vec<Occupation>, RegisterTaskError>
     {
-        task.nodes = occupations
-            .iter()
-            .map(|occ| occ.node_id)
-            .collect();
-        task.nodes.dedup();
-        let mut nodes = self.compute_nodes.write();
-        // check all occupation
-        for occ in &mut occupations {
-            if occ.status != OccupationStatus::Scheduled {
-                return Err(RegisterTaskError::OccupationStatusNotScheduled)
-            }
-            if let Some(mut node) = nodes.get_mut(&occ.node_id) {
-                if can_afford_occupation(
-                    node.memory_remains, occ.memory,
-                    node.processors_remains, occ.workers
-                ) {
-                    acquire_res!(self, node, occ);
-                }
-            } else {
-                return Err(RegisterTaskError::NodeIdNotFound(occ.node_id))
-            }
-
-        }
-        let mut running_occupations = Vec::new();
-        // append occupations to their nodes and also check it's status
-        for occ in occupations {
-            if occ.status == OccupationStatus::Running {
-                running_occupations.push(occ.clone());
-            }
-            nodes.get_mut(&occ.node_id).unwrap().occupations.insert(occ.stage_id, occ);
-        }
-        // append task
-        self.tasks.insert(task.id, task);
-        return Ok(running_occupations)
+        self.state.write().register_task(task, occupations, &self.callback)
     }
     fn degegister_node(&mut self, node_id: u64) -> Result<(), String> {
-        let mut nodes = self.compute_nodes.write();
-        nodes.remove(&node_id);
+        self.deregister_node(node_id)
+    }
+    fn deregister_node(&mut self, node_id: u64) -> Result<(), String> {
+        self.state.write().remove_node(node_id, &self.callback);
         return Ok(());
     }
+    fn update_node_labels(&mut self, node_id: u64, labels: Labels) -> Result<(), String> {
+        let mut state = self.state.write();
+        state.set_labels(node_id, labels)?;
+        // queued occupations may match the node now
+        for occ in state.admit(node_id) {
+            self.callback.occupation_changed(&occ);
+        }
+        self.callback.member_changed(&state.compute_nodes[&node_id]);
+        Ok(())
+    }
+    fn drain_node(&mut self, node_id: u64) -> Result<(), String> {
+        let mut state = self.state.write();
+        state.drain_node(node_id, &self.callback)?;
+        self.callback.member_changed(&state.compute_nodes[&node_id]);
+        Ok(())
+    }
+    fn undrain_node(&mut self, node_id: u64) -> Result<(), String> {
+        let mut state = self.state.write();
+        match state.compute_nodes.get_mut(&node_id) {
+            Some(node) => node.state = NodeState::Active,
+            None => return Err(format!("Cannot find node with id {}", node_id))
+        }
+        self.callback.member_changed(&state.compute_nodes[&node_id]);
+        Ok(())
+    }
+    fn lose_node_occupations(&mut self, node_id: u64) -> Result<(), String> {
+        let mut state = self.state.write();
+        if !state.compute_nodes.contains_key(&node_id) {
+            return Err(format!("Cannot find node with id {}", node_id))
+        }
+        state.lose_node(node_id, &self.callback);
+        Ok(())
+    }
     fn task_ended(&mut self, task_id: u64, status: TaskStatus) -> Result<(), String> {
-        if let Some(task) = self.tasks.get_mut(&task_id) {
-            task.status = status;
-            Ok(())
-        } else {
-            Err(format!("Cannot find task with id {}", task_id))
+        if !status.is_terminal() {
+            return Err(format!("Task cannot end with status {:?}", status))
+        }
+        self.state.write().end_task(task_id, status, &self.callback)
+    }
+    fn update_task_status(&mut self, task_id: u64, status: TaskStatus) -> Result<(), String> {
+        if status.is_terminal() {
+            return Err(format!("Use task_ended to end task with status {:?}", status))
         }
+        self.state.write().change_task_status(task_id, status)
     }
     fn try_acquire_node_resource(
         &mut self,
@@ -206,25 +539,7 @@ impl StateMachineCmds for ResourceManager {
         stage_id: u64,
         node_id: u64
     ) -> Result<bool, ChangeOccupationStatusError> {
-        let mut nodes = self.compute_nodes.write();
-        if let Some(mut node) = nodes.get_mut(&node_id) {
-            if let Some(mut occ) = node.occupations.get_mut(&stage_id) {
-                if occ.task_id == task_id {
-                    if can_afford_occupation(
-                        node.memory_remains, occ.memory,
-                        node.processors_remains, occ.workers
-                    ) {
-                        acquire_res!(self, node, occ);
-                        return Ok(true)
-                    } else {
-                        return Ok(false)
-                    }
-                } else {
-                    return Err(ChangeOccupationStatusError::OccupationTaskNotMatch)
-                }
-            }
-        }
-        return Err(ChangeOccupationStatusError::CannotFindOccupation);
+        self.state.write().try_acquire_node_resource(task_id, stage_id, node_id, &self.callback)
     }
     fn release_occupation(
         &mut self,
@@ -232,32 +547,87 @@ impl StateMachineCmds for ResourceManager {
         stage_id: u64,
         node_id: u64
     ) -> Result<bool, ChangeOccupationStatusError> {
-        let mut nodes = self.compute_nodes.write();
-        if let Some(mut node) = nodes.get_mut(&node_id) {
-            if let Some(mut occ) = node.occupations.get_mut(&stage_id) {
-                if occ.task_id == task_id {
-                    if occ.status == OccupationStatus::Running {
-                        occ.status = OccupationStatus::Released;
-                        node.memory_remains += occ.memory;
-                        node.processors_remains += occ.workers;
-                        self.notify_occupation_changes(occ);
-                        return Ok(true)
-                    } else {
-                        return Ok(false)
-                    }
-                } else {
-                    return Err(ChangeOccupationStatusError::OccupationTaskNotMatch)
-                }
-            }
-        }
-        return Err(ChangeOccupationStatusError::CannotFindOccupation);
+        self.state.write().release_occupation(task_id, stage_id, node_id, &self.callback)
+    }
+    fn stage_failed(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64,
+        reason: String
+    ) -> Result<RetryDecision, ChangeOccupationStatusError> {
+        self.state.write().stage_failed(task_id, stage_id, node_id, reason, &self.callback)
+    }
+    fn retry_stage(&mut self, occupation: Occupation) -> Result<bool, RegisterTaskError> {
+        self.state.write().retry_stage(occupation, &self.callback)
+    }
+    fn speculate_stage(&mut self, occupation: Occupation) -> Result<bool, RegisterTaskError> {
+        self.state.write().speculate_stage(occupation, &self.callback)
+    }
+    fn commit_partition(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64
+    ) -> Result<bool, ChangeOccupationStatusError> {
+        self.state.write().commit_partition(task_id, stage_id, node_id, &self.callback)
+    }
+    fn preemption_done(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64
+    ) -> Result<bool, ChangeOccupationStatusError> {
+        self.state.write().preemption_done(task_id, stage_id, node_id, &self.callback)
+    }
+    fn heartbeat_occupation(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64
+    ) -> Result<bool, ChangeOccupationStatusError> {
+        self.state.write().heartbeat_occupation(task_id, stage_id, node_id)
+    }
+    fn tick(&mut self, now: u64) -> Result<(), ()> {
+        self.state.write().tick(now, &self.callback);
+        Ok(())
+    }
+    fn set_config(&mut self, config: ManagerConfig) -> Result<(), ()> {
+        self.state.write().config = config;
+        Ok(())
+    }
+    fn create_pool(&mut self, pool: Pool) -> Result<(), PoolError> {
+        self.state.write().pools.create(pool)
+    }
+    fn update_pool(&mut self, pool: Pool) -> Result<(), PoolError> {
+        self.state.write().pools.update(pool)
     }
     fn tasks(&self) -> Result<Vec<Task>, ()> {
-        Ok(self.tasks.values().cloned().collect())
+        Ok(self.state.read().tasks.values().cloned().collect())
     }
     fn nodes(&self) -> Result<Vec<ComputeNode>, ()> {
-        let mut nodes = self.compute_nodes.read();
-        Ok(nodes.values().cloned().collect())
+        Ok(self.state.read().compute_nodes.values().cloned().collect())
+    }
+    fn pools(&self) -> Result<Vec<Pool>, ()> {
+        Ok(self.state.read().pools.list())
+    }
+    fn config(&self) -> Result<ManagerConfig, ()> {
+        Ok(self.state.read().config.clone())
+    }
+    fn task(&self, task_id: u64) -> Result<Option<Task>, ()> {
+        Ok(self.state.read().tasks.get(&task_id).cloned())
+    }
+    fn node(&self, node_id: u64) -> Result<Option<ComputeNode>, ()> {
+        Ok(self.state.read().compute_nodes.get(&node_id).cloned())
+    }
+    fn query_tasks(&self, filter: TaskFilter) -> Result<Vec<Task>, ()> {
+        Ok(self.state.read().query_tasks(&filter))
+    }
+    fn query_nodes(&self, filter: NodeFilter) -> Result<Vec<ComputeNode>, ()> {
+        Ok(self.state.read().query_nodes(&filter))
+    }
+    fn utilization(&self) -> Result<ClusterUtilization, ()> {
+        Ok(self.state.read().utilization())
     }
 }
 
@@ -267,117 +637,2313 @@ impl StateMachineCtl for ResourceManager {
         return self.sm_id;
     }
     fn snapshot(&self) -> Option<Vec<u8>> {
-        let nodes = self.compute_nodes.read();
-        Some(bincode::serialize(&(&nodes.clone(), &self.tasks)))
+        Some(self.state.read().snapshot())
     }
     fn recover(&mut self, data: Vec<u8>) {
-        let pack:(BTreeMap<u64, ComputeNode>, BTreeMap<u64, Task>) = bincode::deserialize(&data);
-        self.compute_nodes = Arc::new(RwLock::new(pack.0));
-        self.tasks = pack.1;
+        if let Some(state) = ManagerState::recover(&data) {
+            *self.state.write() = state;
+        }
     }
 }
 
 impl ResourceManager {
     pub fn new(raft: Arc<RaftService>, group_id: u64, membership_cb: &SMCallback) -> ResourceManager {
-        let nodes = Arc::new(RwLock::new(BTreeMap::<u64, ComputeNode>::new()));
-        let callback = Arc::new(CallbackTrigger {
+        let state = Arc::new(RwLock::new(ManagerState::new()));
+        let callback: Arc<CallbackTrigger> = Arc::new(SMCallbackTrigger {
             sm_callback: SMCallback::new(DEFAULT_SERVICE_ID, raft)
         });
         let manager = ResourceManager{
-            compute_nodes: nodes.clone(),
-            tasks: BTreeMap::new(),
+            state: state.clone(),
             sm_id: DEFAULT_SERVICE_ID,
             callback: callback.clone(),
         };
-        let nr1 = nodes.clone();
+        let st1 = state.clone();
         let cb1 = callback.clone();
         membership_cb.internal_subscribe(
             &on_group_member_offline::new(&group_id),
             move |changes| {
-                mark_member(false, changes, &nr1, &cb1)
+                mark_member(false, changes, &st1, &cb1)
         });
-        let nr2 = nodes.clone();
+        let st2 = state.clone();
         let cb2 = callback.clone();
         membership_cb.internal_subscribe(
             &on_group_member_online::new(&group_id),
             move |changes: &Result<(ClientMember, u64), ()>| {
-                mark_member(true, changes, &nr2, &cb2)
+                mark_member(true, changes, &st2, &cb2)
             });
-        let nr3 = nodes.clone();
+        let st3 = state.clone();
         let cb3 = callback.clone();
         membership_cb.internal_subscribe(
             &on_group_member_joined::new(&group_id),
             move |changes: &Result<(ClientMember, u64), ()>| {
-                mark_member(true, changes, &nr3, &cb3)
+                mark_member(true, changes, &st3, &cb3)
             });
-        let nr4 = nodes.clone();
+        let st4 = state.clone();
         let cb4 = callback.clone();
         membership_cb.internal_subscribe(
             &on_group_member_left::new(&group_id),
             move |changes: &Result<(ClientMember, u64), ()>| {
-                mark_member(false, changes, &nr4, &cb4)
+                mark_member(false, changes, &st4, &cb4)
             });
         return manager;
     }
-    fn notify_occupation_changes(&self, occ: &Occupation) {
-        self.callback.sm_callback.notify(
-            &commands::on_occupation_changed::new(),
-            Ok(occ.clone())
-        );
-        if occ.status == OccupationStatus::Released {
-            self.callback.sm_callback.notify(
-                &commands::on_resource_available::new(),
-                Ok(occ.clone())
-            );
-        }
-    }
 }
 
-impl ComputeNode {
-    pub fn new<'a>(
-        address: &'a str,
-        node_id: u64,
-        memory: u64,
-        processors: u32,
-    ) -> ComputeNode {
-        ComputeNode {
-            address: address.to_string(),
-            memory,
-            memory_remains: memory,
-            processors,
-            processors_remains: processors,
-            node_id,
-            online: true,
-            occupations: BTreeMap::new(),
+impl ManagerState {
+    fn new() -> ManagerState {
+        ManagerState {
+            compute_nodes: BTreeMap::new(),
+            tasks: BTreeMap::new(),
+            pools: Pools::new(),
+            admission_seq: 0,
+            clock: 0,
+            config: ManagerConfig::default(),
+            finished: VecDeque::new(),
+            label_index: BTreeMap::new(),
         }
     }
-}
-
-fn mark_member(
-    online: bool,
-    changes: &Result<(ClientMember, u64), ()>,
-    nodes: &Arc<RwLock<BTreeMap<u64, ComputeNode>>>,
-    callback: &Arc<CallbackTrigger>
-) {
-    if let &Ok((ref member, _)) = changes {
-        let mut nr = nodes.write();
-        if let Some(ref mut node) = nr.get_mut(&member.id) {
-            node.online = online;
-            let node_for_update = node.clone();
-            callback.sm_callback.notify(
-                &commands::on_member_changed::new(), Ok(node_for_update)
-            );
+    fn snapshot(&self) -> Vec<u8> {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.push(SNAPSHOT_VERSION);
+        data.append(&mut bincode::serialize(&(
+            &self.compute_nodes, &self.tasks, &self.pools.pools,
+            self.admission_seq, self.clock, &self.config, &self.finished
+        )));
+        data
+    }
+    // `None` if the snapshot cannot be read, the state is left as is
+    fn recover(data: &[u8]) -> Option<ManagerState> {
+        if !data.starts_with(SNAPSHOT_MAGIC) {
+            return Some(legacy::upgrade(bincode::deserialize(data)));
+        }
+        match data.get(SNAPSHOT_MAGIC.len()) {
+            Some(&version) if version == SNAPSHOT_VERSION => {
+                let pack: SnapshotPack = bincode::deserialize(&data[SNAPSHOT_MAGIC.len() + 1..]);
+                Some(ManagerState::from_pack(pack))
+            },
+            version => {
+                error!("Cannot recover resource manager from snapshot of version {:?}, \
+                        supported version is {}", version, SNAPSHOT_VERSION);
+                None
+            }
         }
     }
-}
-
-fn can_afford_occupation(
-    node_mem: u64, occ_mem: u64,
-    node_proc: u32, occ_proc: u32
-) -> bool {
-    node_mem >= occ_mem && node_proc >= occ_proc
-}
-
-struct CallbackTrigger {
-    sm_callback: SMCallback
-}
\ No newline at end of file
+    fn from_pack(pack: SnapshotPack) -> ManagerState {
+        let (compute_nodes, tasks, pools, admission_seq, clock, config, finished) = pack;
+        let mut state = ManagerState {
+            tasks, admission_seq, clock, config, finished,
+            compute_nodes: BTreeMap::new(),
+            pools: Pools { pools },
+            label_index: BTreeMap::new(),
+        };
+        for (_, node) in compute_nodes {
+            state.add_node(node).unwrap();
+        }
+        state
+    }
+    fn add_node(&mut self, node: ComputeNode) -> Result<(), RegisterNodeError> {
+        if self.compute_nodes.contains_key(&node.node_id) {
+            return Err(RegisterNodeError::NodeAlreadyExisted)
+        }
+        self.index_labels(node.node_id, &node.labels, true);
+        self.compute_nodes.insert(node.node_id, node);
+        Ok(())
+    }
+    fn set_labels(&mut self, node_id: u64, labels: Labels) -> Result<(), String> {
+        let old = match self.compute_nodes.get_mut(&node_id) {
+            Some(node) => ::std::mem::replace(&mut node.labels, labels),
+            None => return Err(format!("Cannot find node with id {}", node_id))
+        };
+        self.index_labels(node_id, &old, false);
+        let labels = self.compute_nodes[&node_id].labels.clone();
+        self.index_labels(node_id, &labels, true);
+        Ok(())
+    }
+    fn index_labels(&mut self, node_id: u64, labels: &Labels, add: bool) {
+        for (key, value) in labels {
+            let entry = (key.clone(), value.clone());
+            if add {
+                self.label_index.entry(entry).or_insert_with(BTreeSet::new).insert(node_id);
+            } else {
+                let empty = match self.label_index.get_mut(&entry) {
+                    Some(nodes) => {
+                        nodes.remove(&node_id);
+                        nodes.is_empty()
+                    },
+                    None => false
+                };
+                if empty {
+                    self.label_index.remove(&entry);
+                }
+            }
+        }
+    }
+    fn register_task(
+        &mut self,
+        mut task: Task,
+        occupations: Vec<Occupation>,
+        callback: &CallbackTrigger
+    ) -> Result<Vec<Occupation>, RegisterTaskError> {
+        task.nodes = occupations
+            .iter()
+            .map(|occ| occ.node_id)
+            .collect();
+        task.nodes.sort();
+        task.nodes.dedup();
+        if !self.pools.contains(&task.pool) {
+            return Err(RegisterTaskError::PoolNotFound(task.pool.clone()))
+        }
+        let total_memory: u64 = occupations.iter().map(|occ| occ.memory()).sum();
+        let total_workers: u32 = occupations.iter().map(|occ| occ.workers()).sum();
+        if !self.pools.quota_covers(&task.pool, total_memory, total_workers) {
+            return Err(RegisterTaskError::QuotaExceeded)
+        }
+        // check all occupation
+        for occ in &occupations {
+            self.check_placement(occ, &occupations)?;
+        }
+        // occupations are kept by stage id on each node, an active one is never replaced
+        for (i, occ) in occupations.iter().enumerate() {
+            let placed = self.compute_nodes[&occ.node_id].occupations.get(&occ.stage_id)
+                .map(|existing| existing.is_active())
+                .unwrap_or(false);
+            let repeated = occupations[..i].iter()
+                .any(|other| other.node_id == occ.node_id && other.stage_id == occ.stage_id);
+            if placed || repeated {
+                return Err(RegisterTaskError::StageAlreadyPlaced(occ.stage_id))
+            }
+        }
+        // append occupations to their nodes and queue them for admission
+        for mut occ in occupations {
+            occ.priority = task.priority;
+            occ.submitted = task.submitted;
+            occ.pool = task.pool.clone();
+            self.enqueue(occ);
+        }
+        let mut running_occupations = Vec::new();
+        let mut preemptions = Vec::new();
+        for node_id in &task.nodes {
+            for occ in self.admit(*node_id) {
+                callback.occupation_changed(&occ);
+                if occ.task_id == task.id {
+                    running_occupations.push(occ);
+                }
+            }
+            preemptions.append(&mut self.preempt(*node_id));
+        }
+        // append task
+        if !running_occupations.is_empty() {
+            task.status = TaskStatus::Running;
+        }
+        self.tasks.insert(task.id, task);
+        self.notify_preemptions(preemptions, callback);
+        return Ok(running_occupations)
+    }
+    fn check_placement(&self, occ: &Occupation, pending: &[Occupation]) -> Result<(), RegisterTaskError> {
+        if occ.status != OccupationStatus::Scheduled {
+            return Err(RegisterTaskError::OccupationStatusNotScheduled)
+        }
+        match self.compute_nodes.get(&occ.node_id) {
+            Some(node) if !node.online => Err(RegisterTaskError::NodeOffline(occ.node_id)),
+            Some(node) if node.state == NodeState::Draining => {
+                Err(RegisterTaskError::NodeDraining(occ.node_id))
+            },
+            Some(node) => {
+                if !occ.constraints.matches_required(&node.labels) ||
+                    self.anti_affinity_violated(occ, pending, true) {
+                    return Err(RegisterTaskError::ConstraintsNotSatisfied(occ.stage_id))
+                }
+                Ok(())
+            },
+            None => Err(RegisterTaskError::NodeIdNotFound(occ.node_id))
+        }
+    }
+    // the node of the occupation must exist
+    fn enqueue(&mut self, occ: Occupation) {
+        let key = AdmissionKey {
+            rev_priority: u32::max_value() - occ.priority,
+            submitted: occ.submitted,
+            seq: self.admission_seq
+        };
+        self.admission_seq += 1;
+        let node = self.compute_nodes.get_mut(&occ.node_id).unwrap();
+        node.queue.insert(key, occ.stage_id);
+        node.occupations.insert(occ.stage_id, occ);
+    }
+    // Free the occupation of the failed attempt and decide whether the stage can run again.
+    //  The node is blacklisted for the task after repeated failures on it. Reporting the same
+    //  failure again returns the first decision without counting another attempt.
+    fn stage_failed(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64,
+        reason: String,
+        callback: &CallbackTrigger
+    ) -> Result<RetryDecision, ChangeOccupationStatusError> {
+        if !self.tasks.contains_key(&task_id) {
+            return Err(ChangeOccupationStatusError::CannotFindOccupation)
+        }
+        let failed = match self.compute_nodes.get_mut(&node_id) {
+            Some(node) => match node.occupations.get_mut(&stage_id) {
+                Some(occ) => {
+                    if occ.task_id != task_id {
+                        return Err(ChangeOccupationStatusError::OccupationTaskNotMatch)
+                    }
+                    let status = occ.status;
+                    match status {
+                        OccupationStatus::Running | OccupationStatus::Preempting => {
+                            release_res!(self.pools, node, occ);
+                        },
+                        // reported again, answered below with what was decided the first time
+                        OccupationStatus::Failed => {},
+                        status => return Err(ChangeOccupationStatusError::OccupationNotRunning(status))
+                    }
+                    if occ.status == OccupationStatus::Failed {
+                        None
+                    } else {
+                        occ.status = OccupationStatus::Failed;
+                        Some(occ.clone())
+                    }
+                },
+                None => return Err(ChangeOccupationStatusError::CannotFindOccupation)
+            },
+            None => return Err(ChangeOccupationStatusError::CannotFindOccupation)
+        };
+        let failed = match failed {
+            Some(occ) => occ,
+            None => {
+                let task = &self.tasks[&task_id];
+                return match task.failures.iter()
+                    .rev()
+                    .find(|f| f.stage_id == stage_id && f.node_id == node_id) {
+                    Some(f) => Ok(task.retry.decide(
+                        f.attempt, f.time, task.status.is_terminal() && task.ended <= f.time
+                    )),
+                    // failed without a report, e.g. from an older snapshot
+                    None => Err(ChangeOccupationStatusError::OccupationNotRunning(OccupationStatus::Failed))
+                }
+            }
+        };
+        callback.occupation_changed(&failed);
+        let clock = self.clock;
+        let (attempt, decision, blacklisted) = {
+            let task = self.tasks.get_mut(&task_id).unwrap();
+            let attempt = task.failures.iter().filter(|f| f.stage_id == stage_id).count() as u32 + 1;
+            task.failures.push(StageFailure { time: clock, stage_id, node_id, attempt, reason });
+            let on_node = task.failures.iter().filter(|f| f.node_id == node_id).count() as u32;
+            let blacklisted = task.retry.blacklist_after > 0 &&
+                on_node >= task.retry.blacklist_after &&
+                task.blacklist.insert(node_id);
+            let decision = task.retry.decide(attempt, clock, task.status.is_terminal());
+            (attempt, decision, blacklisted)
+        };
+        self.record_event(task_id, stage_id, node_id, TaskEventKind::StageFailed { attempt });
+        if blacklisted {
+            self.record_event(task_id, 0, node_id, TaskEventKind::NodeBlacklisted);
+            // queued occupations of the task on the node have to be placed elsewhere
+            let lost: Vec<Occupation> = match self.compute_nodes.get_mut(&node_id) {
+                Some(node) => node.occupations.values_mut()
+                    .filter(|occ| occ.task_id == task_id && occ.status == OccupationStatus::Scheduled)
+                    .map(|occ| {
+                        occ.status = OccupationStatus::Lost;
+                        occ.clone()
+                    })
+                    .collect(),
+                None => Vec::new()
+            };
+            for occ in lost {
+                self.record_event(task_id, occ.stage_id, node_id, TaskEventKind::OccupationLost);
+                callback.occupation_changed(&occ);
+            }
+        }
+        for occ in self.admit(node_id) {
+            callback.occupation_changed(&occ);
+        }
+        if decision == RetryDecision::GiveUp {
+            // may have ended already
+            let _ = self.end_task(task_id, TaskStatus::Failed, callback);
+        }
+        Ok(decision)
+    }
+    // Only the first copy of a stage to commit has its output kept, others are superseded.
+    //  Returns whether the output of this copy is the committed one, it is the same answer on every
+    //  call so executors can retry commits safely.
+    fn commit_partition(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64,
+        callback: &CallbackTrigger
+    ) -> Result<bool, ChangeOccupationStatusError> {
+        match self.compute_nodes.get(&node_id).and_then(|node| node.occupations.get(&stage_id)) {
+            Some(occ) if occ.task_id != task_id => {
+                return Err(ChangeOccupationStatusError::OccupationTaskNotMatch)
+            },
+            Some(_) => {},
+            None => return Err(ChangeOccupationStatusError::CannotFindOccupation)
+        }
+        let nodes = match self.tasks.get_mut(&task_id) {
+            Some(task) => {
+                if let Some(committed) = task.commits.get(&stage_id) {
+                    return Ok(*committed == node_id)
+                }
+                if task.status.is_terminal() {
+                    return Ok(false)
+                }
+                task.commits.insert(stage_id, node_id);
+                task.nodes.clone()
+            },
+            None => return Err(ChangeOccupationStatusError::CannotFindOccupation)
+        };
+        self.record_event(task_id, stage_id, node_id, TaskEventKind::PartitionCommitted);
+        for other in nodes.into_iter().filter(|id| *id != node_id) {
+            let superseded = match self.compute_nodes.get_mut(&other) {
+                Some(node) => match node.occupations.get_mut(&stage_id) {
+                    Some(occ) => {
+                        if occ.task_id != task_id || !occ.is_active() {
+                            continue;
+                        }
+                        if occ.status != OccupationStatus::Scheduled {
+                            release_res!(self.pools, node, occ);
+                        }
+                        occ.status = OccupationStatus::Superseded;
+                        occ.clone()
+                    },
+                    None => continue
+                },
+                None => continue
+            };
+            self.record_event(task_id, stage_id, other, TaskEventKind::Superseded);
+            callback.occupation_changed(&superseded);
+            for occ in self.admit(other) {
+                callback.occupation_changed(&occ);
+            }
+        }
+        Ok(true)
+    }
+    // Place a failed stage again, after its backoff
+    fn retry_stage(&mut self, occ: Occupation, callback: &CallbackTrigger)
+        -> Result<bool, RegisterTaskError>
+    {
+        let clock = self.clock;
+        let lost = self.stage_lost(occ.task_id, occ.stage_id);
+        match self.tasks.get(&occ.task_id) {
+            Some(task) => {
+                if task.status.is_terminal() {
+                    return Err(RegisterTaskError::TaskEnded(task.id))
+                }
+                if task.blacklist.contains(&occ.node_id) {
+                    return Err(RegisterTaskError::NodeBlacklisted(occ.node_id))
+                }
+                // lost with the node is not the fault of the stage, it is neither counted as
+                //  a failure nor delayed
+                if !lost {
+                    let failures = task.failures.iter()
+                        .filter(|f| f.stage_id == occ.stage_id)
+                        .count() as u32;
+                    let last = task.failures.iter().rev().find(|f| f.stage_id == occ.stage_id);
+                    let last = match last {
+                        Some(failure) => failure.time,
+                        None => return Err(RegisterTaskError::StageNotFailed(occ.stage_id))
+                    };
+                    if failures >= task.retry.max_attempts {
+                        return Err(RegisterTaskError::AttemptsExhausted(occ.stage_id))
+                    }
+                    if clock < last + task.retry.backoff_for(failures) {
+                        return Err(RegisterTaskError::BackoffNotElapsed(occ.stage_id))
+                    }
+                }
+            },
+            None => return Err(RegisterTaskError::TaskNotFound(occ.task_id))
+        }
+        self.place_copy(occ, callback)
+    }
+    // Extra copy of a running stage on another node, for stragglers. The first copy committed
+    //  with `commit_partition` wins, the other is superseded.
+    fn speculate_stage(&mut self, occ: Occupation, callback: &CallbackTrigger)
+        -> Result<bool, RegisterTaskError>
+    {
+        match self.tasks.get(&occ.task_id) {
+            Some(task) => {
+                if task.status.is_terminal() {
+                    return Err(RegisterTaskError::TaskEnded(task.id))
+                }
+                if task.blacklist.contains(&occ.node_id) {
+                    return Err(RegisterTaskError::NodeBlacklisted(occ.node_id))
+                }
+                if task.commits.contains_key(&occ.stage_id) {
+                    return Err(RegisterTaskError::StageCommitted(occ.stage_id))
+                }
+            },
+            None => return Err(RegisterTaskError::TaskNotFound(occ.task_id))
+        }
+        let (running, copies) = {
+            let copies = self.stage_copies(occ.task_id, occ.stage_id);
+            (copies.iter().any(|copy| copy.status == OccupationStatus::Running), copies.len())
+        };
+        if !running {
+            return Err(RegisterTaskError::StageNotRunning(occ.stage_id))
+        }
+        // one speculative copy at most
+        if copies > 1 {
+            return Err(RegisterTaskError::SpeculationLimit(occ.stage_id))
+        }
+        let task_id = occ.task_id;
+        let stage_id = occ.stage_id;
+        let node_id = occ.node_id;
+        let running = self.place_copy(occ, callback)?;
+        self.record_event(task_id, stage_id, node_id, TaskEventKind::Speculated);
+        Ok(running)
+    }
+    // Occupations of the stage holding or waiting for resources, on all nodes of the task
+    fn stage_copies(&self, task_id: u64, stage_id: u64) -> Vec<&Occupation> {
+        let nodes = match self.tasks.get(&task_id) {
+            Some(task) => &task.nodes,
+            None => return Vec::new()
+        };
+        nodes.iter()
+            .filter_map(|node_id| self.compute_nodes.get(node_id))
+            .filter_map(|node| node.occupations.get(&stage_id))
+            .filter(|occ| occ.task_id == task_id && occ.is_active())
+            .collect()
+    }
+    // The stage is not committed and all of its copies are gone with their nodes or expired
+    fn stage_lost(&self, task_id: u64, stage_id: u64) -> bool {
+        let task = match self.tasks.get(&task_id) {
+            Some(task) => task,
+            None => return false
+        };
+        if task.commits.contains_key(&stage_id) {
+            return false;
+        }
+        let copies: Vec<OccupationStatus> = task.nodes.iter()
+            .filter_map(|node_id| self.compute_nodes.get(node_id))
+            .filter_map(|node| node.occupations.get(&stage_id))
+            .filter(|occ| occ.task_id == task_id)
+            .map(|occ| occ.status)
+            .collect();
+        !copies.is_empty() && copies.iter().all(|status| {
+            *status == OccupationStatus::Lost || *status == OccupationStatus::Expired
+        })
+    }
+    // Queue another occupation for a stage of a registered task
+    fn place_copy(&mut self, mut occ: Occupation, callback: &CallbackTrigger)
+        -> Result<bool, RegisterTaskError>
+    {
+        let (priority, submitted, pool) = {
+            let task = &self.tasks[&occ.task_id];
+            (task.priority, task.submitted, task.pool.clone())
+        };
+        if !self.pools.quota_covers(&pool, occ.memory(), occ.workers()) {
+            return Err(RegisterTaskError::QuotaExceeded)
+        }
+        self.check_placement(&occ, &[])?;
+        let placed = self.compute_nodes[&occ.node_id].occupations.get(&occ.stage_id)
+            .map(|existing| existing.is_active())
+            .unwrap_or(false);
+        if placed {
+            return Err(RegisterTaskError::StageAlreadyPlaced(occ.stage_id))
+        }
+        let task_id = occ.task_id;
+        let stage_id = occ.stage_id;
+        let node_id = occ.node_id;
+        occ.priority = priority;
+        occ.submitted = submitted;
+        occ.pool = pool;
+        self.enqueue(occ);
+        {
+            let task = self.tasks.get_mut(&task_id).unwrap();
+            if !task.nodes.contains(&node_id) {
+                task.nodes.push(node_id);
+                task.nodes.sort();
+            }
+        }
+        let mut running = false;
+        for occ in self.admit(node_id) {
+            callback.occupation_changed(&occ);
+            if occ.task_id == task_id && occ.stage_id == stage_id {
+                running = true;
+            }
+        }
+        let preemptions = self.preempt(node_id);
+        self.notify_preemptions(preemptions, callback);
+        Ok(running)
+    }
+    fn try_acquire_node_resource(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64,
+        callback: &CallbackTrigger
+    ) -> Result<bool, ChangeOccupationStatusError> {
+        let clock = self.clock;
+        let blocked = self.blocked_on(node_id);
+        let acquired = match self.compute_nodes.get_mut(&node_id) {
+            Some(node) => {
+                let remains = node.remains().clone();
+                let accepts = node.accepts();
+                match node.occupations.get_mut(&stage_id) {
+                    Some(occ) => {
+                        if occ.task_id != task_id {
+                            return Err(ChangeOccupationStatusError::OccupationTaskNotMatch)
+                        }
+                        if accepts &&
+                            occ.status == OccupationStatus::Scheduled &&
+                            !blocked.contains(&occ.stage_id) &&
+                            remains.covers(&occ.resources) &&
+                            self.pools.allows(&occ.pool, occ.memory(), occ.workers()) {
+                            acquire_res!(self.pools, clock, node, occ);
+                            callback.occupation_changed(occ);
+                            true
+                        } else {
+                            false
+                        }
+                    },
+                    None => return Err(ChangeOccupationStatusError::CannotFindOccupation)
+                }
+            },
+            None => return Err(ChangeOccupationStatusError::CannotFindOccupation)
+        };
+        if acquired {
+            let _ = self.change_task_status(task_id, TaskStatus::Running);
+        }
+        return Ok(acquired);
+    }
+    fn release_occupation(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64,
+        callback: &CallbackTrigger
+    ) -> Result<bool, ChangeOccupationStatusError> {
+        if let Some(mut node) = self.compute_nodes.get_mut(&node_id) {
+            if let Some(mut occ) = node.occupations.get_mut(&stage_id) {
+                if occ.task_id != task_id {
+                    return Err(ChangeOccupationStatusError::OccupationTaskNotMatch)
+                }
+                if occ.status == OccupationStatus::Running ||
+                    occ.status == OccupationStatus::Preempting {
+                    occ.status = OccupationStatus::Released;
+                    release_res!(self.pools, node, occ);
+                    callback.occupation_changed(occ);
+                } else {
+                    return Ok(false)
+                }
+            } else {
+                return Err(ChangeOccupationStatusError::CannotFindOccupation)
+            }
+        } else {
+            return Err(ChangeOccupationStatusError::CannotFindOccupation);
+        }
+        // freed capacity goes to queued occupations
+        for occ in self.admit(node_id) {
+            callback.occupation_changed(&occ);
+        }
+        return Ok(true)
+    }
+    fn preemption_done(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64,
+        callback: &CallbackTrigger
+    ) -> Result<bool, ChangeOccupationStatusError> {
+        match self.compute_nodes.get(&node_id).and_then(|node| node.occupations.get(&stage_id)) {
+            Some(occ) if occ.task_id != task_id => {
+                return Err(ChangeOccupationStatusError::OccupationTaskNotMatch)
+            },
+            Some(occ) if occ.status != OccupationStatus::Preempting => return Ok(false),
+            Some(_) => {},
+            None => return Err(ChangeOccupationStatusError::CannotFindOccupation)
+        }
+        self.record_event(task_id, stage_id, node_id, TaskEventKind::PreemptionReclaimed);
+        self.release_occupation(task_id, stage_id, node_id, callback)
+    }
+    fn heartbeat_occupation(
+        &mut self,
+        task_id: u64,
+        stage_id: u64,
+        node_id: u64
+    ) -> Result<bool, ChangeOccupationStatusError> {
+        let clock = self.clock;
+        match self.compute_nodes.get_mut(&node_id).and_then(|node| node.occupations.get_mut(&stage_id)) {
+            Some(ref occ) if occ.task_id != task_id => {
+                Err(ChangeOccupationStatusError::OccupationTaskNotMatch)
+            },
+            Some(occ) => {
+                if occ.status == OccupationStatus::Running ||
+                    occ.status == OccupationStatus::Preempting {
+                    occ.last_updated = clock;
+                    Ok(true)
+                } else {
+                    // the lease is already gone, executor should stop the work
+                    Ok(false)
+                }
+            },
+            None => Err(ChangeOccupationStatusError::CannotFindOccupation)
+        }
+    }
+    fn tick(&mut self, now: u64, callback: &CallbackTrigger) {
+        if self.clock == 0 {
+            // occupations acquired before the first tick have no meaningful lease time
+            for node in self.compute_nodes.values_mut() {
+                for occ in node.occupations.values_mut() {
+                    occ.last_updated = now;
+                }
+            }
+        }
+        if now > self.clock {
+            self.clock = now;
+        }
+        // reclaim preemptions out of grace period
+        let clock = self.clock;
+        let expired: Vec<(u64, u64, u64)> = self.compute_nodes.values()
+            .flat_map(|node| node.occupations.values())
+            .filter(|occ| {
+                occ.status == OccupationStatus::Preempting && occ.preempt_deadline <= clock
+            })
+            .map(|occ| (occ.task_id, occ.stage_id, occ.node_id))
+            .collect();
+        for (task_id, stage_id, node_id) in expired {
+            let _ = self.preemption_done(task_id, stage_id, node_id, callback);
+        }
+        self.expire_leases(callback);
+        self.prune_finished();
+    }
+    fn expire_leases(&mut self, callback: &CallbackTrigger) {
+        let timeout = self.config.lease_timeout;
+        if timeout == 0 {
+            return;
+        }
+        let clock = self.clock;
+        let mut expired = Vec::new();
+        for node in self.compute_nodes.values_mut() {
+            for occ in node.occupations.values_mut() {
+                if occ.status == OccupationStatus::Running &&
+                    occ.last_updated.saturating_add(timeout) <= clock {
+                    occ.status = OccupationStatus::Expired;
+                    release_res!(self.pools, node, occ);
+                    expired.push(occ.clone());
+                }
+            }
+        }
+        let mut nodes: Vec<u64> = expired.iter().map(|occ| occ.node_id).collect();
+        nodes.dedup();
+        for occ in expired {
+            self.record_event(occ.task_id, occ.stage_id, occ.node_id, TaskEventKind::LeaseExpired);
+            callback.occupation_changed(&occ);
+        }
+        for node_id in nodes {
+            for occ in self.admit(node_id) {
+                callback.occupation_changed(&occ);
+            }
+        }
+    }
+    // Mark all occupations on the node `Lost` and give their resources back. The node itself is
+    //  kept, the caller decides whether to remove it.
+    fn lose_node(&mut self, node_id: u64, callback: &CallbackTrigger) {
+        let lost = {
+            let node = match self.compute_nodes.get_mut(&node_id) {
+                Some(node) => node,
+                None => return
+            };
+            let mut lost = Vec::new();
+            for occ in node.occupations.values_mut() {
+                match occ.status {
+                    OccupationStatus::Running | OccupationStatus::Preempting => {
+                        release_res!(self.pools, node, occ);
+                    },
+                    OccupationStatus::Scheduled => {},
+                    _ => continue
+                }
+                occ.status = OccupationStatus::Lost;
+                lost.push(occ.clone());
+            }
+            node.queue.clear();
+            lost
+        };
+        for occ in lost {
+            self.record_event(occ.task_id, occ.stage_id, node_id, TaskEventKind::OccupationLost);
+            callback.occupation_changed(&occ);
+        }
+    }
+    fn remove_node(&mut self, node_id: u64, callback: &CallbackTrigger) {
+        self.lose_node(node_id, callback);
+        if let Some(node) = self.compute_nodes.remove(&node_id) {
+            self.index_labels(node_id, &node.labels, false);
+        }
+    }
+    fn set_online(&mut self, node_id: u64, online: bool, callback: &CallbackTrigger) {
+        match self.compute_nodes.get_mut(&node_id) {
+            Some(node) => node.online = online,
+            None => return
+        }
+        if !online {
+            self.lose_node(node_id, callback);
+        }
+        callback.member_changed(&self.compute_nodes[&node_id]);
+    }
+    // Stop taking occupations on the node. Queued occupations are lost for schedulers to place
+    //  them on other nodes, running ones are left to finish.
+    fn drain_node(&mut self, node_id: u64, callback: &CallbackTrigger) -> Result<(), String> {
+        let lost = match self.compute_nodes.get_mut(&node_id) {
+            Some(node) => {
+                node.state = NodeState::Draining;
+                node.queue.clear();
+                let mut lost = Vec::new();
+                for occ in node.occupations.values_mut() {
+                    if occ.status == OccupationStatus::Scheduled {
+                        occ.status = OccupationStatus::Lost;
+                        lost.push(occ.clone());
+                    }
+                }
+                lost
+            },
+            None => return Err(format!("Cannot find node with id {}", node_id))
+        };
+        for occ in lost {
+            self.record_event(occ.task_id, occ.stage_id, node_id, TaskEventKind::OccupationLost);
+            callback.occupation_changed(&occ);
+        }
+        Ok(())
+    }
+    fn admit(&mut self, node_id: u64) -> Vec<Occupation> {
+        let blocked = self.blocked_on(node_id);
+        let promoted = {
+            let clock = self.clock;
+            let pools = &mut self.pools;
+            match self.compute_nodes.get_mut(&node_id) {
+                Some(node) => if node.accepts() {
+                    admit_node(node, pools, clock, &blocked)
+                } else {
+                    Vec::new()
+                },
+                None => Vec::new()
+            }
+        };
+        for occ in &promoted {
+            let _ = self.change_task_status(occ.task_id, TaskStatus::Running);
+        }
+        promoted
+    }
+    fn preempt(&mut self, node_id: u64) -> Vec<(Occupation, u64)> {
+        let deadline = self.clock + self.config.preemption_grace;
+        let blocked = self.blocked_on(node_id);
+        let pools = &self.pools;
+        match self.compute_nodes.get_mut(&node_id) {
+            Some(node) => if node.accepts() {
+                preempt_node(node, pools, deadline, &blocked)
+            } else {
+                Vec::new()
+            },
+            None => Vec::new()
+        }
+    }
+    // Stage ids of occupations queued on the node that cannot run there for their constraints
+    fn blocked_on(&self, node_id: u64) -> BTreeSet<u64> {
+        match self.compute_nodes.get(&node_id) {
+            Some(node) => node.occupations.values()
+                .filter(|occ| occ.status == OccupationStatus::Scheduled)
+                .filter(|occ| {
+                    !occ.constraints.matches_required(&node.labels) ||
+                        self.anti_affinity_violated(occ, &[], false) ||
+                        self.tasks.get(&occ.task_id)
+                            .map(|task| task.blacklist.contains(&node_id))
+                            .unwrap_or(false)
+                })
+                .map(|occ| occ.stage_id)
+                .collect(),
+            None => BTreeSet::new()
+        }
+    }
+    // Whether `occ` on its node conflicts with occupations it should keep away from. `pending` are
+    //  occupations registering together with it. Scheduled occupations only count on registration,
+    //  or queued occupations avoiding each other would wait for each other forever.
+    // Only nodes in the topology domain are looked at, found from the label index.
+    fn anti_affinity_violated(
+        &self, occ: &Occupation, pending: &[Occupation], include_scheduled: bool
+    ) -> bool {
+        if occ.constraints.anti_affinity.is_empty() {
+            return false;
+        }
+        let node = match self.compute_nodes.get(&occ.node_id) {
+            Some(node) => node,
+            None => return true
+        };
+        let counts = |other: &Occupation| {
+            !(other.task_id == occ.task_id && other.stage_id == occ.stage_id) &&
+                match other.status {
+                    OccupationStatus::Running | OccupationStatus::Preempting => true,
+                    OccupationStatus::Scheduled => include_scheduled,
+                    _ => false
+                }
+        };
+        for anti in &occ.constraints.anti_affinity {
+            let mut domain = BTreeSet::new();
+            domain.insert(node.node_id);
+            if let Some(ref key) = anti.topology_key {
+                if let Some(value) = node.labels.get(key) {
+                    if let Some(nodes) = self.label_index.get(&(key.clone(), value.clone())) {
+                        domain.extend(nodes.iter().cloned());
+                    }
+                }
+            }
+            let placed = domain.iter()
+                .filter_map(|node_id| self.compute_nodes.get(node_id))
+                .flat_map(|node| node.occupations.values())
+                .chain(pending.iter().filter(|other| domain.contains(&other.node_id)))
+                .any(|other| other.task_id == anti.task_id && counts(other));
+            if placed {
+                return true;
+            }
+        }
+        false
+    }
+    fn change_task_status(&mut self, task_id: u64, status: TaskStatus) -> Result<(), String> {
+        let from = match self.tasks.get_mut(&task_id) {
+            Some(task) => {
+                if task.status == status {
+                    return Ok(())
+                }
+                if !task.status.can_change_to(&status) {
+                    return Err(format!(
+                        "Task {} cannot change from {:?} to {:?}", task_id, task.status, status
+                    ))
+                }
+                let from = task.status.clone();
+                task.status = status.clone();
+                from
+            },
+            None => return Err(format!("Cannot find task with id {}", task_id))
+        };
+        self.record_event(task_id, 0, 0, TaskEventKind::StatusChanged { from, to: status });
+        Ok(())
+    }
+    // Release all occupations of the task and move it into finished history.
+    fn end_task(&mut self, task_id: u64, status: TaskStatus, callback: &CallbackTrigger)
+        -> Result<(), String>
+    {
+        if self.tasks.get(&task_id).map(|task| task.status.is_terminal()).unwrap_or(false) {
+            return Err(format!("Task {} has already ended", task_id))
+        }
+        self.change_task_status(task_id, status)?;
+        let clock = self.clock;
+        let nodes = {
+            let task = self.tasks.get_mut(&task_id).unwrap();
+            task.ended = clock;
+            task.nodes.clone()
+        };
+        for node_id in &nodes {
+            let released = match self.compute_nodes.get_mut(node_id) {
+                Some(node) => {
+                    let mut released = Vec::new();
+                    for occ in node.occupations.values_mut().filter(|occ| occ.task_id == task_id) {
+                        match occ.status {
+                            OccupationStatus::Running | OccupationStatus::Preempting => {
+                                release_res!(self.pools, node, occ);
+                                occ.status = OccupationStatus::Released;
+                            },
+                            // its admission queue entry will be dropped as stale
+                            OccupationStatus::Scheduled => occ.status = OccupationStatus::Withdrawn,
+                            _ => continue
+                        }
+                        released.push(occ.clone());
+                    }
+                    released
+                },
+                None => continue
+            };
+            for occ in released {
+                callback.occupation_changed(&occ);
+            }
+            for occ in self.admit(*node_id) {
+                callback.occupation_changed(&occ);
+            }
+        }
+        callback.task_ended(&self.tasks[&task_id]);
+        self.finished.push_back(task_id);
+        self.prune_finished();
+        Ok(())
+    }
+    // Drop ended tasks and their occupations beyond the limit or retention
+    fn prune_finished(&mut self) {
+        let clock = self.clock;
+        loop {
+            let expired = match self.finished.front() {
+                Some(task_id) => {
+                    self.finished.len() > self.config.finished_task_limit ||
+                        self.tasks.get(task_id).map(|task| {
+                            task.ended.saturating_add(self.config.finished_task_retention) <= clock
+                        }).unwrap_or(true)
+                },
+                None => break
+            };
+            if !expired {
+                break;
+            }
+            let task_id = self.finished.pop_front().unwrap();
+            if let Some(task) = self.tasks.remove(&task_id) {
+                for node_id in &task.nodes {
+                    if let Some(node) = self.compute_nodes.get_mut(node_id) {
+                        let stages: Vec<u64> = node.occupations.values()
+                            .filter(|occ| occ.task_id == task_id)
+                            .map(|occ| occ.stage_id)
+                            .collect();
+                        for stage_id in stages {
+                            node.occupations.remove(&stage_id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    fn query_tasks(&self, filter: &TaskFilter) -> Vec<Task> {
+        self.tasks.values()
+            .filter(|task| filter.status.as_ref().map(|s| *s == task.status).unwrap_or(true))
+            .filter(|task| filter.pool.as_ref().map(|p| *p == task.pool).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+    fn query_nodes(&self, filter: &NodeFilter) -> Vec<ComputeNode> {
+        self.compute_nodes.values()
+            .filter(|node| node.online || !filter.online_only)
+            .filter(|node| {
+                node.memory_remains() >= filter.min_free_memory &&
+                    node.processors_remains() >= filter.min_free_processors
+            })
+            .map(|node| {
+                if filter.with_occupations {
+                    node.clone()
+                } else {
+                    ComputeNode {
+                        occupations: BTreeMap::new(),
+                        queue: BTreeMap::new(),
+                        address: node.address.clone(),
+                        resources: node.resources.clone(),
+                        resources_remains: node.resources_remains.clone(),
+                        labels: node.labels.clone(),
+                        ..*node
+                    }
+                }
+            })
+            .collect()
+    }
+    fn utilization(&self) -> ClusterUtilization {
+        let mut util = ClusterUtilization::default();
+        for node in self.compute_nodes.values() {
+            let memory_used = node.memory() - node.memory_remains();
+            let processors_used = node.processors() - node.processors_remains();
+            util.nodes += 1;
+            if node.online {
+                util.online_nodes += 1;
+                util.total_memory += node.memory();
+                util.used_memory += memory_used;
+                util.free_memory += node.memory_remains();
+                util.total_processors += node.processors() as u64;
+                util.used_processors += processors_used as u64;
+                util.free_processors += node.processors_remains() as u64;
+                util.total_resources.add(&node.resources);
+                util.free_resources.add(&node.resources_remains);
+            }
+            for occ in node.occupations.values() {
+                *util.occupations.entry(occ.status).or_insert(0) += 1;
+            }
+            util.per_node.push(NodeUtilization {
+                node_id: node.node_id,
+                address: node.address.clone(),
+                online: node.online,
+                state: node.state,
+                memory: node.memory(),
+                memory_used,
+                processors: node.processors(),
+                processors_used,
+            });
+        }
+        util
+    }
+    fn record_event(&mut self, task_id: u64, stage_id: u64, node_id: u64, kind: TaskEventKind) {
+        let time = self.clock;
+        if let Some(task) = self.tasks.get_mut(&task_id) {
+            task.history.push(TaskEvent { time, stage_id, node_id, kind });
+        }
+    }
+    // victims are paired with the task id of the occupation they are preempted for
+    fn notify_preemptions(&mut self, preemptions: Vec<(Occupation, u64)>, callback: &CallbackTrigger) {
+        for (victim, by_task) in preemptions {
+            self.record_event(
+                victim.task_id, victim.stage_id, victim.node_id,
+                TaskEventKind::Preempted { by_task }
+            );
+            callback.occupation_changed(&victim);
+            callback.preemption(&victim);
+        }
+    }
+}
+
+impl ComputeNode {
+    pub fn new<'a>(
+        address: &'a str,
+        node_id: u64,
+        memory: u64,
+        processors: u32,
+    ) -> ComputeNode {
+        let resources = Resources::new()
+            .with(vector::MEMORY, memory)
+            .with(vector::PROCESSORS, processors as u64);
+        ComputeNode {
+            address: address.to_string(),
+            node_id,
+            online: true,
+            occupations: BTreeMap::new(),
+            queue: BTreeMap::new(),
+            resources_remains: resources.clone(),
+            resources,
+            labels: Labels::new(),
+            state: NodeState::Active,
+        }
+    }
+    // whether the node can take new occupations
+    pub fn accepts(&self) -> bool {
+        self.online && self.state == NodeState::Active
+    }
+    pub fn state(&self) -> NodeState { self.state }
+    pub fn with_labels(mut self, labels: Labels) -> ComputeNode {
+        self.labels = labels;
+        self
+    }
+    // add other dimensions to the capacity
+    pub fn with_resources(mut self, resources: Resources) -> ComputeNode {
+        self.resources.add(&resources);
+        self.resources_remains.add(&resources);
+        self
+    }
+    pub fn remains(&self) -> &Resources { &self.resources_remains }
+    pub fn resources(&self) -> &Resources { &self.resources }
+    pub fn labels(&self) -> &Labels { &self.labels }
+    pub fn node_id(&self) -> u64 { self.node_id }
+    pub fn address(&self) -> &str { &self.address }
+    pub fn online(&self) -> bool { self.online }
+    pub fn memory(&self) -> u64 { self.resources.get(vector::MEMORY) }
+    pub fn memory_remains(&self) -> u64 { self.resources_remains.get(vector::MEMORY) }
+    pub fn processors(&self) -> u32 { self.resources.get(vector::PROCESSORS) as u32 }
+    pub fn processors_remains(&self) -> u32 { self.resources_remains.get(vector::PROCESSORS) as u32 }
+    pub fn occupations(&self) -> &BTreeMap<u64, Occupation> { &self.occupations }
+}
+
+impl Task {
+    pub fn new(id: u64, name: &str, pool: &str, priority: u32, submitted: u64, meta: Map) -> Task {
+        Task {
+            id, priority, submitted, meta,
+            name: name.to_string(),
+            pool: pool.to_string(),
+            history: Vec::new(),
+            ended: 0,
+            retry: RetryPolicy::default(),
+            failures: Vec::new(),
+            blacklist: BTreeSet::new(),
+            commits: BTreeMap::new(),
+            status: TaskStatus::Pending,
+            stages: Vec::new(),
+            nodes: Vec::new(),
+        }
+    }
+    pub fn id(&self) -> u64 { self.id }
+    pub fn name(&self) -> &str { &self.name }
+    pub fn status(&self) -> &TaskStatus { &self.status }
+    pub fn pool(&self) -> &str { &self.pool }
+    pub fn priority(&self) -> u32 { self.priority }
+    pub fn history(&self) -> &Vec<TaskEvent> { &self.history }
+    pub fn retry(&self) -> &RetryPolicy { &self.retry }
+    pub fn failures(&self) -> &Vec<StageFailure> { &self.failures }
+    pub fn blacklist(&self) -> &BTreeSet<u64> { &self.blacklist }
+    pub fn commits(&self) -> &BTreeMap<u64, u64> { &self.commits }
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Task {
+        self.retry = retry;
+        self
+    }
+}
+
+impl Occupation {
+    pub fn new(task_id: u64, stage_id: u64, node_id: u64, workers: u32, memory: u64) -> Occupation {
+        Occupation {
+            task_id, stage_id, node_id,
+            status: OccupationStatus::Scheduled,
+            last_updated: 0,
+            priority: 0,
+            submitted: 0,
+            pool: String::new(),
+            started: 0,
+            preempt_deadline: 0,
+            resources: Resources::new()
+                .with(vector::MEMORY, memory)
+                .with(vector::PROCESSORS, workers as u64),
+            constraints: Constraints::default(),
+        }
+    }
+    pub fn with_constraints(mut self, constraints: Constraints) -> Occupation {
+        self.constraints = constraints;
+        self
+    }
+    // ask for other dimensions as well
+    pub fn with_resources(mut self, resources: Resources) -> Occupation {
+        self.resources.add(&resources);
+        self
+    }
+    pub fn memory(&self) -> u64 { self.resources.get(vector::MEMORY) }
+    pub fn workers(&self) -> u32 { self.resources.get(vector::PROCESSORS) as u32 }
+    // holding or waiting for resources
+    pub fn is_active(&self) -> bool {
+        match self.status {
+            OccupationStatus::Scheduled |
+            OccupationStatus::Running |
+            OccupationStatus::Preempting => true,
+            _ => false
+        }
+    }
+}
+
+fn mark_member(
+    online: bool,
+    changes: &Result<(ClientMember, u64), ()>,
+    state: &Arc<RwLock<ManagerState>>,
+    callback: &Arc<CallbackTrigger>
+) {
+    if let &Ok((ref member, _)) = changes {
+        state.write().set_online(member.id, online, &**callback);
+    }
+}
+
+// Promote queued occupations of the node until the one chosen by fair share cannot be afforded.
+// Returns promoted occupations for notification.
+// Occupations in `blocked` are passed over without blocking their pools.
+fn admit_node(
+    node: &mut ComputeNode, pools: &mut Pools, clock: u64, blocked: &BTreeSet<u64>
+) -> Vec<Occupation> {
+    let mut promoted = Vec::new();
+    loop {
+        // find the head of the queue for each pool, and drop occupations acquired by
+        //  `try_acquire_node_resource` or removed
+        let mut stale = Vec::new();
+        let mut heads: BTreeMap<String, (AdmissionKey, u64)> = BTreeMap::new();
+        for (key, stage_id) in &node.queue {
+            match node.occupations.get(stage_id) {
+                Some(occ) if occ.status == OccupationStatus::Scheduled => {
+                    if !blocked.contains(stage_id) && !heads.contains_key(&occ.pool) {
+                        heads.insert(occ.pool.clone(), (*key, *stage_id));
+                    }
+                },
+                _ => stale.push(*key)
+            }
+        }
+        for key in stale {
+            node.queue.remove(&key);
+        }
+        let mut candidates: Vec<String> = heads.keys().cloned().collect();
+        candidates.sort_by(|a, b| pools.fair_cmp(a, b));
+        let mut next = None;
+        for pool in candidates {
+            let (key, stage_id) = heads[&pool];
+            let occ = &node.occupations[&stage_id];
+            if !pools.allows(&occ.pool, occ.memory(), occ.workers()) {
+                continue;
+            }
+            if node.remains().covers(&occ.resources) {
+                next = Some((key, stage_id));
+            }
+            break;
+        }
+        let (key, stage_id) = match next {
+            Some(next) => next,
+            None => break
+        };
+        node.queue.remove(&key);
+        let occ = node.occupations.get_mut(&stage_id).unwrap();
+        acquire_res!(pools, clock, node, occ);
+        promoted.push(occ.clone());
+    }
+    return promoted;
+}
+
+// If the highest priority occupation in the queue cannot be afforded, mark running occupations
+//  with lower priority as `Preempting` until enough capacity would be reclaimed. Capacity held by
+//  occupations already being preempted is counted in, so it won't preempt more than necessary.
+// Occupations their pools cannot take now are passed over, as the reclaimed capacity would not
+//  be admitted to them anyway.
+// Returns victims with the task id they are preempted for.
+fn preempt_node(
+    node: &mut ComputeNode, pools: &Pools, deadline: u64, blocked: &BTreeSet<u64>
+) -> Vec<(Occupation, u64)> {
+    let head = node.queue.values()
+        .filter(|stage_id| !blocked.contains(stage_id))
+        .filter_map(|stage_id| node.occupations.get(stage_id))
+        .filter(|occ| occ.status == OccupationStatus::Scheduled)
+        .find(|occ| pools.allows(&occ.pool, occ.memory(), occ.workers()))
+        .cloned();
+    let head = match head {
+        Some(head) => head,
+        None => return Vec::new()
+    };
+    let demand = head.resources;
+    let mut free = node.remains().clone();
+    for occ in node.occupations.values() {
+        if occ.status == OccupationStatus::Preempting {
+            free.add(&occ.resources);
+        }
+    }
+    if free.covers(&demand) {
+        return Vec::new();
+    }
+    let mut candidates: Vec<(u32, u64, u64)> = node.occupations.values()
+        .filter(|occ| occ.status == OccupationStatus::Running && occ.priority < head.priority)
+        .map(|occ| (occ.priority, u64::max_value() - occ.started, occ.stage_id))
+        .collect();
+    candidates.sort();
+    let mut victims = Vec::new();
+    for (_, _, stage_id) in candidates {
+        if free.covers(&demand) {
+            break;
+        }
+        free.add(&node.occupations[&stage_id].resources);
+        victims.push(stage_id);
+    }
+    if !free.covers(&demand) {
+        // cannot make room even by preempting all of them, not worth killing any work
+        return Vec::new();
+    }
+    victims.into_iter().map(|stage_id| {
+        let occ = node.occupations.get_mut(&stage_id).unwrap();
+        occ.status = OccupationStatus::Preempting;
+        occ.preempt_deadline = deadline;
+        (occ.clone(), head.task_id)
+    }).collect()
+}
+
+// Notifications of the state machine. Tests record them instead of sending.
+trait CallbackTrigger: Send + Sync {
+    fn notify_occupation_changed(&self, occ: &Occupation);
+    fn notify_resource_available(&self, occ: &Occupation);
+    fn preemption(&self, occ: &Occupation);
+    fn task_ended(&self, task: &Task);
+    fn member_changed(&self, node: &ComputeNode);
+    fn occupation_changed(&self, occ: &Occupation) {
+        self.notify_occupation_changed(occ);
+        if occ.status == OccupationStatus::Released ||
+            occ.status == OccupationStatus::Expired ||
+            occ.status == OccupationStatus::Failed ||
+            occ.status == OccupationStatus::Superseded {
+            self.notify_resource_available(occ);
+        }
+    }
+}
+
+struct SMCallbackTrigger {
+    sm_callback: SMCallback
+}
+
+impl CallbackTrigger for SMCallbackTrigger {
+    fn notify_occupation_changed(&self, occ: &Occupation) {
+        self.sm_callback.notify(
+            &commands::on_occupation_changed::new(),
+            Ok(occ.clone())
+        );
+    }
+    fn notify_resource_available(&self, occ: &Occupation) {
+        self.sm_callback.notify(
+            &commands::on_resource_available::new(),
+            Ok(occ.clone())
+        );
+    }
+    fn preemption(&self, occ: &Occupation) {
+        self.sm_callback.notify(
+            &commands::on_preemption::new(),
+            Ok(occ.clone())
+        );
+    }
+    fn task_ended(&self, task: &Task) {
+        self.sm_callback.notify(
+            &commands::on_task_ended::new(),
+            Ok(task.clone())
+        );
+    }
+    fn member_changed(&self, node: &ComputeNode) {
+        self.sm_callback.notify(
+            &commands::on_member_changed::new(),
+            Ok(node.clone())
+        );
+    }
+}
+// Layouts of unversioned snapshots, as they were before any of the scheduling features.
+// Enums are copied as well, bincode encodes variants by their index.
+mod legacy {
+    use super::*;
+    use super::super::pools::DEFAULT_POOL;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct Occupation {
+        pub task_id: u64,
+        pub stage_id: u64,
+        pub workers: u32,
+        pub memory: u64,
+        pub node_id: u64,
+        pub status: OccupationStatus,
+        pub last_updated: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct Task {
+        pub id: u64,
+        pub name: String,
+        pub status: TaskStatus,
+        pub stages: Vec<u64>,
+        pub nodes: Vec<u64>,
+        pub meta: Map,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct ComputeNode {
+        pub address: String,
+        pub memory: u64,
+        pub memory_remains: u64,
+        pub processors: u32,
+        pub processors_remains: u32,
+        pub node_id: u64,
+        pub online: bool,
+        pub occupations: BTreeMap<u64, Occupation>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub enum TaskStatus {
+        Succeed,
+        Failed,
+        Canceled
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum OccupationStatus {
+        Running,
+        Released,
+        Scheduled,
+    }
+
+    pub type SnapshotPack = (BTreeMap<u64, ComputeNode>, BTreeMap<u64, Task>);
+
+    // Everything goes to the default pool with the lowest priority. Tasks had a terminal status
+    //  from the start, so the ones still holding or waiting for resources are taken as not ended.
+    pub fn upgrade(pack: SnapshotPack) -> ManagerState {
+        let (nodes, tasks) = pack;
+        let pool = DEFAULT_POOL.to_string();
+        let mut state = ManagerState::new();
+        let mut queued = Vec::new();
+        for (_, node) in nodes {
+            let mut upgraded = super::ComputeNode::new(
+                &node.address, node.node_id, node.memory, node.processors
+            );
+            upgraded.online = node.online;
+            upgraded.resources_remains.set(vector::MEMORY, node.memory_remains);
+            upgraded.resources_remains.set(vector::PROCESSORS, node.processors_remains as u64);
+            for (stage_id, occ) in node.occupations {
+                let mut upgraded_occ = super::Occupation::new(
+                    occ.task_id, occ.stage_id, occ.node_id, occ.workers, occ.memory
+                );
+                upgraded_occ.last_updated = occ.last_updated;
+                upgraded_occ.pool = pool.clone();
+                match occ.status {
+                    OccupationStatus::Running => {
+                        upgraded_occ.status = super::OccupationStatus::Running;
+                        state.pools.account(&pool, occ.memory, occ.workers, true);
+                    },
+                    OccupationStatus::Released => {
+                        upgraded_occ.status = super::OccupationStatus::Released;
+                    },
+                    OccupationStatus::Scheduled => {
+                        // admission queue didn't exist, queue them in stage order
+                        queued.push(upgraded_occ);
+                        continue;
+                    }
+                }
+                upgraded.occupations.insert(stage_id, upgraded_occ);
+            }
+            state.add_node(upgraded).unwrap();
+        }
+        for (id, task) in tasks {
+            let mut upgraded = super::Task::new(id, &task.name, &pool, 0, 0, task.meta);
+            upgraded.stages = task.stages;
+            upgraded.nodes = task.nodes;
+            let running = upgraded.nodes.iter()
+                .filter_map(|node_id| state.compute_nodes.get(node_id))
+                .flat_map(|node| node.occupations.values())
+                .any(|occ| occ.task_id == id && occ.is_active());
+            let waiting = queued.iter().any(|occ| occ.task_id == id);
+            upgraded.status = if running {
+                super::TaskStatus::Running
+            } else if waiting {
+                super::TaskStatus::Pending
+            } else {
+                match task.status {
+                    TaskStatus::Succeed => super::TaskStatus::Succeed,
+                    TaskStatus::Failed => super::TaskStatus::Failed,
+                    TaskStatus::Canceled => super::TaskStatus::Canceled,
+                }
+            };
+            if upgraded.status.is_terminal() {
+                state.finished.push_back(id);
+            }
+            state.tasks.insert(id, upgraded);
+        }
+        for occ in queued {
+            state.enqueue(occ);
+        }
+        state
+    }
+}
+
+mod test {
+    use super::*;
+    use super::super::pools::{DEFAULT_POOL, Pool};
+    use parking_lot::Mutex;
+    use std::mem;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Notification {
+        OccupationChanged(u64, u64, OccupationStatus), // task id, stage id, new status
+        ResourceAvailable(u64, u64),
+        Preemption(u64, u64),
+        TaskEnded(u64),
+        MemberChanged(u64),
+    }
+
+    struct Recorder {
+        notifications: Mutex<Vec<Notification>>
+    }
+
+    impl Recorder {
+        fn new() -> Recorder {
+            Recorder { notifications: Mutex::new(Vec::new()) }
+        }
+        fn take(&self) -> Vec<Notification> {
+            mem::replace(&mut *self.notifications.lock(), Vec::new())
+        }
+    }
+
+    impl CallbackTrigger for Recorder {
+        fn notify_occupation_changed(&self, occ: &Occupation) {
+            self.notifications.lock().push(
+                Notification::OccupationChanged(occ.task_id, occ.stage_id, occ.status));
+        }
+        fn notify_resource_available(&self, occ: &Occupation) {
+            self.notifications.lock().push(Notification::ResourceAvailable(occ.task_id, occ.stage_id));
+        }
+        fn preemption(&self, occ: &Occupation) {
+            self.notifications.lock().push(Notification::Preemption(occ.task_id, occ.stage_id));
+        }
+        fn task_ended(&self, task: &Task) {
+            self.notifications.lock().push(Notification::TaskEnded(task.id));
+        }
+        fn member_changed(&self, node: &ComputeNode) {
+            self.notifications.lock().push(Notification::MemberChanged(node.node_id));
+        }
+    }
+
+    // nodes have 100 memory and 4 processors
+    fn state(nodes: &[u64]) -> ManagerState {
+        let mut state = ManagerState::new();
+        for id in nodes {
+            state.add_node(ComputeNode::new(&format!("node{}", id), *id, 100, 4)).unwrap();
+        }
+        state
+    }
+
+    fn task(id: u64, priority: u32) -> Task {
+        Task::new(id, "test", DEFAULT_POOL, priority, id, Map::new())
+    }
+
+    fn pool_task(id: u64, pool: &str) -> Task {
+        Task::new(id, "test", pool, 0, id, Map::new())
+    }
+
+    // occupations are (stage id, node id, memory) with one worker each
+    fn register(
+        state: &mut ManagerState, cb: &Recorder, task: Task, occs: &[(u64, u64, u64)]
+    ) -> Result<Vec<Occupation>, RegisterTaskError> {
+        let task_id = task.id;
+        let occs = occs.iter()
+            .map(|&(stage_id, node_id, memory)| Occupation::new(task_id, stage_id, node_id, 1, memory))
+            .collect();
+        state.register_task(task, occs, cb)
+    }
+
+    fn status(state: &ManagerState, node_id: u64, stage_id: u64) -> OccupationStatus {
+        state.compute_nodes[&node_id].occupations[&stage_id].status
+    }
+
+    fn running(occs: Vec<Occupation>) -> Vec<u64> {
+        occs.into_iter().map(|occ| occ.stage_id).collect()
+    }
+
+    #[test]
+    fn admission_by_priority() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        assert_eq!(running(register(&mut state, &cb, task(1, 10), &[(1, 1, 80)]).unwrap()), vec![1]);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 20);
+        // both wait, no preemption as the running one has the highest priority
+        assert!(register(&mut state, &cb, task(2, 0), &[(2, 1, 70)]).unwrap().is_empty());
+        assert!(register(&mut state, &cb, task(3, 5), &[(3, 1, 40)]).unwrap().is_empty());
+        assert_eq!(*state.tasks[&2].status(), TaskStatus::Pending);
+        cb.take();
+        assert!(state.release_occupation(1, 1, 1, &cb).unwrap());
+        // the higher priority one first, then the other one does not fit anymore
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Running);
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Scheduled);
+        assert_eq!(*state.tasks[&3].status(), TaskStatus::Running);
+        assert_eq!(cb.take(), vec![
+            Notification::OccupationChanged(1, 1, OccupationStatus::Released),
+            Notification::ResourceAvailable(1, 1),
+            Notification::OccupationChanged(3, 3, OccupationStatus::Running),
+        ]);
+        assert!(state.release_occupation(3, 3, 1, &cb).unwrap());
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Running);
+        assert!(state.compute_nodes[&1].queue.is_empty());
+    }
+
+    #[test]
+    fn admission_fifo_and_strict_head() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 80)]).unwrap();
+        register(&mut state, &cb, task(2, 0), &[(2, 1, 50)]).unwrap();
+        // fits in the free memory, but must not overtake the head of the queue
+        assert!(register(&mut state, &cb, task(3, 0), &[(3, 1, 10)]).unwrap().is_empty());
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Scheduled);
+        state.release_occupation(1, 1, 1, &cb).unwrap();
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Running);
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Running);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 40);
+        assert_eq!(state.compute_nodes[&1].processors_remains(), 2);
+    }
+
+    #[test]
+    fn acquire_bypasses_queue() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 80)]).unwrap();
+        register(&mut state, &cb, task(2, 0), &[(2, 1, 50)]).unwrap();
+        register(&mut state, &cb, task(3, 0), &[(3, 1, 10)]).unwrap();
+        assert!(!state.try_acquire_node_resource(2, 2, 1, &cb).unwrap());
+        assert!(state.try_acquire_node_resource(3, 3, 1, &cb).unwrap());
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Running);
+        match state.try_acquire_node_resource(2, 3, 1, &cb) {
+            Err(ChangeOccupationStatusError::OccupationTaskNotMatch) => {},
+            other => panic!("unexpected {:?}", other)
+        }
+        // the acquired occupation is dropped from the queue as stale
+        state.release_occupation(1, 1, 1, &cb).unwrap();
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Running);
+        assert!(state.compute_nodes[&1].queue.is_empty());
+    }
+
+    #[test]
+    fn fair_share_between_pools() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        state.pools.create(Pool::new("a", None, 1)).unwrap();
+        state.pools.create(Pool::new("b", None, 1)).unwrap();
+        register(&mut state, &cb, pool_task(1, "a"), &[(1, 1, 50)]).unwrap();
+        register(&mut state, &cb, pool_task(2, "a"), &[(2, 1, 50)]).unwrap();
+        register(&mut state, &cb, pool_task(3, "a"), &[(3, 1, 50)]).unwrap();
+        register(&mut state, &cb, pool_task(4, "b"), &[(4, 1, 50)]).unwrap();
+        assert_eq!(state.pools.pools["a"].used_memory, 100);
+        state.release_occupation(1, 1, 1, &cb).unwrap();
+        // submitted later, but pool b has nothing running
+        assert_eq!(status(&state, 1, 4), OccupationStatus::Running);
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Scheduled);
+        assert_eq!(state.pools.pools["a"].used_memory, 50);
+        assert_eq!(state.pools.pools["b"].used_memory, 50);
+        match register(&mut state, &cb, pool_task(5, "c"), &[(5, 1, 10)]) {
+            Err(RegisterTaskError::PoolNotFound(ref pool)) if pool == "c" => {},
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn pool_quota() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2]);
+        let mut pool = Pool::new("q", None, 1);
+        pool.max_memory = Some(60);
+        state.pools.create(pool).unwrap();
+        // can never fit in the quota
+        match register(&mut state, &cb, pool_task(1, "q"), &[(1, 1, 40), (2, 2, 40)]) {
+            Err(RegisterTaskError::QuotaExceeded) => {},
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(!state.tasks.contains_key(&1));
+        register(&mut state, &cb, pool_task(2, "q"), &[(1, 1, 50)]).unwrap();
+        // the node has room but the pool does not
+        assert!(register(&mut state, &cb, pool_task(3, "q"), &[(2, 2, 20)]).unwrap().is_empty());
+        assert!(!state.try_acquire_node_resource(3, 2, 2, &cb).unwrap());
+        assert_eq!(status(&state, 2, 2), OccupationStatus::Scheduled);
+        // other pools are not affected
+        assert_eq!(running(register(&mut state, &cb, task(4, 0), &[(3, 2, 50)]).unwrap()), vec![3]);
+        state.release_occupation(2, 1, 1, &cb).unwrap();
+        assert_eq!(state.pools.pools["q"].used_memory, 0);
+        // admission on other nodes waits for resources released there
+        assert_eq!(status(&state, 2, 2), OccupationStatus::Scheduled);
+        assert!(state.try_acquire_node_resource(3, 2, 2, &cb).unwrap());
+        assert_eq!(state.pools.pools["q"].used_memory, 20);
+    }
+
+    #[test]
+    fn preemption_done_by_scheduler() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 80)]).unwrap();
+        cb.take();
+        assert!(register(&mut state, &cb, task(2, 10), &[(2, 1, 50)]).unwrap().is_empty());
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Preempting);
+        assert_eq!(cb.take(), vec![
+            Notification::OccupationChanged(1, 1, OccupationStatus::Preempting),
+            Notification::Preemption(1, 1),
+        ]);
+        assert_eq!(state.tasks[&1].history()[1].kind, TaskEventKind::Preempted { by_task: 2 });
+        // still holds its resources until the scheduler is done with it
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 20);
+        // the second victim is not needed, capacity of the first one is counted in
+        assert!(register(&mut state, &cb, task(3, 10), &[(3, 1, 10)]).unwrap().is_empty());
+        assert!(!state.preemption_done(2, 2, 1, &cb).unwrap());
+        assert!(state.preemption_done(1, 1, 1, &cb).unwrap());
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Released);
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Running);
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Running);
+        assert_eq!(state.tasks[&1].history().last().unwrap().kind, TaskEventKind::PreemptionReclaimed);
+    }
+
+    #[test]
+    fn preemption_grace_period() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        state.config.preemption_grace = 100;
+        state.tick(1000, &cb);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 50)]).unwrap();
+        register(&mut state, &cb, task(2, 5), &[(2, 1, 50)]).unwrap();
+        // nothing to preempt for the ones with the same or lower priority
+        register(&mut state, &cb, task(3, 0), &[(3, 1, 50)]).unwrap();
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Running);
+        assert!(register(&mut state, &cb, task(4, 10), &[(4, 1, 50)]).unwrap().is_empty());
+        // the lowest priority goes first
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Preempting);
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Running);
+        assert_eq!(state.compute_nodes[&1].occupations[&1].preempt_deadline, 1100);
+        state.tick(1099, &cb);
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Preempting);
+        state.tick(1100, &cb);
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Released);
+        assert_eq!(status(&state, 1, 4), OccupationStatus::Running);
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Scheduled);
+    }
+
+    #[test]
+    fn no_preemption_beyond_pool_quota() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2]);
+        let mut pool = Pool::new("q", None, 1);
+        pool.max_memory = Some(50);
+        state.pools.create(pool).unwrap();
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 80)]).unwrap();
+        register(&mut state, &cb, pool_task(2, "q"), &[(2, 2, 50)]).unwrap();
+        let mut urgent = pool_task(3, "q");
+        urgent.priority = 10;
+        // the pool is full, preempting the occupation on node 1 would not let it run
+        assert!(register(&mut state, &cb, urgent, &[(3, 1, 40)]).unwrap().is_empty());
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Running);
+        assert!(state.tasks[&1].history().iter()
+            .all(|event| event.kind != TaskEventKind::Preempted { by_task: 3 }));
+        state.release_occupation(2, 2, 2, &cb).unwrap();
+        // the pool has room now, the next placement on the node preempts
+        register(&mut state, &cb, task(4, 0), &[(4, 1, 10)]).unwrap();
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Preempting);
+    }
+
+    #[test]
+    fn node_offline_loses_occupations() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 80), (2, 2, 10)]).unwrap();
+        register(&mut state, &cb, task(2, 0), &[(3, 1, 50)]).unwrap();
+        cb.take();
+        state.set_online(1, false, &cb);
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Lost);
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Lost);
+        assert_eq!(status(&state, 2, 2), OccupationStatus::Running);
+        // resources are given back, the node keeps its capacity for when it comes back
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 100);
+        assert_eq!(state.pools.pools[DEFAULT_POOL].used_memory, 10);
+        assert!(state.compute_nodes[&1].queue.is_empty());
+        assert_eq!(cb.take(), vec![
+            Notification::OccupationChanged(1, 1, OccupationStatus::Lost),
+            Notification::OccupationChanged(2, 3, OccupationStatus::Lost),
+            Notification::MemberChanged(1),
+        ]);
+        assert_eq!(state.tasks[&1].history().last().unwrap().kind, TaskEventKind::OccupationLost);
+        // offline nodes take nothing
+        match register(&mut state, &cb, task(3, 0), &[(4, 1, 10)]) {
+            Err(RegisterTaskError::NodeOffline(1)) => {},
+            other => panic!("unexpected {:?}", other)
+        }
+        state.set_online(1, true, &cb);
+        assert_eq!(running(register(&mut state, &cb, task(3, 0), &[(4, 1, 10)]).unwrap()), vec![4]);
+    }
+
+    #[test]
+    fn deregistered_node() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 80), (2, 2, 10)]).unwrap();
+        cb.take();
+        state.remove_node(1, &cb);
+        assert!(!state.compute_nodes.contains_key(&1));
+        assert_eq!(cb.take(), vec![Notification::OccupationChanged(1, 1, OccupationStatus::Lost)]);
+        assert_eq!(state.pools.pools[DEFAULT_POOL].used_memory, 10);
+        match register(&mut state, &cb, task(2, 0), &[(3, 1, 10)]) {
+            Err(RegisterTaskError::NodeIdNotFound(1)) => {},
+            other => panic!("unexpected {:?}", other)
+        }
+        // removing again is harmless
+        state.remove_node(1, &cb);
+        assert!(cb.take().is_empty());
+    }
+
+    #[test]
+    fn lease_expiry() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        state.config.lease_timeout = 100;
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 50)]).unwrap();
+        register(&mut state, &cb, task(2, 0), &[(2, 1, 40)]).unwrap();
+        register(&mut state, &cb, task(3, 0), &[(3, 1, 50)]).unwrap();
+        // leases taken before the first tick start from it
+        state.tick(1000, &cb);
+        state.tick(1050, &cb);
+        assert!(state.heartbeat_occupation(1, 1, 1).unwrap());
+        assert!(!state.heartbeat_occupation(3, 3, 1).unwrap());
+        cb.take();
+        state.tick(1100, &cb);
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Running);
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Expired);
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Running);
+        assert_eq!(state.compute_nodes[&1].occupations[&3].last_updated, 1100);
+        assert_eq!(cb.take(), vec![
+            Notification::OccupationChanged(2, 2, OccupationStatus::Expired),
+            Notification::ResourceAvailable(2, 2),
+            Notification::OccupationChanged(3, 3, OccupationStatus::Running),
+        ]);
+        assert_eq!(state.tasks[&2].history().last().unwrap().kind, TaskEventKind::LeaseExpired);
+        // the executor finds out on its next heartbeat
+        assert!(!state.heartbeat_occupation(2, 2, 1).unwrap());
+        // time does not go back
+        state.tick(10, &cb);
+        assert_eq!(state.clock, 1100);
+        state.tick(1149, &cb);
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Running);
+        state.tick(1150, &cb);
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Expired);
+        // admitted at 1100
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Running);
+    }
+
+    #[test]
+    fn leases_disabled() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        state.config.lease_timeout = 0;
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 60)]).unwrap();
+        state.tick(1, &cb);
+        state.tick(u64::max_value(), &cb);
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Running);
+    }
+
+    #[test]
+    fn task_lifecycle() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        register(&mut state, &cb, task(1, 10), &[(1, 1, 80)]).unwrap();
+        register(&mut state, &cb, task(2, 0), &[(2, 1, 50)]).unwrap();
+        assert_eq!(*state.tasks[&1].status(), TaskStatus::Running);
+        assert_eq!(*state.tasks[&2].status(), TaskStatus::Pending);
+        assert!(state.change_task_status(2, TaskStatus::Succeeding).is_err());
+        assert!(state.change_task_status(1, TaskStatus::Pending).is_err());
+        state.change_task_status(1, TaskStatus::Succeeding).unwrap();
+        cb.take();
+        // the queued one never held resources, nothing is made available by it
+        state.end_task(2, TaskStatus::Canceled, &cb).unwrap();
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Withdrawn);
+        assert_eq!(cb.take(), vec![
+            Notification::OccupationChanged(2, 2, OccupationStatus::Withdrawn),
+            Notification::TaskEnded(2),
+        ]);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 20);
+        state.end_task(1, TaskStatus::Succeed, &cb).unwrap();
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Released);
+        assert_eq!(cb.take(), vec![
+            Notification::OccupationChanged(1, 1, OccupationStatus::Released),
+            Notification::ResourceAvailable(1, 1),
+            Notification::TaskEnded(1),
+        ]);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 100);
+        assert_eq!(state.tasks[&1].history().iter().map(|event| event.kind.clone()).collect::<Vec<_>>(), vec![
+            TaskEventKind::StatusChanged { from: TaskStatus::Pending, to: TaskStatus::Running },
+            TaskEventKind::StatusChanged { from: TaskStatus::Running, to: TaskStatus::Succeeding },
+            TaskEventKind::StatusChanged { from: TaskStatus::Succeeding, to: TaskStatus::Succeed },
+        ]);
+        // ended tasks stay ended
+        assert!(state.end_task(1, TaskStatus::Failed, &cb).is_err());
+        assert!(state.change_task_status(1, TaskStatus::Running).is_err());
+        assert!(cb.take().is_empty());
+    }
+
+    #[test]
+    fn prune_finished_tasks() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        state.config.finished_task_limit = 2;
+        state.config.finished_task_retention = 1000;
+        state.tick(100, &cb);
+        for id in 1..4 {
+            register(&mut state, &cb, task(id, 0), &[(id, 1, 10)]).unwrap();
+        }
+        state.end_task(1, TaskStatus::Succeed, &cb).unwrap();
+        state.end_task(2, TaskStatus::Failed, &cb).unwrap();
+        assert_eq!(state.tasks.len(), 3);
+        state.end_task(3, TaskStatus::Canceled, &cb).unwrap();
+        // over the limit, the earliest ended one goes with its occupations
+        assert!(!state.tasks.contains_key(&1));
+        assert!(!state.compute_nodes[&1].occupations.contains_key(&1));
+        assert_eq!(state.finished.len(), 2);
+        state.tick(1099, &cb);
+        assert_eq!(state.tasks.len(), 2);
+        state.tick(1100, &cb);
+        assert!(state.tasks.is_empty());
+        assert!(state.compute_nodes[&1].occupations.is_empty());
+        assert!(state.finished.is_empty());
+    }
+
+    #[test]
+    fn queries() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2, 3]);
+        state.pools.create(Pool::new("a", None, 1)).unwrap();
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 80)]).unwrap();
+        register(&mut state, &cb, pool_task(2, "a"), &[(2, 1, 50), (3, 2, 30)]).unwrap();
+        register(&mut state, &cb, task(3, 0), &[(4, 3, 10)]).unwrap();
+        state.end_task(3, TaskStatus::Failed, &cb).unwrap();
+        state.set_online(3, false, &cb);
+
+        let ids = |tasks: Vec<Task>| tasks.into_iter().map(|task| task.id).collect::<Vec<_>>();
+        assert_eq!(ids(state.query_tasks(&TaskFilter::default())), vec![1, 2, 3]);
+        assert_eq!(ids(state.query_tasks(&TaskFilter {
+            status: Some(TaskStatus::Running), pool: None
+        })), vec![1, 2]);
+        assert_eq!(ids(state.query_tasks(&TaskFilter {
+            status: Some(TaskStatus::Running), pool: Some("a".to_string())
+        })), vec![2]);
+        assert_eq!(ids(state.query_tasks(&TaskFilter {
+            status: Some(TaskStatus::Succeed), pool: None
+        })), Vec::<u64>::new());
+
+        let ids = |nodes: Vec<ComputeNode>| nodes.into_iter().map(|node| node.node_id).collect::<Vec<_>>();
+        assert_eq!(ids(state.query_nodes(&NodeFilter::default())), vec![1, 2, 3]);
+        let online = NodeFilter { online_only: true, ..NodeFilter::default() };
+        assert_eq!(ids(state.query_nodes(&online)), vec![1, 2]);
+        let free = NodeFilter { min_free_memory: 50, ..NodeFilter::default() };
+        assert_eq!(ids(state.query_nodes(&free)), vec![2, 3]);
+        let free = NodeFilter { min_free_processors: 4, ..NodeFilter::default() };
+        assert_eq!(ids(state.query_nodes(&free)), vec![3]);
+        assert!(state.query_nodes(&NodeFilter::default())[0].occupations.is_empty());
+        let with = NodeFilter { with_occupations: true, ..NodeFilter::default() };
+        let nodes = state.query_nodes(&with);
+        assert_eq!(nodes[0].occupations.len(), 2);
+        assert_eq!(nodes[0].queue.len(), 1);
+
+        let util = state.utilization();
+        assert_eq!(util.nodes, 3);
+        assert_eq!(util.online_nodes, 2);
+        assert_eq!(util.total_memory, 200);
+        assert_eq!(util.used_memory, 110);
+        assert_eq!(util.free_memory, 90);
+        assert_eq!(util.total_processors, 8);
+        assert_eq!(util.used_processors, 2);
+        assert_eq!(util.occupations[&OccupationStatus::Running], 2);
+        assert_eq!(util.occupations[&OccupationStatus::Scheduled], 1);
+        assert_eq!(util.occupations[&OccupationStatus::Released], 1);
+        assert_eq!(util.per_node[0], NodeUtilization {
+            node_id: 1,
+            address: "node1".to_string(),
+            online: true,
+            state: NodeState::Active,
+            memory: 100,
+            memory_used: 80,
+            processors: 4,
+            processors_used: 1,
+        });
+        assert_eq!(util.per_node[2].memory_used, 0);
+    }
+
+    #[test]
+    fn recover_baseline_snapshot() {
+        use super::legacy;
+        let occ = |task_id, stage_id, memory, status| legacy::Occupation {
+            task_id, stage_id, memory, status,
+            workers: 1,
+            node_id: 1,
+            last_updated: 7,
+        };
+        let mut occupations = BTreeMap::new();
+        occupations.insert(1, occ(1, 1, 60, legacy::OccupationStatus::Running));
+        occupations.insert(2, occ(1, 2, 60, legacy::OccupationStatus::Scheduled));
+        occupations.insert(3, occ(2, 3, 10, legacy::OccupationStatus::Released));
+        let mut nodes = BTreeMap::new();
+        nodes.insert(1, legacy::ComputeNode {
+            address: "node1".to_string(),
+            memory: 100,
+            memory_remains: 40,
+            processors: 4,
+            processors_remains: 3,
+            node_id: 1,
+            online: true,
+            occupations,
+        });
+        let task = |id, status| legacy::Task {
+            id, status,
+            name: format!("task{}", id),
+            stages: vec![id],
+            nodes: vec![1],
+            meta: Map::new(),
+        };
+        let mut tasks = BTreeMap::new();
+        tasks.insert(1, task(1, legacy::TaskStatus::Succeed));
+        tasks.insert(2, task(2, legacy::TaskStatus::Canceled));
+        // encoded as the baseline did
+        let data = bincode::serialize(&(&nodes, &tasks));
+
+        let cb = Recorder::new();
+        let mut state = ManagerState::recover(&data).unwrap();
+        {
+            let node = &state.compute_nodes[&1];
+            assert_eq!(node.memory(), 100);
+            assert_eq!(node.memory_remains(), 40);
+            assert_eq!(node.processors_remains(), 3);
+            assert_eq!(node.occupations[&1].last_updated, 7);
+            assert_eq!(node.queue.len(), 1);
+        }
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Running);
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Scheduled);
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Released);
+        // still holding resources, so not ended yet
+        assert_eq!(*state.tasks[&1].status(), TaskStatus::Running);
+        assert_eq!(*state.tasks[&2].status(), TaskStatus::Canceled);
+        assert_eq!(state.tasks[&1].pool(), DEFAULT_POOL);
+        assert_eq!(state.finished, vec![2].into_iter().collect::<VecDeque<u64>>());
+        assert_eq!(state.pools.pools[DEFAULT_POOL].used_memory, 60);
+
+        // snapshots of the upgraded state recover to the same state
+        let snapshot = state.snapshot();
+        assert_eq!(ManagerState::recover(&snapshot).unwrap().snapshot(), snapshot);
+
+        // the queued occupation is admitted when the running one is released
+        assert!(state.release_occupation(1, 1, 1, &cb).unwrap());
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Running);
+        assert_eq!(state.pools.pools[DEFAULT_POOL].used_memory, 60);
+    }
+
+    #[test]
+    fn recover_unknown_version() {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.push(SNAPSHOT_VERSION + 1);
+        data.extend_from_slice(&state(&[1]).snapshot()[SNAPSHOT_MAGIC.len() + 1..]);
+        assert!(ManagerState::recover(&data).is_none());
+        assert!(ManagerState::recover(SNAPSHOT_MAGIC).is_none());
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn constrained(task_id: u64, stage_id: u64, node_id: u64, memory: u64, constraints: &Constraints)
+        -> Occupation
+    {
+        Occupation::new(task_id, stage_id, node_id, 1, memory).with_constraints(constraints.clone())
+    }
+
+    #[test]
+    fn placement_constraints() {
+        use super::super::constraints::{AntiAffinity, LabelSelector};
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2, 3]);
+        state.set_labels(1, labels(&[("rack", "r1"), ("ssd", "true")])).unwrap();
+        state.set_labels(2, labels(&[("rack", "r1")])).unwrap();
+        state.set_labels(3, labels(&[("rack", "r2")])).unwrap();
+
+        let ssd = Constraints { required: vec![LabelSelector::exists("ssd")], ..Constraints::default() };
+        match state.register_task(task(1, 0), vec![constrained(1, 1, 2, 10, &ssd)], &cb) {
+            Err(RegisterTaskError::ConstraintsNotSatisfied(1)) => {},
+            other => panic!("required label is missing on the node: {:?}", other)
+        }
+        let occs = state.register_task(task(1, 0), vec![constrained(1, 1, 1, 10, &ssd)], &cb);
+        assert_eq!(running(occs.unwrap()), vec![1]);
+
+        // keep task 2 off the rack of task 1
+        let rack = |task_id| Constraints {
+            anti_affinity: vec![AntiAffinity { task_id, topology_key: Some("rack".to_string()) }],
+            ..Constraints::default()
+        };
+        match state.register_task(task(2, 0), vec![constrained(2, 2, 2, 10, &rack(1))], &cb) {
+            Err(RegisterTaskError::ConstraintsNotSatisfied(2)) => {},
+            other => panic!("node is on the same rack: {:?}", other)
+        }
+        let occs = state.register_task(task(2, 0), vec![constrained(2, 2, 3, 10, &rack(1))], &cb);
+        assert_eq!(running(occs.unwrap()), vec![2]);
+        // without topology key only the node itself conflicts
+        let node = Constraints {
+            anti_affinity: vec![AntiAffinity { task_id: 1, topology_key: None }],
+            ..Constraints::default()
+        };
+        let occs = state.register_task(task(3, 0), vec![constrained(3, 3, 2, 10, &node)], &cb);
+        assert_eq!(running(occs.unwrap()), vec![3]);
+        // occupations registering together count as well
+        let occs = vec![constrained(4, 4, 2, 10, &rack(4)), constrained(4, 5, 1, 10, &rack(4))];
+        match state.register_task(task(4, 0), occs, &cb) {
+            Err(RegisterTaskError::ConstraintsNotSatisfied(4)) => {},
+            other => panic!("occupations of the task are on the same rack: {:?}", other)
+        }
+        // node 3 moves to the rack of node 2, where task 2 is now
+        state.set_labels(3, labels(&[("rack", "r1")])).unwrap();
+        match state.register_task(task(5, 0), vec![constrained(5, 6, 2, 10, &rack(2))], &cb) {
+            Err(RegisterTaskError::ConstraintsNotSatisfied(6)) => {},
+            other => panic!("node is on the same rack after relabeling: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn queued_constraints() {
+        use super::super::constraints::LabelSelector;
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        state.set_labels(1, labels(&[("ssd", "true")])).unwrap();
+        let ssd = Constraints { required: vec![LabelSelector::exists("ssd")], ..Constraints::default() };
+        let any = Constraints::default();
+        state.register_task(task(1, 0), vec![constrained(1, 1, 1, 60, &any)], &cb).unwrap();
+        state.register_task(task(2, 0), vec![constrained(2, 2, 1, 60, &ssd)], &cb).unwrap();
+        state.register_task(task(3, 0), vec![constrained(3, 3, 1, 40, &any)], &cb).unwrap();
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Scheduled);
+        // the queue is strict, the small one waits behind
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Scheduled);
+
+        // labels are checked again on admission, the blocked one is passed over
+        state.set_labels(1, Labels::new()).unwrap();
+        assert!(state.release_occupation(1, 1, 1, &cb).unwrap());
+        assert_eq!(status(&state, 1, 2), OccupationStatus::Scheduled);
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Running);
+
+        state.set_labels(1, labels(&[("ssd", "true")])).unwrap();
+        assert_eq!(running(state.admit(1)), vec![2]);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 0);
+    }
+
+    #[test]
+    fn retry_lost_stage() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 50)]).unwrap();
+        match state.retry_stage(Occupation::new(1, 1, 2, 1, 50), &cb) {
+            Err(RegisterTaskError::StageNotFailed(1)) => {},
+            other => panic!("the stage is still running: {:?}", other)
+        }
+        state.set_online(1, false, &cb);
+        assert!(state.retry_stage(Occupation::new(1, 1, 2, 1, 50), &cb).unwrap());
+        assert_eq!(status(&state, 2, 1), OccupationStatus::Running);
+        assert!(state.tasks[&1].failures().is_empty());
+    }
+
+    #[test]
+    fn retry_with_backoff() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2]);
+        state.tick(1000, &cb);
+        let retry = RetryPolicy { max_attempts: 3, backoff: 100, max_backoff: 150, blacklist_after: 0 };
+        register(&mut state, &cb, task(1, 0).with_retry(retry), &[(1, 1, 50)]).unwrap();
+        let first = RetryDecision::Retry { attempt: 2, not_before: 1100 };
+        assert_eq!(state.stage_failed(1, 1, 1, "oom".to_string(), &cb).unwrap(), first);
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Failed);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 100);
+        // reported again, same answer and no extra attempt
+        assert_eq!(state.stage_failed(1, 1, 1, "oom".to_string(), &cb).unwrap(), first);
+        assert_eq!(state.tasks[&1].failures().len(), 1);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 100);
+        match state.retry_stage(Occupation::new(1, 1, 2, 1, 50), &cb) {
+            Err(RegisterTaskError::BackoffNotElapsed(1)) => {},
+            other => panic!("retried before the backoff: {:?}", other)
+        }
+        state.tick(1100, &cb);
+        assert!(state.retry_stage(Occupation::new(1, 1, 2, 1, 50), &cb).unwrap());
+        // backoff doubles up to the max
+        assert_eq!(
+            state.stage_failed(1, 1, 2, "oom".to_string(), &cb).unwrap(),
+            RetryDecision::Retry { attempt: 3, not_before: 1250 }
+        );
+        state.tick(1250, &cb);
+        assert!(state.retry_stage(Occupation::new(1, 1, 1, 1, 50), &cb).unwrap());
+        cb.take();
+        assert_eq!(state.stage_failed(1, 1, 1, "oom".to_string(), &cb).unwrap(), RetryDecision::GiveUp);
+        assert_eq!(state.tasks[&1].status(), &TaskStatus::Failed);
+        assert!(cb.take().contains(&Notification::TaskEnded(1)));
+        assert_eq!(state.stage_failed(1, 1, 1, "oom".to_string(), &cb).unwrap(), RetryDecision::GiveUp);
+        assert!(cb.take().is_empty());
+        assert_eq!(state.tasks[&1].failures().len(), 3);
+        match state.retry_stage(Occupation::new(1, 1, 2, 1, 50), &cb) {
+            Err(RegisterTaskError::TaskEnded(1)) => {},
+            other => panic!("retried an ended task: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn failure_of_inactive_occupation() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 60), (2, 1, 60)]).unwrap();
+        assert!(state.release_occupation(1, 1, 1, &cb).unwrap());
+        match state.stage_failed(1, 1, 1, "late".to_string(), &cb) {
+            Err(ChangeOccupationStatusError::OccupationNotRunning(OccupationStatus::Released)) => {},
+            other => panic!("released occupation failed: {:?}", other)
+        }
+        state.set_online(1, false, &cb);
+        match state.stage_failed(1, 2, 1, "late".to_string(), &cb) {
+            Err(ChangeOccupationStatusError::OccupationNotRunning(OccupationStatus::Lost)) => {},
+            other => panic!("lost occupation failed: {:?}", other)
+        }
+        assert!(state.tasks[&1].failures().is_empty());
+    }
+
+    #[test]
+    fn blacklist_after_failures() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2]);
+        state.tick(1000, &cb);
+        register(&mut state, &cb, task(1, 0), &[(10, 1, 50)]).unwrap();
+        register(&mut state, &cb, task(2, 0), &[(1, 1, 30), (2, 1, 20), (3, 1, 60)]).unwrap();
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Scheduled);
+        let first = RetryDecision::Retry { attempt: 2, not_before: 2000 };
+        assert_eq!(state.stage_failed(2, 1, 1, "disk".to_string(), &cb).unwrap(), first);
+        assert!(state.tasks[&2].blacklist().is_empty());
+        cb.take();
+        assert_eq!(state.stage_failed(2, 2, 1, "disk".to_string(), &cb).unwrap(), first);
+        assert!(state.tasks[&2].blacklist().contains(&1));
+        // the queued stage has to go elsewhere
+        assert_eq!(status(&state, 1, 3), OccupationStatus::Lost);
+        assert_eq!(cb.take(), vec![
+            Notification::OccupationChanged(2, 2, OccupationStatus::Failed),
+            Notification::ResourceAvailable(2, 2),
+            Notification::OccupationChanged(2, 3, OccupationStatus::Lost),
+        ]);
+        assert!(state.tasks[&2].history().iter().any(|e| e.kind == TaskEventKind::NodeBlacklisted));
+        // a repeated report does not count towards the blacklist again
+        assert_eq!(state.stage_failed(2, 2, 1, "disk".to_string(), &cb).unwrap(), first);
+        assert_eq!(state.tasks[&2].failures().len(), 2);
+        // other tasks still run there
+        assert_eq!(status(&state, 1, 10), OccupationStatus::Running);
+        state.tick(2000, &cb);
+        match state.retry_stage(Occupation::new(2, 1, 1, 1, 30), &cb) {
+            Err(RegisterTaskError::NodeBlacklisted(1)) => {},
+            other => panic!("retried on a blacklisted node: {:?}", other)
+        }
+        assert!(state.retry_stage(Occupation::new(2, 1, 2, 1, 30), &cb).unwrap());
+        match state.speculate_stage(Occupation::new(2, 1, 1, 1, 30), &cb) {
+            Err(RegisterTaskError::NodeBlacklisted(1)) => {},
+            other => panic!("speculated on a blacklisted node: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn speculation_limit() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2, 3]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 60), (2, 1, 60)]).unwrap();
+        match state.speculate_stage(Occupation::new(1, 2, 2, 1, 60), &cb) {
+            Err(RegisterTaskError::StageNotRunning(2)) => {},
+            other => panic!("speculated a queued stage: {:?}", other)
+        }
+        assert!(state.speculate_stage(Occupation::new(1, 1, 2, 1, 60), &cb).unwrap());
+        assert_eq!(status(&state, 2, 1), OccupationStatus::Running);
+        assert_eq!(state.tasks[&1].history().last().unwrap().kind, TaskEventKind::Speculated);
+        // one extra copy at most
+        match state.speculate_stage(Occupation::new(1, 1, 3, 1, 60), &cb) {
+            Err(RegisterTaskError::SpeculationLimit(1)) => {},
+            other => panic!("speculated beyond the limit: {:?}", other)
+        }
+        assert!(state.compute_nodes[&3].occupations.is_empty());
+    }
+
+    #[test]
+    fn first_commit_wins() {
+        let cb = Recorder::new();
+        let mut state = state(&[1, 2]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 40)]).unwrap();
+        assert!(state.speculate_stage(Occupation::new(1, 1, 2, 1, 40), &cb).unwrap());
+        register(&mut state, &cb, task(2, 0), &[(5, 1, 70)]).unwrap();
+        assert_eq!(status(&state, 1, 5), OccupationStatus::Scheduled);
+        cb.take();
+        assert!(state.commit_partition(1, 1, 2, &cb).unwrap());
+        // the loser is stopped and its resources go to the queue
+        assert_eq!(status(&state, 1, 1), OccupationStatus::Superseded);
+        assert_eq!(status(&state, 2, 1), OccupationStatus::Running);
+        assert_eq!(status(&state, 1, 5), OccupationStatus::Running);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 30);
+        assert_eq!(cb.take(), vec![
+            Notification::OccupationChanged(1, 1, OccupationStatus::Superseded),
+            Notification::ResourceAvailable(1, 1),
+            Notification::OccupationChanged(2, 5, OccupationStatus::Running),
+        ]);
+        // commits are answered the same way every time
+        assert!(state.commit_partition(1, 1, 2, &cb).unwrap());
+        assert!(!state.commit_partition(1, 1, 1, &cb).unwrap());
+        assert!(!state.commit_partition(1, 1, 1, &cb).unwrap());
+        assert!(cb.take().is_empty());
+        assert_eq!(state.tasks[&1].commits()[&1], 2);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 30);
+        match state.speculate_stage(Occupation::new(1, 1, 1, 1, 40), &cb) {
+            Err(RegisterTaskError::StageCommitted(1)) => {},
+            other => panic!("speculated a committed stage: {:?}", other)
+        }
+        match state.commit_partition(2, 1, 2, &cb) {
+            Err(ChangeOccupationStatusError::OccupationTaskNotMatch) => {},
+            other => panic!("committed a stage of another task: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn concurrent_tasks_on_one_node() {
+        let cb = Recorder::new();
+        let mut state = state(&[1]);
+        register(&mut state, &cb, task(1, 0), &[(1, 1, 30), (2, 1, 30)]).unwrap();
+        // the same stage id of another task would replace a running occupation
+        match register(&mut state, &cb, task(2, 0), &[(3, 1, 30), (1, 1, 30)]) {
+            Err(RegisterTaskError::StageAlreadyPlaced(1)) => {},
+            other => panic!("replaced an active occupation: {:?}", other)
+        }
+        assert!(!state.tasks.contains_key(&2));
+        assert!(!state.compute_nodes[&1].occupations.contains_key(&3));
+        match register(&mut state, &cb, task(2, 0), &[(3, 1, 10), (3, 1, 10)]) {
+            Err(RegisterTaskError::StageAlreadyPlaced(3)) => {},
+            other => panic!("placed a stage twice: {:?}", other)
+        }
+        assert_eq!(running(register(&mut state, &cb, task(2, 0), &[(3, 1, 30)]).unwrap()), vec![3]);
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 10);
+        assert!(state.release_occupation(1, 1, 1, &cb).unwrap());
+        assert!(state.commit_partition(2, 3, 1, &cb).unwrap());
+        assert!(state.release_occupation(2, 3, 1, &cb).unwrap());
+        assert!(state.release_occupation(1, 2, 1, &cb).unwrap());
+        assert_eq!(state.compute_nodes[&1].memory_remains(), 100);
+        // released stage ids can be taken again
+        assert_eq!(running(register(&mut state, &cb, task(3, 0), &[(1, 1, 30)]).unwrap()), vec![1]);
+    }
+}
@@ -1 +1,5 @@
-mod manager;
\ No newline at end of file
+pub mod manager;
+pub mod pools;
+pub mod vector;
+pub mod constraints;
+pub mod client;
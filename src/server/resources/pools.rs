@@ -0,0 +1,223 @@
+// Scheduling pools for sharing the cluster among tenants.
+// Pools form a tree by `parent`. Every task belongs to one pool, and its running occupations are
+//  accounted to the pool and all of its ancestors. A pool can limit memory and processors used by
+//  its subtree, and guarantee a minimum share of them.
+// When resources are freed, queued occupations are admitted by hierarchical weighted fair share.
+//  Siblings below their minimum share go first, then the sibling with the lowest usage to weight
+//  ratio, comparing from the root down to the first level the pools diverge.
+
+use std::collections::BTreeMap;
+use std::cmp::Ordering;
+
+pub static DEFAULT_POOL: &'static str = "default";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Pool {
+    pub name: String,
+    pub parent: Option<String>,
+    pub weight: u32,
+    pub min_memory: u64,
+    pub min_processors: u32,
+    pub max_memory: Option<u64>,
+    pub max_processors: Option<u32>,
+    // usage of the pool and its descendants, maintained by resource manager
+    pub used_memory: u64,
+    pub used_processors: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PoolError {
+    PoolAlreadyExisted,
+    PoolNotFound(String),
+    ParentNotFound(String),
+    CyclicParent,
+    ZeroWeight,
+}
+
+impl Pool {
+    pub fn new(name: &str, parent: Option<&str>, weight: u32) -> Pool {
+        Pool {
+            name: name.to_string(),
+            parent: parent.map(|p| p.to_string()),
+            weight,
+            min_memory: 0,
+            min_processors: 0,
+            max_memory: None,
+            max_processors: None,
+            used_memory: 0,
+            used_processors: 0,
+        }
+    }
+    fn below_min_share(&self) -> bool {
+        self.used_memory < self.min_memory || self.used_processors < self.min_processors
+    }
+    fn allows(&self, memory: u64, processors: u32) -> bool {
+        self.max_memory.map(|max| self.used_memory + memory <= max).unwrap_or(true) &&
+            self.max_processors.map(|max| self.used_processors + processors <= max).unwrap_or(true)
+    }
+    fn quota_covers(&self, memory: u64, processors: u32) -> bool {
+        self.max_memory.map(|max| memory <= max).unwrap_or(true) &&
+            self.max_processors.map(|max| processors <= max).unwrap_or(true)
+    }
+}
+
+pub struct Pools {
+    pub pools: BTreeMap<String, Pool>
+}
+
+impl Pools {
+    pub fn new() -> Pools {
+        let mut pools = BTreeMap::new();
+        pools.insert(DEFAULT_POOL.to_string(), Pool::new(DEFAULT_POOL, None, 1));
+        Pools { pools }
+    }
+    pub fn create(&mut self, mut pool: Pool) -> Result<(), PoolError> {
+        if self.pools.contains_key(&pool.name) {
+            return Err(PoolError::PoolAlreadyExisted);
+        }
+        self.check_pool(&pool)?;
+        pool.used_memory = 0;
+        pool.used_processors = 0;
+        self.pools.insert(pool.name.clone(), pool);
+        Ok(())
+    }
+    // update settings of the pool, usage is kept
+    pub fn update(&mut self, mut pool: Pool) -> Result<(), PoolError> {
+        self.check_pool(&pool)?;
+        let (used_memory, used_processors, old_parent) = match self.pools.get(&pool.name) {
+            Some(old) => (old.used_memory, old.used_processors, old.parent.clone()),
+            None => return Err(PoolError::PoolNotFound(pool.name.clone()))
+        };
+        if old_parent != pool.parent {
+            // move the usage of the subtree to the new ancestors
+            if let Some(ref old_parent) = old_parent {
+                self.account(old_parent, used_memory, used_processors, false);
+            }
+            if let Some(ref new_parent) = pool.parent {
+                self.account(new_parent, used_memory, used_processors, true);
+            }
+        }
+        pool.used_memory = used_memory;
+        pool.used_processors = used_processors;
+        self.pools.insert(pool.name.clone(), pool);
+        Ok(())
+    }
+    pub fn list(&self) -> Vec<Pool> {
+        self.pools.values().cloned().collect()
+    }
+    pub fn contains(&self, name: &String) -> bool {
+        self.pools.contains_key(name)
+    }
+    // names of the pool and its ancestors, from the pool itself up to the root
+    pub fn chain(&self, name: &String) -> Vec<String> {
+        let mut chain = Vec::new();
+        let mut current = Some(name.clone());
+        while let Some(name) = current {
+            current = self.pools.get(&name).and_then(|p| p.parent.clone());
+            chain.push(name);
+        }
+        chain
+    }
+    // if the pool and ancestors can take the occupation now
+    pub fn allows(&self, name: &String, memory: u64, processors: u32) -> bool {
+        self.chain(name).iter().all(|n| {
+            self.pools.get(n).map(|p| p.allows(memory, processors)).unwrap_or(true)
+        })
+    }
+    // if the total demand of a task can ever fit into quotas of the pool and ancestors
+    pub fn quota_covers(&self, name: &String, memory: u64, processors: u32) -> bool {
+        self.chain(name).iter().all(|n| {
+            self.pools.get(n).map(|p| p.quota_covers(memory, processors)).unwrap_or(true)
+        })
+    }
+    // add (`acquire`) or remove usage of the pool and all its ancestors
+    pub fn account(&mut self, name: &String, memory: u64, processors: u32, acquire: bool) {
+        for n in self.chain(name) {
+            if let Some(pool) = self.pools.get_mut(&n) {
+                if acquire {
+                    pool.used_memory += memory;
+                    pool.used_processors += processors;
+                } else {
+                    if pool.used_memory < memory || pool.used_processors < processors {
+                        error!(
+                            "Pool {} releases more than it uses, memory {}/{}, processors {}/{}",
+                            n, memory, pool.used_memory, processors, pool.used_processors
+                        );
+                    }
+                    pool.used_memory = pool.used_memory.saturating_sub(memory);
+                    pool.used_processors = pool.used_processors.saturating_sub(processors);
+                }
+            }
+        }
+    }
+    // ordering of pools for admission, the first one should get resources first
+    pub fn fair_cmp(&self, a: &String, b: &String) -> Ordering {
+        let mut chain_a = self.chain(a);
+        let mut chain_b = self.chain(b);
+        chain_a.reverse();
+        chain_b.reverse();
+        for (pa, pb) in chain_a.iter().zip(chain_b.iter()) {
+            if pa == pb {
+                continue;
+            }
+            let (pool_a, pool_b) = match (self.pools.get(pa), self.pools.get(pb)) {
+                (Some(pool_a), Some(pool_b)) => (pool_a, pool_b),
+                _ => return pa.cmp(pb)
+            };
+            // below min share first
+            let min_order = pool_b.below_min_share().cmp(&pool_a.below_min_share());
+            if min_order != Ordering::Equal {
+                return min_order;
+            }
+            // then lower used / weight, compared by cross multiplication
+            let ratio_a = pool_a.used_processors as u64 * pool_b.weight as u64;
+            let ratio_b = pool_b.used_processors as u64 * pool_a.weight as u64;
+            let ratio_order = ratio_a.cmp(&ratio_b);
+            if ratio_order != Ordering::Equal {
+                return ratio_order;
+            }
+            let mem_a = pool_a.used_memory.saturating_mul(pool_b.weight as u64);
+            let mem_b = pool_b.used_memory.saturating_mul(pool_a.weight as u64);
+            let mem_order = mem_a.cmp(&mem_b);
+            if mem_order != Ordering::Equal {
+                return mem_order;
+            }
+            return pa.cmp(pb);
+        }
+        // one pool is the ancestor of the other, occupations directly in the ancestor go first
+        chain_a.len().cmp(&chain_b.len())
+    }
+    fn check_pool(&self, pool: &Pool) -> Result<(), PoolError> {
+        if pool.weight == 0 {
+            return Err(PoolError::ZeroWeight);
+        }
+        if let Some(ref parent) = pool.parent {
+            if !self.pools.contains_key(parent) {
+                return Err(PoolError::ParentNotFound(parent.clone()));
+            }
+            if self.chain(parent).contains(&pool.name) {
+                return Err(PoolError::CyclicParent);
+            }
+        }
+        Ok(())
+    }
+}
+
+mod test {
+    use super::*;
+
+    #[test]
+    fn account_chain() {
+        let mut pools = Pools::new();
+        pools.create(Pool::new("a", Some(DEFAULT_POOL), 1)).unwrap();
+        let a = "a".to_string();
+        pools.account(&a, 30, 2, true);
+        assert_eq!(pools.pools[DEFAULT_POOL].used_memory, 30);
+        pools.account(&a, 30, 2, false);
+        assert_eq!(pools.pools["a"].used_memory, 0);
+        // usage never goes below zero, even if releases don't match acquisitions
+        pools.account(&a, 10, 1, false);
+        assert_eq!(pools.pools["a"].used_memory, 0);
+        assert_eq!(pools.pools[DEFAULT_POOL].used_processors, 0);
+    }
+}
@@ -0,0 +1,75 @@
+// Resource vector with named dimensions. Capacities of nodes and demands of occupations always
+//  have memory and processors, other dimensions such as local disk for shuffle spill, network bandwidth or
+//  licenses are optional. A missing dimension means zero, so occupations asking for a dimension
+//  that a node does not have cannot be placed on it.
+
+use std::collections::BTreeMap;
+
+pub static MEMORY: &'static str = "memory";
+pub static PROCESSORS: &'static str = "processors";
+pub static DISK: &'static str = "disk";
+pub static NETWORK: &'static str = "network";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Resources {
+    dims: BTreeMap<String, u64>
+}
+
+impl Resources {
+    pub fn new() -> Resources {
+        Resources { dims: BTreeMap::new() }
+    }
+    pub fn with(mut self, name: &str, amount: u64) -> Resources {
+        self.set(name, amount);
+        self
+    }
+    pub fn set(&mut self, name: &str, amount: u64) {
+        if amount == 0 {
+            self.dims.remove(name);
+        } else {
+            self.dims.insert(name.to_string(), amount);
+        }
+    }
+    pub fn get(&self, name: &str) -> u64 {
+        self.dims.get(name).cloned().unwrap_or(0)
+    }
+    pub fn dims(&self) -> &BTreeMap<String, u64> {
+        &self.dims
+    }
+    pub fn is_empty(&self) -> bool {
+        self.dims.is_empty()
+    }
+    // true if every dimension of `demand` fits
+    pub fn covers(&self, demand: &Resources) -> bool {
+        demand.dims.iter().all(|(name, amount)| self.get(name) >= *amount)
+    }
+    pub fn add(&mut self, other: &Resources) {
+        for (name, amount) in &other.dims {
+            *self.dims.entry(name.clone()).or_insert(0) += *amount;
+        }
+    }
+    pub fn sub(&mut self, other: &Resources) {
+        for (name, amount) in &other.dims {
+            let remains = self.get(name).saturating_sub(*amount);
+            self.set(name, remains);
+        }
+    }
+}
+
+mod test {
+    use super::*;
+
+    #[test]
+    fn covers_and_arithmetic() {
+        let mut node = Resources::new().with(MEMORY, 100).with(DISK, 50).with("license", 2);
+        let demand = Resources::new().with(MEMORY, 60).with("license", 1);
+        assert!(node.covers(&demand));
+        node.sub(&demand);
+        assert_eq!(node.get(MEMORY), 40);
+        assert_eq!(node.get("license"), 1);
+        assert!(!node.covers(&demand));
+        assert!(!node.covers(&Resources::new().with(NETWORK, 1)));
+        node.add(&demand);
+        assert_eq!(node, Resources::new().with(MEMORY, 100).with(DISK, 50).with("license", 2));
+    }
+}
@@ -0,0 +1,74 @@
+// RPC service every hivemind server exposes to drivers and other servers.
+// Drivers use `rdd_funcs` and `missing_rdd_funcs` to make sure a worker knows every RDD function
+//  a job requires before scheduling it there. A worker that is built without some of the
+//  functions cannot decode the job script and would fail at run time instead.
+// `submit_job` takes a serialized `ScriptContext`. It is checked against this server's registries
+//  and registered as a pending task in the resource manager for schedulers to pick up.
+// `locality` reports how often partitions ran on their preferred servers here.
+
+use rdd::funcs::{RDDFuncInfo, REGISTRY as FuncREG};
+use contexts::script::ScriptContext;
+use server::resources::client::ResourceManagerClient;
+use server::resources::manager::Task;
+use scheduler::locality::{Locality, LocalityReport};
+use bifrost::utils::bincode;
+use bifrost_hasher::hash_str;
+use futures::Future;
+use neb::dovahkiin::types::Map;
+use uuid::Uuid;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(HIVEMIND_SERVER_RPC) as u64;
+
+service! {
+    rpc rdd_funcs() -> Vec<RDDFuncInfo>;
+    rpc missing_rdd_funcs(required: Vec<u64>) -> Vec<u64>;
+    rpc submit_job(script: Vec<u8>, name: String, pool: String, priority: u32) -> u64 | String;
+    rpc locality() -> LocalityReport;
+}
+
+pub struct HMService {
+    rm: Arc<ResourceManagerClient>,
+    locality: Arc<Locality>,
+}
+
+impl HMService {
+    pub fn new(rm: &Arc<ResourceManagerClient>, locality: &Arc<Locality>) -> HMService {
+        HMService { rm: rm.clone(), locality: locality.clone() }
+    }
+}
+
+impl Service for HMService {
+    fn rdd_funcs(&self) -> Result<Vec<RDDFuncInfo>, ()> {
+        Ok(FuncREG.list())
+    }
+    fn missing_rdd_funcs(&self, required: Vec<u64>) -> Result<Vec<u64>, ()> {
+        Ok(FuncREG.missing(&required))
+    }
+    fn submit_job(&self, script: Vec<u8>, name: String, pool: String, priority: u32)
+        -> Result<u64, String>
+    {
+        let script: ScriptContext = bincode::deserialize(&script);
+        let missing = FuncREG.missing(&script.required_funcs());
+        if !missing.is_empty() {
+            return Err(format!("Missing RDD functions on this server: {:?}", missing))
+        }
+        // make sure every transformer and closure can be constructed here
+        script.compile()?;
+        let task_id = hash_str(&Uuid::new_v4().to_string());
+        let submitted = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000)
+            .unwrap_or(0);
+        let task = Task::new(task_id, &name, &pool, priority, submitted, Map::new());
+        match self.rm.register_task(task, Vec::new()).wait() {
+            Ok(_) => Ok(task_id),
+            Err(e) => Err(format!("Cannot register task: {:?}", e))
+        }
+    }
+    fn locality(&self) -> Result<LocalityReport, ()> {
+        Ok(self.locality.metrics.report())
+    }
+}
+
+dispatch_rpc_service_functions!(HMService);
@@ -0,0 +1,136 @@
+// Standalone mode runs everything in one process: an embedded raft meta server with the resource
+//  manager, this server as the only compute node, and a local scheduler executing jobs in the
+//  calling thread. It is for development and tests, no cluster is needed.
+
+use bifrost::rpc;
+use futures::Future;
+use contexts::script::{RDDComposer, ScriptContext};
+use rdd::unwrap_record;
+use scheduler::JobError;
+use scheduler::local::LocalScheduler;
+use server::{HMServer, ServerOptions, ServerError};
+use server::config::ShuffleConfig;
+use std::any::Any;
+use std::sync::Arc;
+
+pub struct Standalone {
+    pub server: Arc<HMServer>,
+    pub scheduler: LocalScheduler,
+}
+
+impl Standalone {
+    pub fn start(opts: &ServerOptions) -> Result<Standalone, ServerError> {
+        let mut opts = opts.clone();
+        opts.standalone = true;
+        opts.is_meta = true;
+        opts.meta_members = vec![opts.address.clone()];
+        let rpc = rpc::Server::new(&opts.address);
+        rpc::Server::listen_and_resume(&rpc);
+        let server = HMServer::new(&opts, &rpc)?;
+        let scheduler = LocalScheduler::new(
+            &server.resource_manager, server.server_id, &server.tasks, &server.locality
+        );
+        Ok(Standalone { server, scheduler })
+    }
+
+    // Options for a standalone server on the address, with in-memory raft storage
+    pub fn options(address: &str) -> ServerOptions {
+        ServerOptions {
+            processors: 0,
+            memory: 0,
+            address: address.to_string(),
+            group_name: "hivemind".to_string(),
+            meta_members: vec![address.to_string()],
+            storage: String::new(),
+            is_meta: true,
+            standalone: true,
+            spill_dirs: Vec::new(),
+            shuffle: ShuffleConfig::default(),
+        }
+    }
+
+    pub fn collect<C>(&self, composer: &C) -> Result<Vec<C::Item>, JobError>
+        where C: RDDComposer, C::Item: Any
+    {
+        let input = composer.input()
+            .ok_or(JobError::Failed("Job has no source to read from".to_string()))?;
+        let mut script = ScriptContext::new();
+        composer.compile(&mut script);
+        let job = script.compile().map_err(JobError::Failed)?;
+        self.scheduler.run(&job, input)?
+            .into_iter()
+            .map(|record| match unwrap_record::<C::Item>(record) {
+                Ok(item) => Ok(item),
+                Err(record) => Err(JobError::Failed(
+                    format!("Unexpected record type in job result: {:?}", record)
+                ))
+            })
+            .collect()
+    }
+
+    // Jobs running in `collect` on other threads return `JobError::Canceled`
+    pub fn cancel(&self, task_id: u64) -> Result<(), String> {
+        self.server.resource_manager.cancel_job(task_id).wait()
+            .map_err(|e| format!("Cannot cancel task {}: {:?}", task_id, e))
+    }
+
+    pub fn shutdown(&self) -> Result<(), ServerError> {
+        self.server.shutdown()
+    }
+}
+
+mod test {
+    use super::*;
+    use INIT_LOCK;
+    use contexts::script::parallelize;
+    use rdd::RDDTracker;
+    use rdd::funcs::RDDFuncResult;
+    use rdd::transformers;
+
+    def_rdd_func!(
+        APlusB (a: u64)[b: u64] -> u64 {
+            a + b
+        }
+        AGreaterThanN (x: u64)[n: u64] -> bool {
+            x > n
+        }
+    );
+
+    #[derive(Clone)]
+    struct Dummy;
+    impl RDDComposer for Dummy {
+        type Item = u64;
+        fn compile(&self, _ctx: &mut ScriptContext) {}
+    }
+
+    #[test]
+    fn collect() {
+        {
+            let lock = INIT_LOCK.lock();
+            transformers::map::Map::register();
+            transformers::filter::Filter::register();
+            APlusB::register().unwrap();
+            AGreaterThanN::register().unwrap();
+        }
+        let server = Standalone::start(&Standalone::options("127.0.0.1:30100")).unwrap();
+        let source = parallelize((1..9).collect::<Vec<u64>>(), 3);
+        // records of the source come back as they are
+        assert_eq!(source.collect(&server).unwrap(), (1..9).collect::<Vec<u64>>());
+        let res = source
+            .map(APlusB { b: 10 })
+            .filter(AGreaterThanN { n: 13 })
+            .collect(&server)
+            .unwrap();
+        assert_eq!(res, vec![14, 15, 16, 17, 18]);
+        // parallelized input has no owner to prefer
+        let report = server.server.locality.metrics.report();
+        assert_eq!(report.no_preference, 6);
+        assert_eq!(report.process_local, 0);
+        // jobs without a source fail instead of returning nothing
+        match Dummy.map(APlusB { b: 1 }).collect(&server) {
+            Err(JobError::Failed(_)) => {},
+            other => panic!("job without source should fail: {:?}", other.map(|_| ()))
+        }
+        server.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+use harness::*;
+use futures::Future;
+use hivemind::server::decommission::{DataMigration, DecommissionOptions};
+use hivemind::server::resources::client::ResourceManagerClient;
+use hivemind::server::resources::manager::{
+    ComputeNode, Task, TaskStatus, TaskEventKind, Occupation, OccupationStatus, NodeState};
+use hivemind::server::resources::pools::DEFAULT_POOL;
+use neb::dovahkiin::types::Map;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[test]
+fn nodes_register() {
+    let cluster = TestCluster::start(1, 2);
+    assert!(cluster.wait_for(timeout(), |c| c.online_nodes().len() == 3));
+    for node in &cluster.nodes {
+        let state = cluster.node_state(node.node_id()).unwrap();
+        assert_eq!(state.memory(), NODE_MEMORY);
+        assert_eq!(state.processors(), NODE_PROCESSORS as u32);
+    }
+}
+
+#[test]
+fn kill_and_restart() {
+    let mut cluster = TestCluster::start(1, 2);
+    assert!(cluster.wait_for(timeout(), |c| c.online_nodes().len() == 3));
+    let node_id = cluster.nodes[0].node_id();
+    cluster.kill(0);
+    assert!(cluster.wait_for(timeout(), |c| !c.node_state(node_id).unwrap().online()));
+    cluster.restart(0);
+    assert!(cluster.wait_for(timeout(), |c| c.node_state(node_id).unwrap().online()));
+    // registration is idempotent, the node is not duplicated
+    assert_eq!(cluster.online_nodes().len(), 3);
+}
+
+#[test]
+fn partition_and_heal() {
+    let cluster = TestCluster::start(1, 2);
+    assert!(cluster.wait_for(timeout(), |c| c.online_nodes().len() == 3));
+    let node_id = cluster.nodes[1].node_id();
+    cluster.partition(1);
+    assert!(cluster.wait_for(timeout(), |c| !c.node_state(node_id).unwrap().online()));
+    cluster.heal(1);
+    assert!(cluster.wait_for(timeout(), |c| c.node_state(node_id).unwrap().online()));
+}
+
+#[test]
+fn job_survives_node_kill() {
+    let mut cluster = TestCluster::start(1, 2);
+    assert!(cluster.wait_for(timeout(), |c| c.online_nodes().len() == 3));
+    let rm = cluster.rm().clone();
+    let killed = cluster.nodes[0].node_id();
+    let survivor = cluster.nodes[1].node_id();
+    let task_id = 1;
+    let memory = NODE_MEMORY / 4;
+    let task = Task::new(task_id, "e2e", DEFAULT_POOL, 0, 0, Map::new());
+    let occupations = vec![
+        Occupation::new(task_id, 0, killed, 1, memory),
+        Occupation::new(task_id, 1, survivor, 1, memory),
+    ];
+    assert_eq!(rm.register_task(task, occupations).wait().unwrap().len(), 2);
+
+    cluster.kill(0);
+    assert!(cluster.wait_for(timeout(), |c| {
+        c.node_state(killed).unwrap().occupations()[&0].status == OccupationStatus::Lost
+    }));
+    // the lost partition runs again on the other node
+    assert!(rm.retry_stage(Occupation::new(task_id, 0, survivor, 1, memory)).wait().unwrap());
+    for stage_id in 0..2 {
+        assert!(rm.commit_partition(task_id, stage_id, survivor).wait().unwrap());
+        assert!(rm.release_occupation(task_id, stage_id, survivor).wait().unwrap());
+    }
+    rm.task_ended(task_id, TaskStatus::Succeed).wait().unwrap();
+    let task = rm.task(task_id).wait().unwrap().unwrap();
+    assert_eq!(*task.status(), TaskStatus::Succeed);
+    assert_eq!(task.commits()[&0], survivor);
+    assert!(task.failures().is_empty());
+}
+
+// Records peers, and occupations of the decommissioned node as the resource manager sees them when
+//  data is migrated
+struct CheckMigration {
+    rm: Arc<ResourceManagerClient>,
+    node_id: u64,
+    peers: Mutex<Vec<u64>>,
+    occupations: Mutex<Vec<OccupationStatus>>,
+}
+
+impl DataMigration for CheckMigration {
+    fn name(&self) -> &str {
+        "check"
+    }
+    fn migrate(&self, peers: &Vec<ComputeNode>) -> Result<(), String> {
+        *self.peers.lock().unwrap() = peers.iter().map(|node| node.node_id()).collect();
+        let node = self.rm.node(self.node_id).wait()
+            .map_err(|e| format!("{:?}", e))?
+            .ok_or("node is gone".to_string())?;
+        *self.occupations.lock().unwrap() = node.occupations().values().map(|occ| occ.status).collect();
+        Ok(())
+    }
+}
+
+fn decommission_options(timeout: Duration) -> DecommissionOptions {
+    DecommissionOptions { timeout, poll_interval: Duration::from_millis(50) }
+}
+
+#[test]
+fn decommission_waits_for_running() {
+    let cluster = TestCluster::start(1, 2);
+    assert!(cluster.wait_for(timeout(), |c| c.online_nodes().len() == 3));
+    let rm = cluster.rm().clone();
+    let node_id = cluster.nodes[0].node_id();
+    let task = Task::new(1, "draining", DEFAULT_POOL, 0, 0, Map::new());
+    rm.register_task(task, vec![Occupation::new(1, 0, node_id, 1, NODE_MEMORY / 4)]).wait().unwrap();
+    let decommission = {
+        let server = cluster.nodes[0].server().clone();
+        thread::spawn(move || server.decommission(&decommission_options(timeout())))
+    };
+    assert!(cluster.wait_for(timeout(), |c| c.node_state(node_id).unwrap().state() == NodeState::Draining));
+    assert!(rm.release_occupation(1, 0, node_id).wait().unwrap());
+    decommission.join().unwrap().unwrap();
+    assert!(cluster.node_state(node_id).is_none());
+    let task = rm.task(1).wait().unwrap().unwrap();
+    assert!(!task.history().iter().any(|event| event.kind == TaskEventKind::OccupationLost));
+}
+
+#[test]
+fn decommission_loses_stragglers() {
+    let cluster = TestCluster::start(1, 2);
+    assert!(cluster.wait_for(timeout(), |c| c.online_nodes().len() == 3));
+    let rm = cluster.rm().clone();
+    let node_id = cluster.nodes[0].node_id();
+    let task = Task::new(1, "straggler", DEFAULT_POOL, 0, 0, Map::new());
+    rm.register_task(task, vec![Occupation::new(1, 0, node_id, 1, NODE_MEMORY / 4)]).wait().unwrap();
+    let migration = Arc::new(CheckMigration {
+        rm: rm.clone(),
+        node_id,
+        peers: Mutex::new(Vec::new()),
+        occupations: Mutex::new(Vec::new()),
+    });
+    cluster.nodes[0].server().add_migration(migration.clone());
+    cluster.nodes[0].server().decommission(&decommission_options(Duration::from_millis(200))).unwrap();
+    // lost while the node was still there, before data migration and leaving the group
+    assert_eq!(*migration.occupations.lock().unwrap(), vec![OccupationStatus::Lost]);
+    let mut peers = migration.peers.lock().unwrap().clone();
+    peers.sort();
+    let mut expected = vec![cluster.meta[0].node_id(), cluster.nodes[1].node_id()];
+    expected.sort();
+    assert_eq!(peers, expected);
+    assert!(cluster.node_state(node_id).is_none());
+    let task = rm.task(1).wait().unwrap().unwrap();
+    assert!(task.history().iter().any(|event| {
+        event.node_id == node_id && event.kind == TaskEventKind::OccupationLost
+    }));
+}
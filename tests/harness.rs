@@ -0,0 +1,153 @@
+// Local test cluster. Meta servers and compute nodes all run in this process on loopback ports.
+// Nodes can be killed and restarted, or partitioned from the meta group, to test failure handling.
+// Both stop the RPC server and the membership heartbeats of the node without leaving the group,
+//  so the meta group only finds out by heartbeat timeout, as with a real crash or network split.
+
+use bifrost::rpc;
+use futures::Future;
+use hivemind::server::{HMServer, ServerOptions};
+use hivemind::server::config::ShuffleConfig;
+use hivemind::server::resources::client::ResourceManagerClient;
+use hivemind::server::resources::manager::{ComputeNode, NodeFilter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static NEXT_PORT: AtomicUsize = ATOMIC_USIZE_INIT;
+const BASE_PORT: usize = 31000;
+
+pub const NODE_MEMORY: u64 = 1024 * 1024 * 1024;
+pub const NODE_PROCESSORS: u16 = 4;
+
+pub struct TestNode {
+    pub opts: ServerOptions,
+    pub rpc: Arc<rpc::Server>,
+    pub server: Option<Arc<HMServer>>,
+}
+
+pub struct TestCluster {
+    pub meta: Vec<TestNode>,
+    pub nodes: Vec<TestNode>,
+}
+
+fn next_address() -> String {
+    let port = BASE_PORT + NEXT_PORT.fetch_add(1, Ordering::SeqCst);
+    format!("127.0.0.1:{}", port)
+}
+
+impl TestNode {
+    fn start(opts: ServerOptions) -> TestNode {
+        let rpc = rpc::Server::new(&opts.address);
+        rpc::Server::listen_and_resume(&rpc);
+        let server = HMServer::new(&opts, &rpc).unwrap();
+        TestNode { opts, rpc, server: Some(server) }
+    }
+    pub fn server(&self) -> &Arc<HMServer> {
+        self.server.as_ref().expect("node is killed")
+    }
+    pub fn node_id(&self) -> u64 {
+        self.rpc.server_id
+    }
+    // no traffic in or out, the server itself is not touched
+    fn cut_off(&self) {
+        if let Some(ref server) = self.server {
+            server.member_service.close();
+        }
+        self.rpc.shutdown();
+    }
+}
+
+impl TestCluster {
+    // Starts `metas` meta servers and `nodes` compute only servers. Meta servers are also
+    //  compute nodes.
+    pub fn start(metas: usize, nodes: usize) -> TestCluster {
+        assert!(metas > 0, "cluster needs at least one meta server");
+        let group_name = format!("test-{}", NEXT_PORT.load(Ordering::SeqCst));
+        let meta_members: Vec<String> = (0..metas).map(|_| next_address()).collect();
+        let options = |address: &String, is_meta: bool| ServerOptions {
+            processors: NODE_PROCESSORS,
+            memory: NODE_MEMORY,
+            address: address.clone(),
+            group_name: group_name.clone(),
+            meta_members: meta_members.clone(),
+            storage: String::new(),
+            is_meta,
+            standalone: false,
+            spill_dirs: Vec::new(),
+            shuffle: ShuffleConfig::default(),
+        };
+        let meta = meta_members.iter()
+            .map(|address| TestNode::start(options(address, true)))
+            .collect();
+        let nodes = (0..nodes)
+            .map(|_| TestNode::start(options(&next_address(), false)))
+            .collect();
+        TestCluster { meta, nodes }
+    }
+
+    pub fn rm(&self) -> &Arc<ResourceManagerClient> {
+        &self.meta[0].server().resource_manager
+    }
+
+    // Stop the node abruptly, like a crashed process. It neither leaves the group nor deregisters.
+    pub fn kill(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        node.cut_off();
+        node.server = None;
+    }
+
+    // Start a killed node again on the same address with a new RPC server, it should take over
+    //  its old registration
+    pub fn restart(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        assert!(node.server.is_none(), "node is still running");
+        node.rpc = rpc::Server::new(&node.opts.address);
+        rpc::Server::listen_and_resume(&node.rpc);
+        node.server = Some(HMServer::new(&node.opts, &node.rpc).unwrap());
+    }
+
+    // Cut the node off from the meta group while it keeps running, and bring it back with `heal`
+    pub fn partition(&self, index: usize) {
+        let node = &self.nodes[index];
+        assert!(node.server.is_some(), "node is killed");
+        node.cut_off();
+    }
+    pub fn heal(&self, index: usize) {
+        let node = &self.nodes[index];
+        rpc::Server::listen_and_resume(&node.rpc);
+        node.server().rejoin_group().unwrap();
+    }
+
+    pub fn node_state(&self, node_id: u64) -> Option<ComputeNode> {
+        self.rm().node(node_id).wait().unwrap()
+    }
+    pub fn online_nodes(&self) -> Vec<ComputeNode> {
+        self.rm().nodes(NodeFilter { online_only: true, ..NodeFilter::default() }).wait().unwrap()
+    }
+
+    // Poll until `cond` holds, membership changes reach the resource manager asynchronously
+    pub fn wait_for<F>(&self, timeout: Duration, cond: F) -> bool
+        where F: Fn(&TestCluster) -> bool
+    {
+        let started = Instant::now();
+        while started.elapsed() < timeout {
+            if cond(self) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        cond(self)
+    }
+}
+
+impl Drop for TestCluster {
+    fn drop(&mut self) {
+        for node in self.nodes.iter().chain(self.meta.iter()) {
+            if let Some(ref server) = node.server {
+                server.leave_group();
+                node.rpc.shutdown();
+            }
+        }
+    }
+}
@@ -7,5 +7,10 @@ extern crate serde_derive;
 extern crate bifrost;
 #[macro_use]
 extern crate bifrost_hasher;
+extern crate hivemind;
+extern crate futures;
+extern crate neb;
 
-mod closure_serialize;
\ No newline at end of file
+mod closure_serialize;
+mod harness;
+mod cluster;